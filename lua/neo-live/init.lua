@@ -5,6 +5,13 @@ M.config = {
     address = "127.0.0.1",
     port = "3248",
     binary_path = vim.fn.getcwd() .. "/target/debug/neo-live", -- TMP
+    name = vim.env.USER or "anonymous",
+    color = nil, -- e.g. "#e06c75", left to the other peers when unset
+    token = nil, -- session token printed by the server, asked for on connect when unset
+    room = nil, -- room on the server to join, the server's default room when unset
+    role = nil, -- "owner", "editor" or "viewer", whichever the token was handed out for
+    invite = nil, -- neolive:// invite printed by the server, used instead of address, port, token, room and role
+    remote = nil, -- command to reach the server through, e.g. "ssh host neo-live serve --stdio --unix /tmp/neo-live.sock", used instead of address and port
 }
 
 M._client_job = nil
@@ -14,6 +21,17 @@ M._known_buffers = {}
 M._sent_open = false
 M._pending_updates = {}
 M._prompting_buffers = {}
+M._opening = {}
+M._creating = {}
+M._deleted = {}
+M._edit_ready = {}
+M._peer_ns = vim.api.nvim_create_namespace("neo-live-peers")
+M._peer_marks = {}
+
+-- msgpack nil comes back as vim.NIL, which is truthy
+local function present(value)
+    return value ~= nil and value ~= vim.NIL
+end
 
 -- Get all lines from the current buffer (0)
 local function get_buffer_text()
@@ -81,34 +99,97 @@ local function collect_open_buffers()
     return buffers
 end
 
+-- Text between two positions, with the newline after each line like on_bytes counts it
+local function get_region_text(bufnr, start_row, start_col, end_row, end_col)
+    local lines = vim.api.nvim_buf_get_lines(bufnr, start_row, end_row + 1, false)
+    local parts = {}
+    for row = start_row, end_row do
+        local line = lines[row - start_row + 1] or ""
+        local from = row == start_row and start_col or 0
+        local to = row == end_row and end_col or #line
+        table.insert(parts, string.sub(line, from + 1, to))
+    end
+    return table.concat(parts, "\n")
+end
+
+-- remote changes land in read-only buffers too
+local function apply_remote(bufnr, apply)
+    local modifiable = vim.bo[bufnr].modifiable
+    vim.bo[bufnr].modifiable = true
+    M._is_applying_remote = true
+    apply()
+    M._is_applying_remote = false
+    vim.bo[bufnr].modifiable = modifiable
+end
+
+local function send_full_update(bufnr, normalized)
+    local cursor = { 0, 0 }
+    if bufnr == vim.api.nvim_get_current_buf() then
+        cursor = vim.api.nvim_win_get_cursor(0)
+    end
+
+    local text = get_buffer_text_for(bufnr)
+    log.log(
+        string.format("Sending PluginUpdate buffer=%s len=%d", normalized, #text),
+        "TRACE"
+    )
+
+    local payload = vim.mpack.encode({
+        cursor_row = cursor[1],
+        cursor_col = cursor[2],
+        buffer = normalized,
+        text = text,
+    })
+
+    local length = encode_length(#payload)
+    M._client_job:write(length .. payload)
+    M._edit_ready[normalized] = true
+end
+
 local function attach_buffer(bufnr)
     vim.api.nvim_buf_attach(bufnr, false, {
-        on_lines = function()
+        on_bytes = function(_, _, _, start_row, start_col, byte_offset,
+                            _, _, old_end_byte, new_end_row, new_end_col, new_end_byte)
             if M._is_applying_remote or not M._client_job or not M._sent_open then return end
 
             local buffer = vim.api.nvim_buf_get_name(bufnr)
             local normalized = normalize_buffer_name(buffer)
             if normalized == "" then
-                log.log("Skipping PluginUpdate: empty buffer name", "WARN")
+                log.log("Skipping PluginEdit: empty buffer name", "WARN")
+                return
+            end
+            -- the buffer was deleted from the session, stop sharing it
+            if M._deleted[normalized] then
+                M._deleted[normalized] = nil
+                return true
+            end
+
+            -- the client only knows our contents once the whole buffer went over
+            if not M._edit_ready[normalized] then
+                send_full_update(bufnr, normalized)
                 return
             end
 
-            local cursor = { 0, 0 }
-            if bufnr == vim.api.nvim_get_current_buf() then
-                cursor = vim.api.nvim_win_get_cursor(0)
+            local end_row = start_row + new_end_row
+            local end_col = new_end_row == 0 and start_col + new_end_col or new_end_col
+            local inserted = get_region_text(bufnr, start_row, start_col, end_row, end_col)
+            if #inserted ~= new_end_byte then
+                log.log("Edit region mismatch, sending whole buffer", "WARN")
+                send_full_update(bufnr, normalized)
+                return
             end
 
-            local text = get_buffer_text_for(bufnr)
             log.log(
-                string.format("Sending PluginUpdate buffer=%s len=%d", normalized, #text),
+                string.format("Sending PluginEdit buffer=%s start=%d removed=%d inserted=%d",
+                    normalized, byte_offset, old_end_byte, #inserted),
                 "TRACE"
             )
 
             local payload = vim.mpack.encode({
-                cursor_row = cursor[1],
-                cursor_col = cursor[2],
                 buffer = normalized,
-                text = text,
+                start = byte_offset,
+                removed = old_end_byte,
+                inserted = inserted,
             })
 
             local length = encode_length(#payload)
@@ -117,18 +198,152 @@ local function attach_buffer(bufnr)
     })
 end
 
-function M.setup(opts)
-    M.config = vim.tbl_deep_extend("force", M.config, opts or {})
+local function pick_file(list)
+    if #list.files == 0 then
+        return vim.notify("neo-live: no shared files under '" .. list.directory .. "'")
+    end
+    vim.ui.select(list.files, { prompt = "neo-live: open shared file" }, function(choice)
+        if choice then M.open(choice) end
+    end)
 end
 
-function M.connect()
-    if M._client_job then return end
+-- a file opened from the host's listing, which we may not have locally
+local function show_opened(bufname, lines, read_only)
+    M._opening[bufname] = nil
+    M._managed_buffers[bufname] = true
+    local bufnr = vim.fn.bufnr(bufname, true)
+    vim.fn.bufload(bufnr)
+    vim.bo[bufnr].buflisted = true
+
+    apply_remote(bufnr, function()
+        vim.api.nvim_buf_set_lines(bufnr, 0, -1, false, lines)
+    end)
+    vim.bo[bufnr].modifiable = not read_only
+    M._edit_ready[bufname] = true
+    attach_buffer(bufnr)
+    vim.api.nvim_set_current_buf(bufnr)
+end
 
-    local bin = vim.fn.exepath(M.config.binary_path)
-    if bin == "" then return print("Binary not found!") end
+local function write_message(message)
+    local payload = vim.mpack.encode(message)
+    local length = encode_length(#payload)
+    M._client_job:write(length .. payload)
+end
 
-    log.log("Connecting to " .. M.config.address .. ":" .. M.config.port)
+local function buffer_created(name)
+    if not M._creating[name] then
+        return vim.notify("neo-live: " .. name .. " was created")
+    end
+    M._creating[name] = nil
+    M.open(name)
+end
+
+local function buffer_renamed(from, to)
+    M._managed_buffers[to] = M._managed_buffers[from]
+    M._known_buffers[to] = M._known_buffers[from]
+    M._edit_ready[to] = M._edit_ready[from]
+    M._managed_buffers[from] = nil
+    M._known_buffers[from] = nil
+    M._edit_ready[from] = nil
+
+    local bufnr = vim.fn.bufnr(from)
+    if bufnr ~= -1 and M._managed_buffers[to] then
+        vim.api.nvim_buf_set_name(bufnr, to)
+    end
+    vim.notify("neo-live: " .. from .. " was renamed to " .. to)
+end
+
+local function buffer_deleted(name)
+    if M._managed_buffers[name] and vim.fn.bufnr(name) ~= -1 then
+        M._deleted[name] = true
+    end
+    M._managed_buffers[name] = nil
+    M._known_buffers[name] = nil
+    M._edit_ready[name] = nil
+    vim.notify("neo-live: " .. name .. " was deleted", vim.log.levels.WARN)
+end
+
+local function send_cursor()
+    if not M._client_job or not M._sent_open then return end
+
+    local bufnr = vim.api.nvim_get_current_buf()
+    local normalized = normalize_buffer_name(vim.api.nvim_buf_get_name(bufnr))
+    if normalized == "" or not M._managed_buffers[normalized] then return end
+
+    local cursor = vim.api.nvim_win_get_cursor(0)
+    local head = vim.api.nvim_buf_get_offset(bufnr, cursor[1] - 1) + cursor[2]
+    local anchor = head
+    local mode = vim.api.nvim_get_mode().mode
+    if mode == "v" or mode == "V" or mode == "\22" then
+        local pos = vim.fn.getpos("v")
+        anchor = vim.api.nvim_buf_get_offset(bufnr, pos[2] - 1) + pos[3] - 1
+    end
+
+    local payload = vim.mpack.encode({ buffer = normalized, head = head, anchor = anchor })
+    local length = encode_length(#payload)
+    M._client_job:write(length .. payload)
+end
+
+local function peer_highlight(peer, color)
+    if not present(color) then return "Cursor", "Visual" end
+    local group = "NeoLivePeer" .. peer
+    vim.api.nvim_set_hl(0, group, { bg = color, fg = "Black" })
+    vim.api.nvim_set_hl(0, group .. "Selection", { bg = color, blend = 60 })
+    return group, group .. "Selection"
+end
+
+local function show_peer_cursor(decoded)
+    local old = M._peer_marks[decoded.peer]
+    if old then
+        pcall(vim.api.nvim_buf_clear_namespace, old.bufnr, M._peer_ns, 0, -1)
+        M._peer_marks[decoded.peer] = nil
+    end
+    -- other peers still have marks in that buffer, put theirs back
+    for _, mark in pairs(M._peer_marks) do
+        if old and mark.bufnr == old.bufnr then
+            mark.draw()
+        end
+    end
+
+    if not present(decoded.head) or not M._managed_buffers[decoded.buffer] then return end
+
+    local bufnr = vim.fn.bufnr(decoded.buffer)
+    if bufnr == -1 then return end
+
+    local cursor_hl, selection_hl = peer_highlight(decoded.peer, decoded.color)
+    local function draw()
+        local head = decoded.head
+        local anchor = present(decoded.anchor) and decoded.anchor or head
+        pcall(vim.api.nvim_buf_set_extmark, bufnr, M._peer_ns, head.row, head.col, {
+            end_col = head.col + 1,
+            hl_group = cursor_hl,
+            virt_text = { { " " .. decoded.name .. " ", cursor_hl } },
+            virt_text_pos = "eol",
+            strict = false,
+        })
+        if anchor.row ~= head.row or anchor.col ~= head.col then
+            local from, to = anchor, head
+            if from.row > to.row or (from.row == to.row and from.col > to.col) then
+                from, to = to, from
+            end
+            pcall(vim.api.nvim_buf_set_extmark, bufnr, M._peer_ns, from.row, from.col, {
+                end_row = to.row,
+                end_col = to.col,
+                hl_group = selection_hl,
+                strict = false,
+            })
+        end
+    end
+    draw()
+    M._peer_marks[decoded.peer] = { bufnr = bufnr, draw = draw }
+end
+
+function M.setup(opts)
+    M.config = vim.tbl_deep_extend("force", M.config, opts or {})
+end
 
+-- spawns the client with `cmd` and wires it up to the editor
+local function start(cmd)
     local buffer = ""
     local function onClientUpdate(err, data)
         vim.schedule(function()
@@ -153,10 +368,61 @@ function M.connect()
             local ok, decoded = pcall(vim.mpack.decode, raw_payload)
 
             log.log(string.format("decoded: %s, ok: %s", vim.inspect(decoded), tostring(ok)), "TRACE")
-            if ok and decoded and type(decoded) == "table" and decoded.text and decoded.buffer then
+            if ok and decoded and type(decoded) == "table" and decoded.error then
+                log.log("client error: " .. decoded.error, "ERROR")
+                vim.schedule(function()
+                    vim.notify("neo-live: " .. decoded.error, vim.log.levels.ERROR)
+                end)
+            elseif ok and decoded and type(decoded) == "table" and decoded.share then
+                log.log("sharing on " .. decoded.share)
+                vim.schedule(function()
+                    vim.notify(
+                        "neo-live: others can join at " .. decoded.share .. " with --role " .. decoded.role
+                            .. " --token " .. decoded.token .. ", or with :LiveConnect " .. decoded.invite,
+                        vim.log.levels.INFO
+                    )
+                end)
+            elseif ok and decoded and type(decoded) == "table" and decoded.shutdown then
+                log.log("server shut down: " .. decoded.shutdown, "WARN")
+                vim.schedule(function()
+                    vim.notify("neo-live: server shut down (" .. decoded.shutdown .. ")", vim.log.levels.WARN)
+                end)
+            elseif ok and decoded and type(decoded) == "table" and decoded.peer then
+                vim.schedule(function() show_peer_cursor(decoded) end)
+            elseif ok and decoded and type(decoded) == "table" and decoded.files then
+                vim.schedule(function() pick_file(decoded) end)
+            elseif ok and decoded and type(decoded) == "table" and decoded.create then
+                vim.schedule(function() buffer_created(decoded.create) end)
+            elseif ok and decoded and type(decoded) == "table" and decoded.rename then
+                vim.schedule(function() buffer_renamed(decoded.rename, decoded.to) end)
+            elseif ok and decoded and type(decoded) == "table" and decoded.delete then
+                vim.schedule(function() buffer_deleted(decoded.delete) end)
+            elseif ok and decoded and type(decoded) == "table" and decoded.edits and decoded.buffer then
+                local bufname = decoded.buffer
+                vim.schedule(function()
+                    if not M._managed_buffers[bufname] then
+                        log.log("Ignoring patch for unmanaged buffer " .. bufname, "TRACE")
+                        return
+                    end
+
+                    local bufnr = vim.fn.bufnr(bufname, true)
+                    apply_remote(bufnr, function()
+                        for _, edit in ipairs(decoded.edits) do
+                            local lines = vim.split(edit.text, "\n", true)
+                            vim.api.nvim_buf_set_text(
+                                bufnr, edit.start_row, edit.start_col, edit.end_row, edit.end_col,
+                                lines
+                            )
+                        end
+                    end)
+                end)
+            elseif ok and decoded and type(decoded) == "table" and decoded.text and decoded.buffer then
                 local bufname = decoded.buffer
                 local lines = vim.split(decoded.text, "\n", true)
                 vim.schedule(function()
+                    if M._opening[bufname] then
+                        return show_opened(bufname, lines, decoded.read_only)
+                    end
                     if decoded.text == "" and not M._managed_buffers[bufname] then
                         return
                     end
@@ -176,6 +442,8 @@ function M.connect()
 
                         if choice ~= 1 then
                             M._pending_updates[bufname] = nil
+                            -- our contents differ from the session now, resend them on next edit
+                            M._edit_ready[bufname] = nil
                             return
                         end
 
@@ -186,10 +454,13 @@ function M.connect()
 
                     -- WARN: edits that happen between here will fall through the cracks,
                     -- might need a better solution
-                    M._is_applying_remote = true
                     local bufnr = vim.fn.bufnr(bufname, true)
-                    vim.api.nvim_buf_set_lines(bufnr, 0, -1, false, lines)
-                    M._is_applying_remote = false
+                    apply_remote(bufnr, function()
+                        vim.api.nvim_buf_set_lines(bufnr, 0, -1, false, lines)
+                    end)
+                    -- the server won't take our edits to it, so don't let them be made
+                    vim.bo[bufnr].modifiable = not decoded.read_only
+                    M._edit_ready[bufname] = true
                 end)
             else
                 log.log("failed to decode", "ERROR")
@@ -199,7 +470,7 @@ function M.connect()
 
     -- spawn neo-live client and listen for updates
     M._client_job = vim.system(
-        { bin, "--port", M.config.port, "connect", "--address", M.config.address },
+        cmd,
         {
             stdin = true,
             stdout = onClientUpdate,
@@ -211,6 +482,7 @@ function M.connect()
             log.log("Client exited with code " .. obj.code)
             M._client_job = nil
             M._sent_open = false
+            M._edit_ready = {}
         end
     )
 
@@ -237,6 +509,124 @@ function M.connect()
             attach_buffer(args.buf)
         end
     })
+
+    vim.api.nvim_create_autocmd({ "CursorMoved", "CursorMovedI", "ModeChanged" }, {
+        callback = send_cursor,
+    })
+end
+
+function M.connect(invite)
+    if M._client_job then return end
+
+    local bin = vim.fn.exepath(M.config.binary_path)
+    if bin == "" then return print("Binary not found!") end
+
+    if invite == nil or invite == "" then invite = M.config.invite end
+    if invite then
+        log.log("Connecting with an invite")
+        local cmd = { bin, "connect", "--invite", invite, "--name", M.config.name }
+        if M.config.color then
+            table.insert(cmd, "--color")
+            table.insert(cmd, M.config.color)
+        end
+        return start(cmd)
+    end
+
+    local token = M.config.token or vim.fn.inputsecret("neo-live session token: ")
+    if token == "" then return print("A session token is required") end
+
+    local cmd
+    if M.config.remote then
+        log.log("Connecting through " .. M.config.remote)
+        cmd = { bin, "connect", "--stdio-remote", M.config.remote, "--name", M.config.name, "--token", token }
+    else
+        log.log("Connecting to " .. M.config.address .. ":" .. M.config.port)
+        cmd = {
+            bin, "--port", M.config.port, "connect", "--address", M.config.address,
+            "--name", M.config.name, "--token", token,
+        }
+    end
+    if M.config.color then
+        table.insert(cmd, "--color")
+        table.insert(cmd, M.config.color)
+    end
+    if M.config.room then
+        table.insert(cmd, "--room")
+        table.insert(cmd, M.config.room)
+    end
+    if M.config.role then
+        table.insert(cmd, "--role")
+        table.insert(cmd, M.config.role)
+    end
+
+    start(cmd)
+end
+
+-- hosts a session on the configured port and joins it, no token needed
+function M.share()
+    if M._client_job then return end
+
+    local bin = vim.fn.exepath(M.config.binary_path)
+    if bin == "" then return print("Binary not found!") end
+
+    log.log("Sharing on port " .. M.config.port)
+
+    local cmd = { bin, "--port", M.config.port, "share", "--name", M.config.name }
+    if M.config.token then
+        table.insert(cmd, "--token")
+        table.insert(cmd, M.config.token)
+    end
+    if M.config.color then
+        table.insert(cmd, "--color")
+        table.insert(cmd, M.config.color)
+    end
+    start(cmd)
+end
+
+function M.list_files(directory)
+    if not M._client_job or not M._sent_open then return print("Not connected") end
+
+    local payload = vim.mpack.encode({ directory = directory or "" })
+    local length = encode_length(#payload)
+    M._client_job:write(length .. payload)
+end
+
+function M.open(name)
+    if not M._client_job or not M._sent_open then return print("Not connected") end
+
+    if M._known_buffers[name] then
+        local bufnr = vim.fn.bufnr(name)
+        if bufnr ~= -1 then vim.api.nvim_set_current_buf(bufnr) end
+        return
+    end
+    M._known_buffers[name] = true
+    M._opening[name] = true
+    send_plugin_open({ name })
+end
+
+function M.create(name)
+    if not M._client_job or not M._sent_open then return print("Not connected") end
+
+    name = normalize_buffer_name(name)
+    M._creating[name] = true
+    write_message({ create = name })
+end
+
+-- renames the current buffer for everyone once the server agrees
+function M.rename(to)
+    if not M._client_job or not M._sent_open then return print("Not connected") end
+
+    local from = normalize_buffer_name(vim.api.nvim_buf_get_name(0))
+    if not M._managed_buffers[from] then return print("Buffer is not shared") end
+    write_message({ rename = from, to = normalize_buffer_name(to) })
+end
+
+function M.delete()
+    if not M._client_job or not M._sent_open then return print("Not connected") end
+
+    local name = normalize_buffer_name(vim.api.nvim_buf_get_name(0))
+    if not M._managed_buffers[name] then return print("Buffer is not shared") end
+    write_message({ delete = name })
 end
 
 function M.stop()
@@ -245,6 +635,14 @@ function M.stop()
         M._client_job = nil
     end
     M._sent_open = false
+    M._edit_ready = {}
+    M._opening = {}
+    M._creating = {}
+    M._deleted = {}
+    for _, mark in pairs(M._peer_marks) do
+        pcall(vim.api.nvim_buf_clear_namespace, mark.bufnr, M._peer_ns, 0, -1)
+    end
+    M._peer_marks = {}
 end
 
 return M
@@ -1,6 +1,30 @@
 if vim.g.loaded_neo_live then return end
 vim.g.loaded_neo_live = 1
 
-vim.api.nvim_create_user_command("LiveConnect", function()
-    require("neo-live").connect()
+vim.api.nvim_create_user_command("LiveConnect", function(opts)
+    require("neo-live").connect(opts.args)
+end, { nargs = "?" })
+
+vim.api.nvim_create_user_command("LiveShare", function()
+    require("neo-live").share()
+end, {})
+
+vim.api.nvim_create_user_command("LiveFiles", function(opts)
+    require("neo-live").list_files(opts.args)
+end, { nargs = "?" })
+
+vim.api.nvim_create_user_command("LiveOpen", function(opts)
+    require("neo-live").open(opts.args)
+end, { nargs = 1 })
+
+vim.api.nvim_create_user_command("LiveCreate", function(opts)
+    require("neo-live").create(opts.args)
+end, { nargs = 1, complete = "file" })
+
+vim.api.nvim_create_user_command("LiveRename", function(opts)
+    require("neo-live").rename(opts.args)
+end, { nargs = 1, complete = "file" })
+
+vim.api.nvim_create_user_command("LiveDelete", function()
+    require("neo-live").delete()
 end, {})
@@ -0,0 +1,352 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+
+use crate::protocol::Role;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const TOKEN_LEN: usize = 16;
+const FINGERPRINT_LEN: usize = 16;
+
+// the client sends a fresh ephemeral key, the server answers with one of its own and its static
+// key, and the role token goes in as a pre-shared key at the end of that answer
+const NOISE_PARAMS: &str = "Noise_NXpsk2_25519_ChaChaPoly_SHA256";
+const MAX_NOISE_MESSAGE: usize = 65535;
+const PROLOGUE_LABEL: &[u8] = b"neo-live handshake";
+const PSK_LABEL: &[u8] = b"neo-live psk";
+const SERVER_KEY_LABEL: &[u8] = b"neo-live server key";
+const RESUME_LABEL: &[u8] = b"neo-live resume";
+
+/// Random token for sessions started without `--token`, hex encoded so it's easy to paste.
+pub fn generate_token() -> String {
+    random_bytes(TOKEN_LEN)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Token for joining in `role`. Owners use the session token itself, the others get one
+/// derived from it, so handing out a viewer token gives away nothing more.
+pub fn role_token(token: &str, role: Role) -> String {
+    let label: &[u8] = match role {
+        Role::Owner => return token.to_owned(),
+        Role::Editor => b"neo-live editor",
+        Role::Viewer => b"neo-live viewer",
+    };
+    let mut mac =
+        HmacSha256::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(label);
+    mac.finalize().into_bytes()[..TOKEN_LEN]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Role whose token `given` is, for peers that hand over the token itself instead of proving
+/// they know it. Compared in constant time.
+pub fn role_for_token(token: &str, given: &str) -> Option<Role> {
+    [Role::Owner, Role::Editor, Role::Viewer]
+        .into_iter()
+        .find(|&role| constant_time_eq(role_token(token, role).as_bytes(), given.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Fresh Yrs client id. Kept within 32 bits like the ids Yrs picks itself, which also keeps it
+/// exact for JavaScript peers.
+pub fn client_id() -> u64 {
+    let mut bytes = [0u8; 4];
+    getrandom::getrandom(&mut bytes).expect("Failed to read from the system RNG");
+    u32::from_be_bytes(bytes) as u64
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes).expect("Failed to read from the system RNG");
+    bytes
+}
+
+/// Secret a client is handed along with its id in `room`, to claim the id back with when it
+/// reconnects. Derived from the session token rather than stored, so it outlives the room and
+/// the server process both.
+pub fn resume_secret(token: &str, room: &str, client_id: u64) -> Vec<u8> {
+    let mut mac =
+        HmacSha256::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(RESUME_LABEL);
+    mac.update(room.as_bytes());
+    mac.update(&client_id.to_be_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// What a reconnecting client sends to show it holds the resume secret, over the transcript of
+/// the handshake so it can't be replayed on another connection.
+pub fn resume_proof(secret: &[u8], transcript: &[u8]) -> Vec<u8> {
+    resume_mac(secret, transcript)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+pub fn verify_resume(secret: &[u8], transcript: &[u8], proof: &[u8]) -> bool {
+    resume_mac(secret, transcript).verify_slice(proof).is_ok()
+}
+
+fn resume_mac(secret: &[u8], transcript: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(RESUME_LABEL);
+    mac.update(transcript);
+    mac
+}
+
+/// Keys for the encrypted stream, one per direction.
+pub struct SessionKeys {
+    pub client_to_server: [u8; 32],
+    pub server_to_client: [u8; 32],
+    /// Hash of everything the handshake exchanged, the same on both ends and on no other
+    /// connection.
+    pub transcript: Vec<u8>,
+}
+
+/// One end of the Noise handshake that keys a connection. Both sides pick a fresh ephemeral key
+/// for it, so what went over a connection stays unreadable to anyone who records it, even with
+/// the token. The role token is mixed in as a pre-shared key, which makes the handshake fail
+/// unless both ends hold the same one.
+pub struct Handshake {
+    state: snow::HandshakeState,
+}
+
+impl Handshake {
+    /// The client's end, joining `room` with `token`, the token of the role it asks for.
+    pub fn client(token: &str, room: &str) -> Option<Self> {
+        let psk = psk(token);
+        let prologue = [PROLOGUE_LABEL, room.as_bytes()].concat();
+        let state = snow::Builder::new(NOISE_PARAMS.parse().ok()?)
+            .psk(2, &psk)
+            .prologue(&prologue)
+            .build_initiator()
+            .ok()?;
+        Some(Self { state })
+    }
+
+    /// The server's end, for a client asking to join `room` as `role`.
+    pub fn server(session_token: &str, room: &str, role: Role) -> Option<Self> {
+        let psk = psk(&role_token(session_token, role));
+        let prologue = [PROLOGUE_LABEL, room.as_bytes()].concat();
+        let key = server_key(session_token);
+        let state = snow::Builder::new(NOISE_PARAMS.parse().ok()?)
+            .local_private_key(&key)
+            .psk(2, &psk)
+            .prologue(&prologue)
+            .build_responder()
+            .ok()?;
+        Some(Self { state })
+    }
+
+    /// The next message for the other end.
+    pub fn write(&mut self) -> Option<Vec<u8>> {
+        let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+        let len = self.state.write_message(&[], &mut message).ok()?;
+        message.truncate(len);
+        Some(message)
+    }
+
+    /// Takes in the other end's message. On the client this fails unless the server holds the
+    /// same role token.
+    pub fn read(&mut self, message: &[u8]) -> Option<()> {
+        let mut payload = vec![0u8; message.len()];
+        self.state.read_message(message, &mut payload).ok()?;
+        Some(())
+    }
+
+    /// Fingerprint of the static key the server answered with, on the client once it read that
+    /// answer. Anyone holding the role token can answer the handshake, only the session's own
+    /// server has this key.
+    pub fn server_fingerprint(&self) -> Option<Vec<u8>> {
+        self.state.get_remote_static().map(fingerprint)
+    }
+
+    /// The keys both ends come out with, once each sent its message.
+    pub fn finish(mut self) -> Option<SessionKeys> {
+        if !self.state.is_handshake_finished() {
+            return None;
+        }
+        let transcript = self.state.get_handshake_hash().to_vec();
+        let (client_to_server, server_to_client) = self.state.dangerously_get_raw_split();
+        Some(SessionKeys {
+            client_to_server,
+            server_to_client,
+            transcript,
+        })
+    }
+}
+
+// noise takes a key of 32 bytes, tokens are whatever was passed to --token
+fn psk(token: &str) -> [u8; 32] {
+    derive(token, PSK_LABEL)
+}
+
+// the server's static key. derived from the session token so it stays the same across restarts
+fn server_key(session_token: &str) -> [u8; 32] {
+    derive(session_token, SERVER_KEY_LABEL)
+}
+
+/// Fingerprint of the static key the server of a session answers handshakes with, for invites to
+/// pin.
+pub fn server_fingerprint(session_token: &str) -> Vec<u8> {
+    let mut dh = DefaultResolver
+        .resolve_dh(&DHChoice::Curve25519)
+        .expect("the default resolver does Curve25519");
+    dh.set(&server_key(session_token));
+    fingerprint(dh.pubkey())
+}
+
+fn fingerprint(public_key: &[u8]) -> Vec<u8> {
+    Sha256::digest(public_key)[..FINGERPRINT_LEN].to_vec()
+}
+
+fn derive(token: &str, label: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, token.as_bytes())
+        .expand(label, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_tokens_differ() {
+        let token = generate_token();
+        assert_eq!(token.len(), TOKEN_LEN * 2);
+        assert_ne!(token, generate_token());
+    }
+
+    #[test]
+    fn role_tokens_differ_per_role() {
+        assert_eq!(role_token("secret", Role::Owner), "secret");
+        let editor = role_token("secret", Role::Editor);
+        let viewer = role_token("secret", Role::Viewer);
+        assert_eq!(editor.len(), TOKEN_LEN * 2);
+        assert_ne!(editor, viewer);
+        assert_eq!(viewer, role_token("secret", Role::Viewer));
+        assert_ne!(viewer, role_token("other", Role::Viewer));
+    }
+
+    #[test]
+    fn handed_over_tokens_map_to_their_role() {
+        let viewer = role_token("secret", Role::Viewer);
+        assert_eq!(role_for_token("secret", "secret"), Some(Role::Owner));
+        assert_eq!(role_for_token("secret", &viewer), Some(Role::Viewer));
+        assert_eq!(role_for_token("other", &viewer), None);
+        assert_eq!(role_for_token("secret", ""), None);
+    }
+
+    // a client with `token` and a server for `session_token`, with the two messages that went
+    // between them. the client has no keys if it didn't accept the server's answer
+    fn connect(
+        token: &str,
+        session_token: &str,
+        role: Role,
+    ) -> (Option<SessionKeys>, SessionKeys, [Vec<u8>; 2]) {
+        let mut client = Handshake::client(token, "main").unwrap();
+        let mut server = Handshake::server(session_token, "main", role).unwrap();
+        let hello = client.write().unwrap();
+        server.read(&hello).unwrap();
+        let welcome = server.write().unwrap();
+        let keys = client.read(&welcome).and_then(|()| client.finish());
+        (keys, server.finish().unwrap(), [hello, welcome])
+    }
+
+    #[test]
+    fn both_ends_agree_on_fresh_keys() {
+        let token = role_token("secret", Role::Editor);
+        let (client, server, _) = connect(&token, "secret", Role::Editor);
+        let client = client.unwrap();
+        assert_eq!(client.client_to_server, server.client_to_server);
+        assert_eq!(client.server_to_client, server.server_to_client);
+        assert_eq!(client.transcript, server.transcript);
+        assert_ne!(client.client_to_server, client.server_to_client);
+
+        let (again, _, _) = connect(&token, "secret", Role::Editor);
+        assert_ne!(again.unwrap().client_to_server, client.client_to_server);
+    }
+
+    #[test]
+    fn handshake_fails_unless_both_ends_hold_the_token() {
+        assert!(connect("guess", "secret", Role::Owner).0.is_none());
+        // a viewer's token doesn't get anyone in as an editor
+        let viewer = role_token("secret", Role::Viewer);
+        assert!(connect(&viewer, "secret", Role::Editor).0.is_none());
+
+        // nor into another room than the one the client meant
+        let mut client = Handshake::client("secret", "main").unwrap();
+        let mut server = Handshake::server("secret", "other", Role::Owner).unwrap();
+        assert!(server.read(&client.write().unwrap()).is_none());
+    }
+
+    #[test]
+    fn holding_the_token_is_not_enough_to_read_along() {
+        let (keys, _, [hello, welcome]) = connect("secret", "secret", Role::Owner);
+        let keys = keys.unwrap();
+
+        // someone who saw both messages go by, with the token but neither end's ephemeral key
+        let mut client = Handshake::client("secret", "main").unwrap();
+        client.write().unwrap();
+        assert!(client.read(&welcome).is_none());
+
+        let mut server = Handshake::server("secret", "main", Role::Owner).unwrap();
+        server.read(&hello).unwrap();
+        server.write().unwrap();
+        let theirs = server.finish().unwrap();
+        assert_ne!(theirs.client_to_server, keys.client_to_server);
+        assert_ne!(theirs.server_to_client, keys.server_to_client);
+    }
+
+    #[test]
+    fn only_the_session_holds_the_server_key() {
+        let token = role_token("secret", Role::Editor);
+        let mut client = Handshake::client(&token, "main").unwrap();
+        let mut server = Handshake::server("secret", "main", Role::Editor).unwrap();
+        server.read(&client.write().unwrap()).unwrap();
+        client.read(&server.write().unwrap()).unwrap();
+        assert_eq!(
+            client.server_fingerprint(),
+            Some(server_fingerprint("secret"))
+        );
+
+        // any other editor could answer in the server's place, the token is all it takes. it
+        // has no way to the server's key though
+        let mut client = Handshake::client(&token, "main").unwrap();
+        let mut impostor = Handshake::server(&token, "main", Role::Owner).unwrap();
+        impostor.read(&client.write().unwrap()).unwrap();
+        client.read(&impostor.write().unwrap()).unwrap();
+        assert_ne!(
+            client.server_fingerprint(),
+            Some(server_fingerprint("secret"))
+        );
+    }
+
+    #[test]
+    fn resume_secrets_are_bound_to_room_and_id() {
+        let secret = resume_secret("secret", "main", 7);
+        assert_eq!(secret, resume_secret("secret", "main", 7));
+        assert_ne!(secret, resume_secret("secret", "main", 8));
+        assert_ne!(secret, resume_secret("secret", "other", 7));
+        assert_ne!(secret, resume_secret("guess", "main", 7));
+
+        let (_, keys, _) = connect("secret", "secret", Role::Owner);
+        let proof = resume_proof(&secret, &keys.transcript);
+        assert!(verify_resume(&secret, &keys.transcript, &proof));
+        let other = resume_secret("secret", "main", 8);
+        assert!(!verify_resume(&other, &keys.transcript, &proof));
+        let (_, elsewhere, _) = connect("secret", "secret", Role::Owner);
+        assert!(!verify_resume(&secret, &elsewhere.transcript, &proof));
+    }
+}
@@ -1,20 +1,158 @@
 use std::collections::HashMap;
-use std::net::SocketAddrV4;
+use std::future::Future;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::process::Stdio;
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::{error, info, trace};
-use tokio::io::{self, AsyncWriteExt};
+use log::{error, info, trace, warn};
+use tokio::io::{self, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::process::Command;
 use tokio::sync::{mpsc, Mutex, Notify, RwLock};
 use tokio::task;
 
 use yrs::updates::decoder::Decode;
 use yrs::updates::encoder::Encode;
-use yrs::{Doc, GetString, ReadTxn, StateVector, Text, Transact, Update};
+use yrs::{
+    Assoc, Doc, GetString, IndexedSequence, Observable, ReadTxn, StateVector, StickyIndex, Text,
+    Transact, Update,
+};
 
-use crate::protocol::{self, FrameReader, MessageKind, PluginOpen, PluginUpdate, SyncMessage};
+use crate::auth;
+use crate::diff;
+use crate::protocol::{
+    self, Authenticate, Authenticated, AwarenessUpdate, ErrorMessage, FileList, FrameError,
+    FrameReader, Hello, ListFiles, MessageKind, PeerState, Permissions, PluginCreate, PluginCursor,
+    PluginDelete, PluginEdit, PluginError, PluginFileList, PluginListFiles, PluginOpen,
+    PluginPatch, PluginPeerCursor, PluginRename, PluginShutdown, PluginUpdate, Position,
+    RenameBuffer, Role, ServerShutdown, SyncMessage, Welcome,
+};
+use crate::secure::{SecureReader, SecureWriter};
+use crate::websocket;
 
 const CHANNEL_SIZE: usize = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const CANDIDATE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How this client presents itself to the other peers.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    pub name: String,
+    pub color: Option<String>,
+    /// Session token the server was started with
+    pub token: String,
+    /// Room to join on the server
+    pub room: String,
+    /// Role the token is for
+    pub role: Role,
+    /// Fingerprint of the server's key, as invites carry it. Servers without that key are
+    /// refused
+    pub server_fingerprint: Option<Vec<u8>>,
+    /// Largest frame in bytes taken from the server or the plugin. Anything larger ends the
+    /// session
+    pub max_frame: usize,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            name: "anonymous".to_owned(),
+            color: None,
+            token: String::new(),
+            room: protocol::DEFAULT_ROOM.to_owned(),
+            role: Role::default(),
+            server_fingerprint: None,
+            max_frame: protocol::DEFAULT_MAX_FRAME,
+        }
+    }
+}
+
+async fn write_plugin_message<WOut>(
+    output: &mut WOut,
+    msg: &impl serde::Serialize,
+) -> Result<(), ()>
+where
+    WOut: tokio::io::AsyncWrite + Unpin,
+{
+    let framed = match protocol::encode_frame(msg) {
+        Some(framed) => framed,
+        None => {
+            error!("Failed to encode plugin message");
+            return Ok(());
+        }
+    };
+
+    if let Err(e) = output.write_all(&framed).await {
+        error!("Failed to write to stdout: {}", e);
+        return Err(());
+    }
+    if let Err(e) = output.flush().await {
+        error!("Failed to flush stdout: {}", e);
+        return Err(());
+    }
+    Ok(())
+}
+
+// applies a remote update, collecting what it did to each of the `watched` buffers along with
+// their text from before, so the plugin can patch only those regions
+fn apply_observed(
+    doc: &Doc,
+    update: Update,
+    watched: Vec<String>,
+) -> HashMap<String, (String, Vec<diff::TextChange<'static>>)> {
+    let collected = Arc::new(std::sync::Mutex::new(HashMap::new()));
+    let mut subscriptions = Vec::new();
+    for name in watched {
+        let text = doc.get_or_insert_text(name.as_str());
+        let old_text = text.get_string(&doc.transact());
+        let sink = Arc::clone(&collected);
+        if let Ok(mut collected) = sink.lock() {
+            collected.insert(name.clone(), (old_text, Vec::new()));
+        }
+        subscriptions.push(text.observe(move |txn, event| {
+            if let Ok(mut collected) = sink.lock() {
+                if let Some((_, changes)) = collected.get_mut(&name) {
+                    changes.extend(diff::from_delta(event.delta(txn)));
+                }
+            }
+        }));
+    }
+    {
+        let mut txn = doc.transact_mut();
+        let _ = txn.apply_update(update);
+    }
+    drop(subscriptions);
+
+    let collected = match collected.lock() {
+        Ok(mut collected) => std::mem::take(&mut *collected),
+        Err(_) => HashMap::new(),
+    };
+    collected
+}
+
+// server connection once the handshake is done and the stream is encrypted
+type ServerReader<RH> = FrameReader<SecureReader<BufReader<RH>>>;
+
+struct Connection<RH, WH> {
+    reader: ServerReader<RH>,
+    writer: SecureWriter<WH>,
+    client_id: u64,
+    permissions: Permissions,
+    resume_secret: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum HandshakeError {
+    // the connection went away before the server answered, worth trying again
+    Closed,
+    // the server turned us down, trying again won't help
+    Rejected,
+}
 
 #[derive(Clone)]
 struct BufferState {
@@ -28,9 +166,21 @@ struct ClientContext<W>
 where
     W: tokio::io::AsyncWrite + Unpin + Send + 'static,
 {
-    doc: Arc<Doc>,
+    // swapped for a fresh one when the server refuses one of our updates
+    doc: Arc<RwLock<Arc<Doc>>>,
     buffers: Arc<RwLock<HashMap<String, BufferState>>>,
-    write: Arc<Mutex<W>>,
+    // None while disconnected from the server
+    write: Arc<Mutex<Option<SecureWriter<W>>>>,
+    options: Arc<ClientOptions>,
+    // latest awareness of every other peer, replayed once the buffer it points into is synced
+    peers: Arc<RwLock<HashMap<u64, (String, PeerState)>>>,
+    // old name to new of every buffer renamed this session, for plugin messages that were
+    // already on their way
+    renamed: Arc<RwLock<HashMap<String, String>>>,
+    // what the server lets us change, as of the last handshake
+    permissions: Arc<RwLock<Permissions>>,
+    // proves to the server that our client id is ours when we reconnect
+    resume_secret: Arc<Vec<u8>>,
 }
 
 impl<W> Clone for ClientContext<W>
@@ -42,6 +192,11 @@ where
             doc: Arc::clone(&self.doc),
             buffers: Arc::clone(&self.buffers),
             write: Arc::clone(&self.write),
+            options: Arc::clone(&self.options),
+            peers: Arc::clone(&self.peers),
+            renamed: Arc::clone(&self.renamed),
+            permissions: Arc::clone(&self.permissions),
+            resume_secret: Arc::clone(&self.resume_secret),
         }
     }
 }
@@ -50,14 +205,34 @@ impl<W> ClientContext<W>
 where
     W: tokio::io::AsyncWrite + Unpin + Send + 'static,
 {
-    fn new(write: W) -> Self {
+    // the doc edits under the id the server handed out, so that id stays ours for the whole
+    // session, across reconnects too
+    fn new(
+        options: ClientOptions,
+        client_id: u64,
+        permissions: Permissions,
+        resume_secret: Vec<u8>,
+    ) -> Self {
         Self {
-            doc: Arc::new(Doc::new()),
+            doc: Arc::new(RwLock::new(Arc::new(Doc::with_client_id(client_id)))),
             buffers: Arc::new(RwLock::new(HashMap::new())),
-            write: Arc::new(Mutex::new(write)),
+            write: Arc::new(Mutex::new(None)),
+            options: Arc::new(options),
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            renamed: Arc::new(RwLock::new(HashMap::new())),
+            permissions: Arc::new(RwLock::new(permissions)),
+            resume_secret: Arc::new(resume_secret),
         }
     }
 
+    async fn doc(&self) -> Arc<Doc> {
+        Arc::clone(&*self.doc.read().await)
+    }
+
+    async fn can_write(&self, buffer: &str) -> bool {
+        self.permissions.read().await.can_write(buffer)
+    }
+
     async fn send_message(&self, msg: &SyncMessage) -> Result<(), ()> {
         let framed = match protocol::encode_frame(msg) {
             Some(framed) => framed,
@@ -67,18 +242,86 @@ where
             }
         };
 
-        let mut writer = self.write.lock().await;
+        let mut guard = self.write.lock().await;
+        let Some(writer) = guard.as_mut() else {
+            trace!("Offline, holding {:?} for buffer {}", msg.kind, msg.buffer);
+            return Err(());
+        };
         if let Err(e) = writer.write_all(&framed).await {
             error!("Failed to write to server: {}", e);
+            *guard = None;
             return Err(());
         }
         if let Err(e) = writer.flush().await {
             error!("Failed to flush to server: {}", e);
+            *guard = None;
             return Err(());
         }
         Ok(())
     }
 
+    // drops the connection and everything that only made sense while connected. local edits
+    // stay in the doc and are pushed once reconnected
+    async fn go_offline<WOut>(&self, output: &mut WOut, announced: bool) -> Result<(), ()>
+    where
+        WOut: tokio::io::AsyncWrite + Unpin,
+    {
+        *self.write.lock().await = None;
+
+        // the server forgets everyone's cursor when we leave and replays them on rejoin
+        let peers: Vec<(u64, String)> = {
+            let mut peers = self.peers.write().await;
+            peers
+                .drain()
+                .map(|(peer, (buffer, _))| (peer, buffer))
+                .collect()
+        };
+        for (peer, buffer) in peers {
+            let cursor = PluginPeerCursor::new(peer, buffer, String::new(), None, None, None);
+            write_plugin_message(output, &cursor).await?;
+        }
+
+        // a server that said goodbye already told the user why it is gone
+        if announced {
+            return Ok(());
+        }
+        let error = PluginError::new("Lost connection to the server, reconnecting".to_owned());
+        write_plugin_message(output, &error).await
+    }
+
+    // after a reconnect, asks for whatever was missed in every tracked buffer and pushes what
+    // was edited while offline
+    async fn resync(&self) -> Result<(), ()> {
+        let buffer_names: Vec<String> = self.buffers.read().await.keys().cloned().collect();
+        let doc = self.doc().await;
+        let state_vector = doc.transact().state_vector().encode_v1();
+        for buffer_name in buffer_names {
+            let msg = SyncMessage::new(MessageKind::InitialSync, buffer_name, state_vector.clone());
+            self.send_message(&msg).await?;
+        }
+
+        // the room may have closed and reopened empty while we were away, so the server gets
+        // everything we have and not only what it hadn't seen yet. that spans every buffer we
+        // edited meanwhile, which a resync may and an update may not
+        let (update, state_vector) = {
+            let txn = doc.transact();
+            let state_vector = txn.state_vector();
+            if state_vector.is_empty() {
+                info!("Resynced with server");
+                return Ok(());
+            }
+            let update = txn.encode_diff_v1(&StateVector::default());
+            (update, state_vector.encode_v1())
+        };
+        let msg = SyncMessage::new(MessageKind::Resync, String::new(), update);
+        self.send_message(&msg).await?;
+        for state in self.buffers.write().await.values_mut() {
+            state.last_state_vector = state_vector.clone();
+        }
+        info!("Resynced with server");
+        Ok(())
+    }
+
     async fn ensure_buffer_synced(&self, buffer: &str) -> Result<(), ()> {
         let notify;
         let should_send;
@@ -104,14 +347,21 @@ where
             }
         }
 
+        // while offline the request goes out with the resync once reconnected
         if should_send {
             let msg = SyncMessage::new(MessageKind::InitialSync, buffer.to_owned(), Vec::new());
-            self.send_message(&msg).await?;
-            info!("Sent InitialSync to server for buffer {}", buffer);
+            if self.send_message(&msg).await.is_ok() {
+                info!("Sent InitialSync to server for buffer {}", buffer);
+            }
         }
 
         notify.notified().await;
-        Ok(())
+        // a buffer the server turned down is dropped before waiters are woken
+        if self.buffers.read().await.contains_key(buffer) {
+            Ok(())
+        } else {
+            Err(())
+        }
     }
 
     async fn handle_server_message<WOut>(
@@ -122,6 +372,39 @@ where
     where
         WOut: tokio::io::AsyncWrite + Unpin,
     {
+        if msg.kind == MessageKind::Awareness {
+            return self.handle_awareness(msg, output).await;
+        }
+        if msg.kind == MessageKind::Error {
+            return self.handle_error(msg, output).await;
+        }
+        if msg.kind == MessageKind::ServerShutdown {
+            let Some(notice) = msg.body::<ServerShutdown>() else {
+                return Ok(());
+            };
+            warn!("Server is shutting down: {}", notice.reason());
+            let shutdown = PluginShutdown::new(notice.reason().clone());
+            return write_plugin_message(output, &shutdown).await;
+        }
+        if msg.kind == MessageKind::CreateBuffer {
+            return write_plugin_message(output, &PluginCreate::new(msg.buffer)).await;
+        }
+        if msg.kind == MessageKind::RenameBuffer {
+            return self.handle_rename(msg, output).await;
+        }
+        if msg.kind == MessageKind::DeleteBuffer {
+            return self.handle_delete(msg, output).await;
+        }
+        if msg.kind == MessageKind::FileList {
+            let Some(list) = msg.body::<FileList>() else {
+                return Ok(());
+            };
+            let list = PluginFileList::new(list.directory().clone(), list.files().clone());
+            return write_plugin_message(output, &list).await;
+        }
+        if msg.kind == MessageKind::Resync {
+            return self.handle_resync(msg, output).await;
+        }
         if !msg.is_update() {
             trace!("Ignoring non-update message from server");
             return Ok(());
@@ -130,7 +413,32 @@ where
         let buffer_name = msg.buffer;
         let update_data = msg.payload;
 
+        let was_synced = {
+            let buffers = self.buffers.read().await;
+            buffers
+                .get(&buffer_name)
+                .map(|state| state.synced)
+                .unwrap_or(false)
+        };
+
+        let doc = self.doc().await;
+        let text = doc.get_or_insert_text(buffer_name.as_str());
+        let mut old_text = String::new();
+        let mut changes = Vec::new();
+        let mut elsewhere = HashMap::new();
         if !update_data.is_empty() {
+            // the answer to a first sync carries the whole document, so any buffer the plugin
+            // already has may change along with this one. watch them all
+            let mut watched: Vec<String> = {
+                let buffers = self.buffers.read().await;
+                buffers
+                    .iter()
+                    .filter(|(name, state)| state.synced && **name != buffer_name)
+                    .map(|(name, _)| name.clone())
+                    .collect()
+            };
+            watched.push(buffer_name.clone());
+
             let update = match Update::decode_v1(&update_data) {
                 Ok(update) => update,
                 Err(e) => {
@@ -138,13 +446,13 @@ where
                     return Ok(());
                 }
             };
-            let mut txn = self.doc.transact_mut();
-            let _ = txn.apply_update(update);
+            let mut collected = apply_observed(&doc, update, watched);
+            (old_text, changes) = collected.remove(&buffer_name).unwrap_or_default();
+            elsewhere = collected;
         }
 
         let (text_content, state_vector) = {
-            let text = self.doc.get_or_insert_text(buffer_name.as_str());
-            let txn = self.doc.transact();
+            let txn = doc.transact();
             (text.get_string(&txn), txn.state_vector().encode_v1())
         };
 
@@ -162,49 +470,496 @@ where
             entry.notify.notify_waiters();
         }
 
-        let plugin_update = PluginUpdate::new(0, 0, buffer_name, text_content);
-        let framed = match protocol::encode_frame(&plugin_update) {
-            Some(framed) => framed,
-            None => {
-                error!("Failed to encode plugin update");
+        for (name, (old_text, changes)) in elsewhere {
+            self.patch_buffer(name, &old_text, &changes, output).await?;
+        }
+
+        // the plugin only has contents to patch once it received the buffer once
+        if was_synced {
+            return self
+                .patch_buffer(buffer_name, &old_text, &changes, output)
+                .await;
+        }
+
+        let read_only = !self.can_write(&buffer_name).await;
+        let plugin_update =
+            PluginUpdate::new(0, 0, buffer_name.clone(), text_content).read_only(read_only);
+        write_plugin_message(output, &plugin_update).await?;
+        trace!("Sent PluginUpdate to stdout");
+
+        self.replay_peer_cursors(&buffer_name, output).await
+    }
+
+    // another client's offline edits, which may reach into any buffer the plugin has
+    async fn handle_resync<WOut>(&self, msg: SyncMessage, output: &mut WOut) -> Result<(), ()>
+    where
+        WOut: tokio::io::AsyncWrite + Unpin,
+    {
+        let watched: Vec<String> = {
+            let buffers = self.buffers.read().await;
+            buffers
+                .iter()
+                .filter(|(_, state)| state.synced)
+                .map(|(name, _)| name.clone())
+                .collect()
+        };
+        let doc = self.doc().await;
+        let update = match Update::decode_v1(&msg.payload) {
+            Ok(update) => update,
+            Err(e) => {
+                error!("Failed to decode resync from server: {}", e);
                 return Ok(());
             }
         };
+        for (name, (old_text, changes)) in apply_observed(&doc, update, watched) {
+            self.patch_buffer(name, &old_text, &changes, output).await?;
+        }
+        Ok(())
+    }
 
-        if let Err(e) = output.write_all(&framed).await {
-            error!("Failed to write PluginUpdate to stdout: {}", e);
-            return Err(());
+    // hands the plugin what a remote transaction did to a buffer it already has, as line edits
+    // where they fit and the whole text where they don't
+    async fn patch_buffer<WOut>(
+        &self,
+        buffer_name: String,
+        old_text: &str,
+        changes: &[diff::TextChange<'_>],
+        output: &mut WOut,
+    ) -> Result<(), ()>
+    where
+        WOut: tokio::io::AsyncWrite + Unpin,
+    {
+        if changes.is_empty() {
+            trace!("No changes to buffer {}", buffer_name);
+            return Ok(());
         }
-        if let Err(e) = output.flush().await {
-            error!("Failed to flush PluginUpdate to stdout: {}", e);
-            return Err(());
+        if let Some(edits) = diff::to_line_edits(old_text, changes) {
+            let patch = PluginPatch::new(buffer_name, edits);
+            write_plugin_message(output, &patch).await?;
+            trace!("Sent PluginPatch to stdout");
+            return Ok(());
         }
+        error!(
+            "Remote changes don't fit buffer {}, sending it whole",
+            buffer_name
+        );
 
+        let doc = self.doc().await;
+        let text = doc.get_or_insert_text(buffer_name.as_str());
+        let text_content = text.get_string(&doc.transact());
+        let read_only = !self.can_write(&buffer_name).await;
+        let plugin_update = PluginUpdate::new(0, 0, buffer_name, text_content).read_only(read_only);
+        write_plugin_message(output, &plugin_update).await?;
         trace!("Sent PluginUpdate to stdout");
         Ok(())
     }
 
+    // errors about a buffer mean the server won't sync it, so it stops being tracked. a refused
+    // update instead means our doc went somewhere the server's won't follow
+    async fn handle_error<WOut>(&self, msg: SyncMessage, output: &mut WOut) -> Result<(), ()>
+    where
+        WOut: tokio::io::AsyncWrite + Unpin,
+    {
+        let error = msg.body::<ErrorMessage>();
+        let reason = error
+            .as_ref()
+            .map(|e| e.message().clone())
+            .unwrap_or_else(|| "unknown error".to_owned());
+        warn!("Server error for buffer {:?}: {}", msg.buffer, reason);
+
+        if error.is_some_and(|e| e.update_refused()) {
+            let _ = self.rebuild().await;
+        } else if !msg.buffer.is_empty() {
+            if let Some(state) = self.buffers.write().await.remove(&msg.buffer) {
+                state.notify.notify_waiters();
+            }
+        }
+        let error = PluginError::new(format!("Server error: {}", reason));
+        write_plugin_message(output, &error).await
+    }
+
+    // starts over from the server's doc. the refused changes stay in ours, and everything we
+    // edited after builds on them, so the server would refuse that too. a fresh doc under the
+    // same id picks up our clock where the server last saw it, and every buffer goes to the
+    // plugin whole again once the server answered for it
+    async fn rebuild(&self) -> Result<(), ()> {
+        let buffer_names: Vec<String> = {
+            let mut buffers = self.buffers.write().await;
+            // the first refusal already started this. the ones that were on their way behind it
+            // are answered along with it
+            if !buffers.is_empty() && buffers.values().all(|state| !state.synced) {
+                return Ok(());
+            }
+            for state in buffers.values_mut() {
+                state.synced = false;
+                state.syncing = true;
+                state.last_state_vector.clear();
+            }
+            buffers.keys().cloned().collect()
+        };
+        {
+            let mut doc = self.doc.write().await;
+            *doc = Arc::new(Doc::with_client_id(doc.client_id()));
+        }
+        warn!("Server refused our changes, starting over from its document");
+
+        for buffer_name in buffer_names {
+            let msg = SyncMessage::new(MessageKind::InitialSync, buffer_name, Vec::new());
+            self.send_message(&msg).await?;
+        }
+        Ok(())
+    }
+
+    // applies the server's move of the text. whatever we edited that the server hadn't seen yet
+    // stayed behind under the old name, so it is redone under the new one
+    async fn handle_rename<WOut>(&self, msg: SyncMessage, output: &mut WOut) -> Result<(), ()>
+    where
+        WOut: tokio::io::AsyncWrite + Unpin,
+    {
+        let Some(rename) = msg.body::<RenameBuffer>() else {
+            return Ok(());
+        };
+        let (old, new) = (msg.buffer, rename.to().clone());
+        info!("{} was renamed to {}", old, new);
+
+        // edits to the new name wait until the text is moved over, ones to the old name are
+        // redirected
+        let synced = {
+            let mut buffers = self.buffers.write().await;
+            let state = buffers.remove(&old);
+            if let Some(state) = &state {
+                state.notify.notify_waiters();
+            }
+            match state {
+                Some(state) if state.synced => {
+                    let moving = BufferState {
+                        synced: false,
+                        syncing: true,
+                        ..state
+                    };
+                    buffers.insert(new.clone(), moving);
+                    true
+                }
+                _ => false,
+            }
+        };
+        {
+            let mut renamed = self.renamed.write().await;
+            renamed.remove(&new);
+            for to in renamed.values_mut() {
+                if *to == old {
+                    *to = new.clone();
+                }
+            }
+            renamed.insert(old.clone(), new.clone());
+        }
+
+        {
+            let doc = self.doc().await;
+            let source = doc.get_or_insert_text(old.as_str());
+            let target = doc.get_or_insert_text(new.as_str());
+            let local = source.get_string(&doc.transact());
+            let mut txn = doc.transact_mut();
+            match Update::decode_v1(rename.update()) {
+                Ok(update) => {
+                    let _ = txn.apply_update(update);
+                }
+                Err(e) => error!("Failed to decode rename of {} from server: {}", old, e),
+            }
+            // the plugin never had the buffer when it wasn't synced, so there is nothing to
+            // carry over
+            if synced {
+                let leftover = source.len(&txn);
+                if leftover > 0 {
+                    source.remove_range(&mut txn, 0, leftover);
+                }
+                let moved = target.get_string(&txn);
+                if let Some(change) = diff::diff(&moved, &local) {
+                    diff::apply_change(&target, &mut txn, &change);
+                }
+            }
+        }
+        if let Some(state) = self.buffers.write().await.get_mut(&new) {
+            // the state vector stays as it was, so the next send carries our edits too
+            state.synced = true;
+            state.syncing = false;
+            state.notify.notify_waiters();
+        }
+
+        self.forget_peers(&old, output).await?;
+        write_plugin_message(output, &PluginRename::new(old, new.clone())).await?;
+        let _ = self.send_local_changes(new).await;
+        Ok(())
+    }
+
+    async fn handle_delete<WOut>(&self, msg: SyncMessage, output: &mut WOut) -> Result<(), ()>
+    where
+        WOut: tokio::io::AsyncWrite + Unpin,
+    {
+        let buffer = msg.buffer;
+        info!("{} was deleted", buffer);
+        if !msg.payload.is_empty() {
+            let doc = self.doc().await;
+            match Update::decode_v1(&msg.payload) {
+                Ok(update) => {
+                    let mut txn = doc.transact_mut();
+                    let _ = txn.apply_update(update);
+                }
+                Err(e) => error!("Failed to decode deletion of {} from server: {}", buffer, e),
+            }
+        }
+
+        if let Some(state) = self.buffers.write().await.remove(&buffer) {
+            state.notify.notify_waiters();
+        }
+        self.renamed.write().await.retain(|_, to| *to != buffer);
+        self.forget_peers(&buffer, output).await?;
+        write_plugin_message(output, &PluginDelete::new(buffer)).await
+    }
+
+    // clears the cursors of peers in a buffer that went away
+    async fn forget_peers<WOut>(&self, buffer: &str, output: &mut WOut) -> Result<(), ()>
+    where
+        WOut: tokio::io::AsyncWrite + Unpin,
+    {
+        let gone: Vec<u64> = {
+            let mut peers = self.peers.write().await;
+            let gone = peers
+                .iter()
+                .filter(|(_, (name, _))| name == buffer)
+                .map(|(peer, _)| *peer)
+                .collect::<Vec<_>>();
+            for peer in &gone {
+                peers.remove(peer);
+            }
+            gone
+        };
+        for peer in gone {
+            let cursor =
+                PluginPeerCursor::new(peer, buffer.to_owned(), String::new(), None, None, None);
+            write_plugin_message(output, &cursor).await?;
+        }
+        Ok(())
+    }
+
+    // the name a buffer goes by now, for plugin messages sent before it heard of a rename
+    async fn current_name(&self, buffer: &str) -> String {
+        let renamed = self.renamed.read().await;
+        renamed
+            .get(buffer)
+            .cloned()
+            .unwrap_or_else(|| buffer.to_owned())
+    }
+
+    async fn handle_awareness<WOut>(&self, msg: SyncMessage, output: &mut WOut) -> Result<(), ()>
+    where
+        WOut: tokio::io::AsyncWrite + Unpin,
+    {
+        let Some(update) = msg.body::<AwarenessUpdate>() else {
+            return Ok(());
+        };
+        if update.client_id() == self.doc().await.client_id() {
+            return Ok(());
+        }
+
+        let Some(state) = update.state() else {
+            self.peers.write().await.remove(&update.client_id());
+            let cursor = PluginPeerCursor::new(
+                update.client_id(),
+                msg.buffer,
+                String::new(),
+                None,
+                None,
+                None,
+            );
+            return write_plugin_message(output, &cursor).await;
+        };
+
+        self.peers
+            .write()
+            .await
+            .insert(update.client_id(), (msg.buffer.clone(), state.clone()));
+
+        let synced = {
+            let buffers = self.buffers.read().await;
+            buffers
+                .get(&msg.buffer)
+                .map(|state| state.synced)
+                .unwrap_or(false)
+        };
+        if !synced {
+            trace!("Holding cursor for unsynced buffer {}", msg.buffer);
+            return Ok(());
+        }
+        self.send_peer_cursor(update.client_id(), msg.buffer, state, output)
+            .await
+    }
+
+    // resolves a peer's sticky cursor against our copy of the buffer
+    async fn send_peer_cursor<WOut>(
+        &self,
+        peer: u64,
+        buffer: String,
+        state: &PeerState,
+        output: &mut WOut,
+    ) -> Result<(), ()>
+    where
+        WOut: tokio::io::AsyncWrite + Unpin,
+    {
+        let doc = self.doc().await;
+        let (head, anchor) = {
+            let text = doc.get_or_insert_text(buffer.as_str());
+            let txn = doc.transact();
+            let content = text.get_string(&txn);
+            let resolve = |encoded: &Option<Vec<u8>>| {
+                let index = StickyIndex::decode_v1(encoded.as_ref()?).ok()?;
+                let offset = index.get_offset(&txn)?;
+                let offset = (offset.index as usize).min(content.len());
+                let (row, col) = diff::position(&content, offset);
+                Some(Position { row, col })
+            };
+            (resolve(&state.head), resolve(&state.anchor))
+        };
+
+        let cursor = PluginPeerCursor::new(
+            peer,
+            buffer,
+            state.name.clone(),
+            state.color.clone(),
+            head,
+            anchor,
+        );
+        write_plugin_message(output, &cursor).await
+    }
+
+    async fn replay_peer_cursors<WOut>(&self, buffer: &str, output: &mut WOut) -> Result<(), ()>
+    where
+        WOut: tokio::io::AsyncWrite + Unpin,
+    {
+        let peers: Vec<(u64, PeerState)> = {
+            let peers = self.peers.read().await;
+            peers
+                .iter()
+                .filter(|(_, (name, _))| name == buffer)
+                .map(|(peer, (_, state))| (*peer, state.clone()))
+                .collect()
+        };
+        for (peer, state) in peers {
+            self.send_peer_cursor(peer, buffer.to_owned(), &state, output)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_plugin_cursor(&self, cursor: PluginCursor) -> Result<(), ()> {
+        let buffer_name = self.current_name(cursor.buffer()).await;
+        let synced = {
+            let buffers = self.buffers.read().await;
+            buffers
+                .get(&buffer_name)
+                .map(|state| state.synced)
+                .unwrap_or(false)
+        };
+        if !synced {
+            trace!("Not sharing cursor in unsynced buffer {}", buffer_name);
+            return Ok(());
+        }
+
+        let doc = self.doc().await;
+        let (head, anchor) = {
+            let text = doc.get_or_insert_text(buffer_name.as_str());
+            let txn = doc.transact();
+            let len = text.len(&txn);
+            let sticky = |offset: u32| {
+                text.sticky_index(&txn, offset.min(len), Assoc::After)
+                    .map(|index| index.encode_v1())
+            };
+            (sticky(cursor.head()), sticky(cursor.anchor()))
+        };
+
+        let state = PeerState {
+            name: self.options.name.clone(),
+            color: self.options.color.clone(),
+            anchor,
+            head,
+        };
+        let update = AwarenessUpdate::new(doc.client_id(), Some(state));
+        let msg = SyncMessage::with_body(MessageKind::Awareness, buffer_name, &update).ok_or(())?;
+        self.send_message(&msg).await
+    }
+
     async fn handle_plugin_update(&self, plugin_update: PluginUpdate) -> Result<(), ()> {
-        let buffer_name = plugin_update.buffer().clone();
-        if buffer_name.is_empty() {
+        if plugin_update.buffer().is_empty() {
             error!("PluginUpdate missing buffer name");
             return Ok(());
         }
+        let buffer_name = self.current_name(plugin_update.buffer()).await;
+        if !self.can_write(&buffer_name).await {
+            warn!("Dropping PluginUpdate for read-only buffer {}", buffer_name);
+            return Ok(());
+        }
 
         self.ensure_buffer_synced(&buffer_name).await?;
 
-        let text_content = plugin_update.text().clone();
         {
-            let text = self.doc.get_or_insert_text(buffer_name.as_str());
-            let len = {
-                let txn = self.doc.transact();
-                text.len(&txn)
+            let doc = self.doc().await;
+            let text = doc.get_or_insert_text(buffer_name.as_str());
+            let current = {
+                let txn = doc.transact();
+                text.get_string(&txn)
             };
-            let mut txn = self.doc.transact_mut();
-            text.remove_range(&mut txn, 0, len);
-            text.insert(&mut txn, 0, &text_content);
+            if let Some(change) = diff::diff(&current, plugin_update.text()) {
+                let mut txn = doc.transact_mut();
+                diff::apply_change(&text, &mut txn, &change);
+            }
         }
 
+        self.send_local_changes(buffer_name).await
+    }
+
+    async fn handle_plugin_edit(&self, edit: PluginEdit) -> Result<(), ()> {
+        if edit.buffer().is_empty() {
+            error!("PluginEdit missing buffer name");
+            return Ok(());
+        }
+        let buffer_name = self.current_name(edit.buffer()).await;
+        if !self.can_write(&buffer_name).await {
+            warn!("Dropping PluginEdit for read-only buffer {}", buffer_name);
+            return Ok(());
+        }
+
+        self.ensure_buffer_synced(&buffer_name).await?;
+
+        {
+            let doc = self.doc().await;
+            let text = doc.get_or_insert_text(buffer_name.as_str());
+            let mut txn = doc.transact_mut();
+            let len = text.len(&txn) as usize;
+            let change = diff::from_line_edit(
+                len,
+                edit.start() as usize,
+                edit.removed() as usize,
+                edit.inserted(),
+            );
+            let Some(change) = change else {
+                error!(
+                    "PluginEdit {}+{} out of range for buffer {} of len {}",
+                    edit.start(),
+                    edit.removed(),
+                    buffer_name,
+                    len
+                );
+                return Ok(());
+            };
+            diff::apply_change(&text, &mut txn, &change);
+        }
+
+        self.send_local_changes(buffer_name).await
+    }
+
+    // sends everything the server hasn't seen yet for the buffer. while offline nothing is marked
+    // as sent, so the next call after reconnecting picks it all up
+    async fn send_local_changes(&self, buffer_name: String) -> Result<(), ()> {
         let last_state_vector = {
             let buffers = self.buffers.read().await;
             buffers
@@ -213,9 +968,10 @@ where
                 .unwrap_or_default()
         };
 
-        let update = {
-            let txn = self.doc.transact();
-            if last_state_vector.is_empty() {
+        let (update, state_vector) = {
+            let doc = self.doc().await;
+            let txn = doc.transact();
+            let update = if last_state_vector.is_empty() {
                 txn.encode_diff_v1(&StateVector::default())
             } else {
                 match StateVector::decode_v1(&last_state_vector) {
@@ -225,28 +981,56 @@ where
                         txn.encode_diff_v1(&StateVector::default())
                     }
                 }
-            }
+            };
+            (update, txn.state_vector().encode_v1())
         };
 
-        {
-            let mut buffers = self.buffers.write().await;
-            if let Some(state) = buffers.get_mut(&buffer_name) {
-                let txn = self.doc.transact();
-                state.last_state_vector = txn.state_vector().encode_v1();
-            }
-        }
-
         if update.is_empty() {
             trace!("Skipping empty update for buffer {}", buffer_name);
             return Ok(());
         }
 
-        let msg = SyncMessage::new(MessageKind::Update, buffer_name, update);
+        let msg = SyncMessage::new(MessageKind::Update, buffer_name.clone(), update);
         self.send_message(&msg).await?;
         trace!("Sent update to server");
+
+        // the update is a diff of the whole doc, so it caught every buffer up, not just this one
+        let mut buffers = self.buffers.write().await;
+        for state in buffers.values_mut() {
+            state.last_state_vector = state_vector.clone();
+        }
         Ok(())
     }
 
+    async fn handle_plugin_list_files(&self, request: PluginListFiles) -> Result<(), ()> {
+        let list = ListFiles::new(request.directory().clone());
+        let msg = SyncMessage::with_body(MessageKind::ListFiles, String::new(), &list).ok_or(())?;
+        self.send_message(&msg).await
+    }
+
+    // lifecycle requests only go out. the plugin hears back once the server announces the change
+    async fn handle_plugin_create(&self, request: PluginCreate) -> Result<(), ()> {
+        let msg = SyncMessage::new(
+            MessageKind::CreateBuffer,
+            request.create().clone(),
+            Vec::new(),
+        );
+        self.send_message(&msg).await
+    }
+
+    async fn handle_plugin_rename(&self, request: PluginRename) -> Result<(), ()> {
+        let buffer = self.current_name(request.rename()).await;
+        let rename = RenameBuffer::new(request.to().clone());
+        let msg = SyncMessage::with_body(MessageKind::RenameBuffer, buffer, &rename).ok_or(())?;
+        self.send_message(&msg).await
+    }
+
+    async fn handle_plugin_delete(&self, request: PluginDelete) -> Result<(), ()> {
+        let buffer = self.current_name(request.delete()).await;
+        let msg = SyncMessage::new(MessageKind::DeleteBuffer, buffer, Vec::new());
+        self.send_message(&msg).await
+    }
+
     async fn handle_open_buffers(&self, buffers: Vec<String>) {
         for buffer in buffers {
             let context = self.clone();
@@ -264,60 +1048,337 @@ where
 // TcpStream to server to read and write buffer updates
 // stdout to write updated contents to plugin
 // stdin to read changes from plugin
-pub async fn connect(read_socket: SocketAddrV4) {
-    let stream = match TcpStream::connect(read_socket).await {
-        Ok(stream) => stream,
-        Err(e) => {
-            error!("Failed to connect to remote: {}", e);
-            return;
+//
+// host can be an IPv4 or IPv6 address or a hostname. it is resolved again on every reconnect and
+// each address it resolves to is tried in turn
+pub async fn connect(host: String, port: u16, options: ClientOptions) {
+    connect_any(vec![host], port, options).await
+}
+
+/// Like `connect`, but with several addresses the server might be reached at, as an invite
+/// carries them. Each is tried in turn until one answers, again on every reconnect.
+pub async fn connect_any(hosts: Vec<String>, port: u16, options: ClientOptions) {
+    let connector = move || {
+        let hosts = hosts.clone();
+        async move {
+            let mut last_error =
+                io::Error::new(io::ErrorKind::NotFound, "no address to connect to");
+            for host in &hosts {
+                // an address from another network may never answer, so don't wait on it forever
+                let attempt = TcpStream::connect((host.as_str(), port));
+                match tokio::time::timeout(CANDIDATE_TIMEOUT, attempt).await {
+                    Ok(Ok(stream)) => return Ok(stream.into_split()),
+                    Ok(Err(e)) => {
+                        trace!("Could not reach {}:{}: {}", host, port, e);
+                        last_error = e;
+                    }
+                    Err(_) => {
+                        trace!("Timed out reaching {}:{}", host, port);
+                        last_error = io::Error::new(io::ErrorKind::TimedOut, "connect timed out");
+                    }
+                }
+            }
+            Err(last_error)
+        }
+    };
+    run_client(connector, io::stdin(), io::stdout(), options).await;
+}
+
+/// Like `connect`, but over a WebSocket at a `ws://` URL, for servers only reachable through a
+/// web proxy.
+pub async fn connect_websocket(url: String, options: ClientOptions) {
+    let max_frame = options.max_frame;
+    let connector = move || {
+        let url = url.clone();
+        async move {
+            let ws = websocket::connect(&url, max_frame).await?;
+            Ok(io::split(websocket::into_stream(ws)))
+        }
+    };
+    run_client(connector, io::stdin(), io::stdout(), options).await;
+}
+
+/// Like `connect`, but to a server on a Unix domain socket on this machine.
+#[cfg(unix)]
+pub async fn connect_unix(path: PathBuf, options: ClientOptions) {
+    let connector = move || {
+        let path = path.clone();
+        async move { Ok(UnixStream::connect(path).await?.into_split()) }
+    };
+    run_client(connector, io::stdin(), io::stdout(), options).await;
+}
+
+/// Like `connect`, but to whatever `command` talks to through its stdin and stdout, such as
+/// `ssh host neo-live serve --stdio`. The command is run through the shell again on every
+/// reconnect, and sees its stdin close when the connection is dropped.
+pub async fn connect_command(command: String, options: ClientOptions) {
+    let connector = move || {
+        let command = command.clone();
+        async move {
+            let mut child = shell(&command)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()?;
+            let missing = || io::Error::new(io::ErrorKind::BrokenPipe, "command has no pipes");
+            let stdout = child.stdout.take().ok_or_else(missing)?;
+            let stdin = child.stdin.take().ok_or_else(missing)?;
+            Ok((stdout, stdin))
         }
     };
-    let (read_half, write_half) = stream.into_split();
-    run_client(read_half, write_half, io::stdin(), io::stdout()).await;
+    run_client(connector, io::stdin(), io::stdout(), options).await;
 }
 
-pub async fn run_client<R, W, RH, WH>(
+fn shell(command: &str) -> Command {
+    let (program, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut shell = Command::new(program);
+    shell.arg(flag).arg(command);
+    shell
+}
+
+// sends Hello for our room over a fresh connection, opening a Noise handshake the server can only
+// answer if it holds our token. everything after its Welcome is encrypted, so the connection is
+// handed back wrapped. rejections are forwarded to the plugin so the user sees why the session
+// didn't start
+async fn handshake<RH, WH, WOut>(
     read_half: RH,
-    write_half: WH,
-    input: R,
-    output: W,
-) where
-    R: tokio::io::AsyncRead + Send + Unpin + 'static,
-    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
-    RH: tokio::io::AsyncRead + Send + Unpin + 'static,
-    WH: tokio::io::AsyncWrite + Send + Unpin + 'static,
+    mut write_half: WH,
+    options: &ClientOptions,
+    resume: Option<(u64, &[u8])>,
+    output: &mut WOut,
+) -> Result<Connection<RH, WH>, HandshakeError>
+where
+    RH: tokio::io::AsyncRead + Unpin,
+    WH: tokio::io::AsyncWrite + Unpin,
+    WOut: tokio::io::AsyncWrite + Unpin,
 {
-    // define reader for stream, stdin, stdout
-    let context = ClientContext::new(write_half);
+    let mut reader = FrameReader::new(read_half);
+
+    let mut noise =
+        auth::Handshake::client(&options.token, &options.room).ok_or(HandshakeError::Rejected)?;
+    let opening = noise.write().ok_or(HandshakeError::Rejected)?;
+    let hello = Hello::current()
+        .in_room(options.room.clone())
+        .as_role(options.role)
+        .resuming(resume.map(|(client_id, _)| client_id))
+        .opening(opening);
+    let msg = SyncMessage::with_body(MessageKind::Hello, String::new(), &hello)
+        .ok_or(HandshakeError::Rejected)?;
+    write_handshake_message(&mut write_half, &msg).await?;
+
+    let msg = read_handshake_reply(&mut reader, output, MessageKind::Welcome).await?;
+    let welcome: Welcome = msg.body().ok_or(HandshakeError::Rejected)?;
+    info!(
+        "Connected to neo-live {} with capabilities {:?}",
+        welcome.crate_version(),
+        welcome.capabilities()
+    );
+    // older servers ignore the room and would put us in their only session
+    if options.room != protocol::DEFAULT_ROOM
+        && !welcome.capabilities().iter().any(|c| c == "rooms")
+    {
+        let error = PluginError::new(format!(
+            "Server does not support rooms, cannot join room {}",
+            options.room
+        ));
+        let _ = write_plugin_message(output, &error).await;
+        return Err(HandshakeError::Rejected);
+    }
+
+    // a wrong token and a server that isn't the session's look the same from here
+    if noise.read(welcome.handshake()).is_none() {
+        warn!("Server did not answer the handshake for our token");
+        let error = PluginError::new(
+            "Server did not accept the token for this role, not joining".to_owned(),
+        );
+        let _ = write_plugin_message(output, &error).await;
+        return Err(HandshakeError::Rejected);
+    }
+    // others holding the same token could answer too, only the session's server has its key
+    if let Some(expected) = &options.server_fingerprint {
+        if noise.server_fingerprint().as_ref() != Some(expected) {
+            warn!("Server answered with a key other than the invite's");
+            let error =
+                PluginError::new("Server is not the one the invite is for, not joining".to_owned());
+            let _ = write_plugin_message(output, &error).await;
+            return Err(HandshakeError::Rejected);
+        }
+    }
+    let keys = noise.finish().ok_or(HandshakeError::Rejected)?;
+    let mut reader = FrameReader::new(SecureReader::new(
+        reader.into_inner(),
+        &keys.server_to_client,
+    ))
+    .with_max_frame(options.max_frame);
+    let mut writer = SecureWriter::new(write_half, &keys.client_to_server);
+
+    let resume_proof = resume.map(|(_, secret)| auth::resume_proof(secret, &keys.transcript));
+    let authenticate = Authenticate::new(resume_proof);
+    let msg = SyncMessage::with_body(MessageKind::Authenticate, String::new(), &authenticate)
+        .ok_or(HandshakeError::Rejected)?;
+    write_handshake_message(&mut writer, &msg).await?;
+
+    let msg = read_handshake_reply(&mut reader, output, MessageKind::Authenticated).await?;
+    let authenticated: Authenticated = msg.body().ok_or(HandshakeError::Rejected)?;
+    info!(
+        "Authenticated with server as client {}",
+        authenticated.client_id()
+    );
+
+    Ok(Connection {
+        reader,
+        writer,
+        client_id: authenticated.client_id(),
+        permissions: authenticated.permissions().clone(),
+        resume_secret: authenticated.resume_secret().clone(),
+    })
+}
+
+async fn write_handshake_message<WH>(
+    write_half: &mut WH,
+    msg: &SyncMessage,
+) -> Result<(), HandshakeError>
+where
+    WH: tokio::io::AsyncWrite + Unpin,
+{
+    let framed = protocol::encode_frame(msg).ok_or(HandshakeError::Rejected)?;
+    write_half
+        .write_all(&framed)
+        .await
+        .map_err(|_| HandshakeError::Closed)?;
+    write_half.flush().await.map_err(|_| HandshakeError::Closed)
+}
 
-    let mut stdin_reader = FrameReader::new(input);
-    let Some(initial_msg_bytes) = stdin_reader.read_one().await else {
-        error!("Stdin closed before PluginOpen");
-        return;
+// reads the next handshake step, which has to be of the expected kind. an Error from the server
+// ends the attempt and is shown to the user
+async fn read_handshake_reply<RH, WOut>(
+    reader: &mut FrameReader<RH>,
+    output: &mut WOut,
+    expected: MessageKind,
+) -> Result<SyncMessage, HandshakeError>
+where
+    RH: tokio::io::AsyncRead + Unpin,
+    WOut: tokio::io::AsyncWrite + Unpin,
+{
+    let Some(msg_bytes) = reader.read_one().await else {
+        return Err(HandshakeError::Closed);
     };
-    let initial_open: PluginOpen = match rmp_serde::from_slice(&initial_msg_bytes) {
-        Ok(open) => open,
+    let msg: SyncMessage = match rmp_serde::from_slice(&msg_bytes) {
+        Ok(msg) => msg,
         Err(e) => {
-            error!("Failed to deserialize initial PluginOpen: {}", e);
-            return;
+            error!("Failed to deserialize handshake reply: {}", e);
+            return Err(HandshakeError::Rejected);
         }
     };
 
-    // read from stream for broadcasted updates
-    let (stream_tx, mut stream_rx) = mpsc::channel(CHANNEL_SIZE);
-    let stream_reader = FrameReader::new(read_half);
-    task::spawn(async move { stream_reader.read_loop(stream_tx).await });
-    trace!("Spawned server stream read loop");
+    match msg.kind {
+        kind if kind == expected => Ok(msg),
+        MessageKind::Error => {
+            let reason = msg
+                .body::<ErrorMessage>()
+                .map(|e| e.message().clone())
+                .unwrap_or_else(|| "unknown error".to_owned());
+            warn!("Server rejected connection: {}", reason);
+            let error = PluginError::new(format!("Server rejected connection: {}", reason));
+            let _ = write_plugin_message(output, &error).await;
+            Err(HandshakeError::Rejected)
+        }
+        kind => {
+            error!("Expected {:?} during handshake, got {:?}", expected, kind);
+            Err(HandshakeError::Rejected)
+        }
+    }
+}
 
-    // write stdin updates to stream
-    let (stdin_tx, mut stdin_rx) = mpsc::channel(CHANNEL_SIZE);
-    task::spawn(async move { stdin_reader.read_loop(stdin_tx).await });
-    trace!("Spawned plugin stream read loop");
+// opens connections until one gets through the handshake, backing off exponentially
+async fn reconnect<C, Fut, RH, WH, W>(
+    connector: &mut C,
+    context: &ClientContext<WH>,
+    output: &mut W,
+) -> Option<ServerReader<RH>>
+where
+    C: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<(RH, WH)>>,
+    RH: tokio::io::AsyncRead + Unpin,
+    WH: tokio::io::AsyncWrite + Send + Unpin + 'static,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
 
-    let mut output = output;
-    let server_context = context.clone();
-    let server_task = task::spawn(async move {
-        while let Some(msg_bytes) = stream_rx.recv().await {
+        let (read_half, write_half) = match connector().await {
+            Ok(halves) => halves,
+            Err(e) => {
+                warn!("Reconnect failed, retrying in {:?}: {}", backoff, e);
+                continue;
+            }
+        };
+        let options = &context.options;
+        let client_id = context.doc().await.client_id();
+        let resume = Some((client_id, context.resume_secret.as_slice()));
+        match handshake(read_half, write_half, options, resume, output).await {
+            // someone else took our id while we were away. edits made under it can't be told
+            // apart from theirs anymore
+            Ok(connection) if connection.client_id != client_id => {
+                error!(
+                    "Server moved us from client {} to {}",
+                    client_id, connection.client_id
+                );
+                let error = PluginError::new(
+                    "Server could not restore this session, please reconnect".to_owned(),
+                );
+                let _ = write_plugin_message(output, &error).await;
+                return None;
+            }
+            Ok(connection) => {
+                *context.permissions.write().await = connection.permissions;
+                *context.write.lock().await = Some(connection.writer);
+                return Some(connection.reader);
+            }
+            Err(HandshakeError::Closed) => {
+                warn!("Server closed the connection during handshake, retrying");
+            }
+            Err(HandshakeError::Rejected) => return None,
+        }
+    }
+}
+
+// relays server messages to the plugin for as long as the session lasts, reconnecting whenever
+// the connection drops
+async fn run_server_loop<C, Fut, RH, WH, W>(
+    mut connector: C,
+    mut reader: ServerReader<RH>,
+    context: ClientContext<WH>,
+    mut output: W,
+) where
+    C: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<(RH, WH)>>,
+    RH: tokio::io::AsyncRead + Unpin,
+    WH: tokio::io::AsyncWrite + Send + Unpin + 'static,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    loop {
+        let mut announced = false;
+        loop {
+            let msg_bytes = match reader.read_frame().await {
+                Ok(msg_bytes) => msg_bytes,
+                Err(FrameError::Closed) => break,
+                // reconnecting would only get the same frame again
+                Err(FrameError::TooLarge(len)) => {
+                    let error = PluginError::new(format!(
+                        "Server sent a frame of {} bytes, over the limit of {}",
+                        len,
+                        reader.max_frame()
+                    ));
+                    let _ = write_plugin_message(&mut output, &error).await;
+                    return;
+                }
+            };
             let msg: SyncMessage = match rmp_serde::from_slice(&msg_bytes) {
                 Ok(msg) => msg,
                 Err(e) => {
@@ -326,17 +1387,103 @@ pub async fn run_client<R, W, RH, WH>(
                 }
             };
 
-            if server_context
+            announced |= msg.kind == MessageKind::ServerShutdown;
+            if context
                 .handle_server_message(msg, &mut output)
                 .await
                 .is_err()
             {
-                break;
+                return;
             }
         }
+
         error!("Server disconnected");
-    });
+        if context.go_offline(&mut output, announced).await.is_err() {
+            return;
+        }
+        let Some(new_reader) = reconnect(&mut connector, &context, &mut output).await else {
+            return;
+        };
+        reader = new_reader;
+        // a failure here just means we dropped again, which the read loop notices
+        let _ = context.resync().await;
+    }
+}
+
+pub async fn run_client<C, Fut, R, W, RH, WH>(
+    mut connector: C,
+    input: R,
+    output: W,
+    options: ClientOptions,
+) where
+    C: FnMut() -> Fut + Send,
+    Fut: Future<Output = io::Result<(RH, WH)>> + Send,
+    R: tokio::io::AsyncRead + Send + Unpin + 'static,
+    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+    RH: tokio::io::AsyncRead + Send + Unpin + 'static,
+    WH: tokio::io::AsyncWrite + Send + Unpin + 'static,
+{
+    // define reader for stream, stdin, stdout
+    let mut output = output;
 
+    let (read_half, write_half) = match connector().await {
+        Ok(halves) => halves,
+        Err(e) => {
+            error!("Failed to connect to remote: {}", e);
+            return;
+        }
+    };
+    let connection = match handshake(read_half, write_half, &options, None, &mut output).await {
+        Ok(connection) => connection,
+        Err(HandshakeError::Closed) => {
+            let error =
+                PluginError::new("Server closed the connection during handshake".to_owned());
+            let _ = write_plugin_message(&mut output, &error).await;
+            error!("Handshake with server failed");
+            return;
+        }
+        Err(HandshakeError::Rejected) => {
+            error!("Handshake with server failed");
+            return;
+        }
+    };
+    let max_frame = options.max_frame;
+    let context = ClientContext::new(
+        options,
+        connection.client_id,
+        connection.permissions,
+        connection.resume_secret,
+    );
+    *context.write.lock().await = Some(connection.writer);
+    let stream_reader = connection.reader;
+
+    let mut stdin_reader = FrameReader::new(input).with_max_frame(max_frame);
+    let initial_msg_bytes = match stdin_reader.read_frame().await {
+        Ok(bytes) => bytes,
+        Err(FrameError::Closed) => {
+            error!("Stdin closed before PluginOpen");
+            return;
+        }
+        Err(FrameError::TooLarge(len)) => {
+            let _ = write_plugin_message(&mut output, &plugin_too_large(len, max_frame)).await;
+            return;
+        }
+    };
+    let initial_open: PluginOpen = match rmp_serde::from_slice(&initial_msg_bytes) {
+        Ok(open) => open,
+        Err(e) => {
+            error!("Failed to deserialize initial PluginOpen: {}", e);
+            return;
+        }
+    };
+
+    // write stdin updates to stream
+    let (stdin_tx, mut stdin_rx) = mpsc::channel(CHANNEL_SIZE);
+    let stdin_task = task::spawn(async move { stdin_reader.read_loop(stdin_tx).await });
+    trace!("Spawned plugin stream read loop");
+
+    // failed sends below only mean the server is unreachable right now. the edits are already in
+    // the doc and go out after reconnecting
     let plugin_context = context.clone();
     let plugin_task = task::spawn(async move {
         while let Some(msg_bytes) = stdin_rx.recv().await {
@@ -346,6 +1493,36 @@ pub async fn run_client<R, W, RH, WH>(
                 continue;
             }
 
+            if let Ok(list) = rmp_serde::from_slice::<PluginListFiles>(&msg_bytes) {
+                let _ = plugin_context.handle_plugin_list_files(list).await;
+                continue;
+            }
+
+            if let Ok(create) = rmp_serde::from_slice::<PluginCreate>(&msg_bytes) {
+                let _ = plugin_context.handle_plugin_create(create).await;
+                continue;
+            }
+
+            if let Ok(rename) = rmp_serde::from_slice::<PluginRename>(&msg_bytes) {
+                let _ = plugin_context.handle_plugin_rename(rename).await;
+                continue;
+            }
+
+            if let Ok(delete) = rmp_serde::from_slice::<PluginDelete>(&msg_bytes) {
+                let _ = plugin_context.handle_plugin_delete(delete).await;
+                continue;
+            }
+
+            if let Ok(plugin_cursor) = rmp_serde::from_slice::<PluginCursor>(&msg_bytes) {
+                let _ = plugin_context.handle_plugin_cursor(plugin_cursor).await;
+                continue;
+            }
+
+            if let Ok(plugin_edit) = rmp_serde::from_slice::<PluginEdit>(&msg_bytes) {
+                let _ = plugin_context.handle_plugin_edit(plugin_edit).await;
+                continue;
+            }
+
             let plugin_update: PluginUpdate = match rmp_serde::from_slice(&msg_bytes) {
                 Ok(update) => update,
                 Err(e) => {
@@ -354,9 +1531,7 @@ pub async fn run_client<R, W, RH, WH>(
                 }
             };
 
-            if plugin_context.handle_plugin_update(plugin_update).await.is_err() {
-                break;
-            }
+            let _ = plugin_context.handle_plugin_update(plugin_update).await;
         }
         error!("Stdin closed");
     });
@@ -365,5 +1540,21 @@ pub async fn run_client<R, W, RH, WH>(
         .handle_open_buffers(initial_open.buffers().clone())
         .await;
 
-    let _ = tokio::join!(server_task, plugin_task);
+    // the session ends when the editor goes away or the server turns us down for good
+    let stdin_closed = tokio::select! {
+        _ = plugin_task => true,
+        _ = run_server_loop(connector, stream_reader, context, &mut output) => false,
+    };
+    if stdin_closed {
+        if let Ok(Err(FrameError::TooLarge(len))) = stdin_task.await {
+            let _ = write_plugin_message(&mut output, &plugin_too_large(len, max_frame)).await;
+        }
+    }
+}
+
+fn plugin_too_large(len: usize, max_frame: usize) -> PluginError {
+    PluginError::new(format!(
+        "Plugin sent a frame of {} bytes, over the limit of {}, disconnecting",
+        len, max_frame
+    ))
 }
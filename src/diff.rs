@@ -0,0 +1,404 @@
+use std::borrow::Cow;
+
+use yrs::types::Delta;
+use yrs::{Any, Out, Text, TextRef, TransactionMut};
+
+use crate::protocol::LineEdit;
+
+/// A single contiguous replacement, in byte offsets into the old text.
+#[derive(Debug, PartialEq)]
+pub struct TextChange<'a> {
+    pub start: usize,
+    pub removed: usize,
+    pub inserted: Cow<'a, str>,
+}
+
+// trims the common prefix and suffix so only the edited region is left. a keystroke or paste
+// becomes one small change instead of rewriting the whole buffer, which keeps CRDT identity of
+// the untouched text intact so concurrent edits elsewhere in the buffer still merge.
+// offsets always land on char boundaries
+pub fn diff<'a>(old: &str, new: &'a str) -> Option<TextChange<'a>> {
+    if old == new {
+        return None;
+    }
+
+    let prefix = old
+        .char_indices()
+        .zip(new.chars())
+        .find(|((_, a), b)| a != b)
+        .map(|((i, _), _)| i)
+        .unwrap_or_else(|| old.len().min(new.len()));
+
+    let suffix = old[prefix..]
+        .chars()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a.len_utf8())
+        .sum::<usize>();
+
+    Some(TextChange {
+        start: prefix,
+        removed: old.len() - prefix - suffix,
+        inserted: Cow::Borrowed(&new[prefix..new.len() - suffix]),
+    })
+}
+
+pub fn apply_change(text: &TextRef, txn: &mut TransactionMut, change: &TextChange) {
+    if change.removed > 0 {
+        text.remove_range(txn, change.start as u32, change.removed as u32);
+    }
+    if !change.inserted.is_empty() {
+        text.insert(txn, change.start as u32, &change.inserted);
+    }
+}
+
+// carries `change`, made to `base`, over onto `current`, which is `base` with other edits made
+// since. it lands like a concurrent edit would in the CRDT: it removes whatever is left of the
+// text it removed and keeps everything the other side inserted, so neither side loses edits.
+// the other side's edits reduce to one contiguous change. returns changes in offsets into
+// `current`, last one first so each applies to the text the one before left
+pub fn rebase<'a>(base: &str, current: &str, change: &TextChange<'a>) -> Vec<TextChange<'a>> {
+    let (start, end) = (change.start, change.start + change.removed);
+    let Some(other) = diff(base, current) else {
+        return vec![TextChange {
+            start,
+            removed: change.removed,
+            inserted: change.inserted.clone(),
+        }];
+    };
+    let (other_start, other_end) = (other.start, other.start + other.removed);
+    // where base text past the other change sits in `current`
+    let shift = |offset: usize| offset - other_end + other_start + other.inserted.len();
+
+    if start >= other_end {
+        return vec![TextChange {
+            start: shift(start),
+            removed: change.removed,
+            inserted: change.inserted.clone(),
+        }];
+    }
+    if start >= other_start {
+        // starts in text the other side already removed, so what it inserts goes after what
+        // the other side put there
+        return vec![TextChange {
+            start: shift(other_end),
+            removed: end.saturating_sub(other_end),
+            inserted: change.inserted.clone(),
+        }];
+    }
+
+    let mut changes = Vec::new();
+    if end > other_end {
+        changes.push(TextChange {
+            start: shift(other_end),
+            removed: end - other_end,
+            inserted: Cow::Borrowed(""),
+        });
+    }
+    changes.push(TextChange {
+        start,
+        removed: end.min(other_start) - start,
+        inserted: change.inserted.clone(),
+    });
+    changes
+}
+
+// the editor counts every line as terminated by a newline, including the last one, while the
+// shared text is the lines joined without a trailing newline. edits that stay inside the text
+// map over as-is, edits that reach the virtual final newline have to give it back. returns None
+// when the edit doesn't fit a text of `len` bytes
+pub fn from_line_edit(
+    len: usize,
+    start: usize,
+    removed: usize,
+    inserted: &str,
+) -> Option<TextChange<'_>> {
+    let end = start.checked_add(removed)?;
+    if end <= len {
+        return Some(TextChange {
+            start,
+            removed,
+            inserted: Cow::Borrowed(inserted),
+        });
+    }
+    if end != len + 1 {
+        return None;
+    }
+
+    if inserted.is_empty() {
+        // whole lines removed from the end, the newline before them becomes the final one
+        let start = start.checked_sub(1)?;
+        return Some(TextChange {
+            start,
+            removed: len - start,
+            inserted: Cow::Borrowed(""),
+        });
+    }
+
+    let inserted = inserted.strip_suffix('\n')?;
+    if start <= len {
+        Some(TextChange {
+            start,
+            removed: len - start,
+            inserted: Cow::Borrowed(inserted),
+        })
+    } else {
+        // appended after the last line
+        Some(TextChange {
+            start: len,
+            removed: 0,
+            inserted: Cow::Owned(format!("\n{}", inserted)),
+        })
+    }
+}
+
+// turns a Yrs text delta into replacements, each in offsets of the text as it is after the ones
+// before it were applied
+pub fn from_delta(delta: &[Delta<Out>]) -> Vec<TextChange<'static>> {
+    let mut changes: Vec<TextChange> = Vec::new();
+    let mut pos = 0;
+    for op in delta {
+        match op {
+            Delta::Retain(len, _) => pos += *len as usize,
+            Delta::Deleted(len) => changes.push(TextChange {
+                start: pos,
+                removed: *len as usize,
+                inserted: Cow::Borrowed(""),
+            }),
+            Delta::Inserted(value, _) => {
+                // buffers only ever hold plain strings, anything else has no text to show
+                let inserted = match value {
+                    Out::Any(Any::String(s)) => s.to_string(),
+                    _ => String::new(),
+                };
+                let len = inserted.len();
+                // a delete directly followed by an insert is one replacement
+                match changes.last_mut() {
+                    Some(last) if last.start == pos && last.inserted.is_empty() => {
+                        last.inserted = Cow::Owned(inserted);
+                    }
+                    _ => changes.push(TextChange {
+                        start: pos,
+                        removed: 0,
+                        inserted: Cow::Owned(inserted),
+                    }),
+                }
+                pos += len;
+            }
+        }
+    }
+    changes
+}
+
+pub fn position(text: &str, offset: usize) -> (u32, u32) {
+    let before = &text[..offset];
+    let row = before.matches('\n').count();
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (row as u32, (offset - line_start) as u32)
+}
+
+// converts byte replacements against `old` into row/column edits the editor can apply one after
+// another without rewriting the rest of the buffer
+pub fn to_line_edits(old: &str, changes: &[TextChange]) -> Option<Vec<LineEdit>> {
+    let mut text = old.to_owned();
+    let mut edits = Vec::with_capacity(changes.len());
+    for change in changes {
+        let end = change.start.checked_add(change.removed)?;
+        if end > text.len() || !text.is_char_boundary(change.start) || !text.is_char_boundary(end) {
+            return None;
+        }
+        let (start_row, start_col) = position(&text, change.start);
+        let (end_row, end_col) = position(&text, end);
+        edits.push(LineEdit {
+            start_row,
+            start_col,
+            end_row,
+            end_col,
+            text: change.inserted.to_string(),
+        });
+        text.replace_range(change.start..end, &change.inserted);
+    }
+    Some(edits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(old: &str, change: &TextChange) -> String {
+        let mut text = old.to_owned();
+        text.replace_range(
+            change.start..change.start + change.removed,
+            &change.inserted,
+        );
+        text
+    }
+
+    fn rebased(base: &str, current: &str, new: &str) -> String {
+        let change = diff(base, new).unwrap();
+        rebase(base, current, &change)
+            .iter()
+            .fold(current.to_owned(), |text, change| apply(&text, change))
+    }
+
+    #[test]
+    fn rebase_keeps_edits_to_different_regions() {
+        let base = "one\ntwo\nthree";
+        let ours = "one\n2\nthree";
+        let theirs = "one\ntwo\nthree\nfour";
+        assert_eq!(rebased(base, ours, theirs), "one\n2\nthree\nfour");
+        assert_eq!(rebased(base, theirs, ours), "one\n2\nthree\nfour");
+        assert_eq!(rebased(base, base, "changed"), "changed");
+    }
+
+    #[test]
+    fn rebase_of_overlapping_edits_keeps_both_insertions() {
+        let base = "one\ntwo\nthree";
+        assert_eq!(
+            rebased(base, "one\nTWO\nthree", "one\n2\nthree"),
+            "one\nTWO2\nthree"
+        );
+
+        // the other side typed into text this change removed
+        let base = "one\ntwo\nsix";
+        assert_eq!(
+            rebased(base, "one\ntwo and more\nsix", "one\nsix"),
+            "one\n and moresix"
+        );
+        // this change starts inside text the other side removed
+        assert_eq!(rebased(base, "one\nsix", "one\ntwX\nsix"), "one\nXsix");
+    }
+
+    #[test]
+    fn identical_text_has_no_change() {
+        assert_eq!(diff("same", "same"), None);
+    }
+
+    #[test]
+    fn insertion_in_the_middle() {
+        let change = diff("hello world", "hello brave world").expect("change");
+        assert_eq!(
+            change,
+            TextChange {
+                start: 6,
+                removed: 0,
+                inserted: Cow::Borrowed("brave "),
+            }
+        );
+    }
+
+    #[test]
+    fn deletion_at_the_end() {
+        let change = diff("fn main() {}\n", "fn main()").expect("change");
+        assert_eq!(change.start, 9);
+        assert_eq!(change.removed, 4);
+        assert_eq!(change.inserted, "");
+    }
+
+    #[test]
+    fn repeated_characters_do_not_overlap() {
+        let change = diff("aaa", "aaaa").expect("change");
+        assert_eq!(apply("aaa", &change), "aaaa");
+        assert_eq!(change.removed, 0);
+        assert_eq!(change.inserted, "a");
+    }
+
+    #[test]
+    fn multibyte_offsets_stay_on_char_boundaries() {
+        let old = "héllo wörld";
+        let new = "héllo wårld";
+        let change = diff(old, new).expect("change");
+        assert_eq!(change.inserted, "å");
+        assert_eq!(change.removed, "ö".len());
+        assert_eq!(apply(old, &change), new);
+    }
+
+    #[test]
+    fn replacement_from_empty() {
+        let change = diff("", "new file").expect("change");
+        assert_eq!(apply("", &change), "new file");
+    }
+
+    #[test]
+    fn line_edit_inside_text_is_unchanged() {
+        let change = from_line_edit(5, 1, 2, "xy").expect("change");
+        assert_eq!(apply("hello", &change), "hxylo");
+    }
+
+    #[test]
+    fn line_edit_appending_a_line() {
+        // "a" -> "a\nb": the editor inserts "b\n" after the final newline
+        let change = from_line_edit(1, 2, 0, "b\n").expect("change");
+        assert_eq!(apply("a", &change), "a\nb");
+    }
+
+    #[test]
+    fn line_edit_deleting_the_last_line() {
+        // "a\nb" -> "a": the editor removes "b\n" and the text loses the joining newline
+        let change = from_line_edit(3, 1, 3, "\n").expect("change");
+        assert_eq!(apply("a\nb", &change), "a");
+    }
+
+    #[test]
+    fn line_edit_removing_trailing_lines() {
+        // "a\nb" -> "a" reported as removing "b\n" with nothing inserted
+        let change = from_line_edit(3, 2, 2, "").expect("change");
+        assert_eq!(apply("a\nb", &change), "a");
+    }
+
+    #[test]
+    fn delta_merges_delete_and_insert() {
+        let delta = vec![
+            Delta::Retain(2, None),
+            Delta::Deleted(3),
+            Delta::Inserted(Out::Any("xyz".into()), None),
+            Delta::Retain(1, None),
+            Delta::Inserted(Out::Any("!".into()), None),
+        ];
+        let changes = from_delta(&delta);
+        assert_eq!(changes.len(), 2);
+        assert_eq!((changes[0].start, changes[0].removed), (2, 3));
+        assert_eq!(changes[0].inserted, "xyz");
+        assert_eq!((changes[1].start, changes[1].removed), (6, 0));
+    }
+
+    #[test]
+    fn line_edits_track_rows_across_changes() {
+        let old = "one\ntwo\nthree";
+        let changes = vec![
+            TextChange {
+                start: 4,
+                removed: 3,
+                inserted: Cow::Borrowed("2\n2.5"),
+            },
+            // end of "three", which moved down a row after the first change
+            TextChange {
+                start: 15,
+                removed: 0,
+                inserted: Cow::Borrowed("3"),
+            },
+        ];
+        let edits = to_line_edits(old, &changes).expect("edits");
+        assert_eq!((edits[0].start_row, edits[0].start_col), (1, 0));
+        assert_eq!((edits[0].end_row, edits[0].end_col), (1, 3));
+        assert_eq!((edits[1].start_row, edits[1].start_col), (3, 5));
+        assert_eq!(edits[1].text, "3");
+    }
+
+    #[test]
+    fn line_edits_reject_offsets_past_the_end() {
+        let changes = vec![TextChange {
+            start: 2,
+            removed: 5,
+            inserted: Cow::Borrowed(""),
+        }];
+        assert_eq!(to_line_edits("abc", &changes), None);
+    }
+
+    #[test]
+    fn line_edit_out_of_range_is_rejected() {
+        assert_eq!(from_line_edit(3, 2, 5, ""), None);
+        assert_eq!(from_line_edit(3, 4, 0, "no newline"), None);
+    }
+}
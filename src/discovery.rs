@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+/// UDP port announcements go to. Separate from the TCP port sessions are served on.
+pub const DISCOVERY_PORT: u16 = 3248;
+// administratively scoped, so routers keep it inside the site
+const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 72, 48);
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+// announcements are tiny, anything bigger isn't one
+const MAX_DATAGRAM: usize = 1024;
+
+/// What a server multicasts on the local network every few seconds. It never carries the token,
+/// anyone on the network can read it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Announcement {
+    neo_live: String,
+    host: String,
+    room: String,
+    port: u16,
+}
+
+impl Announcement {
+    pub fn new(host: String, room: String, port: u16) -> Self {
+        Self {
+            neo_live: env!("CARGO_PKG_VERSION").to_owned(),
+            host,
+            room,
+            port,
+        }
+    }
+
+    pub fn host(&self) -> &String {
+        &self.host
+    }
+
+    pub fn room(&self) -> &String {
+        &self.room
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn crate_version(&self) -> &String {
+        &self.neo_live
+    }
+}
+
+/// A session `discover` heard about, with the address it was announced from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Session {
+    pub host: String,
+    pub room: String,
+    pub address: SocketAddr,
+    pub crate_version: String,
+}
+
+/// Name of this machine as shown to others, from the `hostname` command since std has no way to
+/// ask.
+pub async fn host_name() -> String {
+    tokio::process::Command::new("hostname")
+        .output()
+        .await
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Announces `room`, served on `bound`, to the local network until the task is dropped. Gives up
+/// with a warning if nobody else could connect to `bound` or the network won't take multicast, the
+/// session itself keeps running.
+pub async fn announce(bound: SocketAddr, room: String) {
+    if let Some(reason) = unreachable_reason(&bound) {
+        warn!("Not announcing the session on {}: {}", bound, reason);
+        return;
+    }
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Could not open a socket to announce the session: {}", e);
+            return;
+        }
+    };
+    let port = bound.port();
+    let announcement = Announcement::new(host_name().await, room, port);
+    let Ok(datagram) = rmp_serde::to_vec_named(&announcement) else {
+        return;
+    };
+    info!(
+        "Announcing room {} on port {} to the local network",
+        announcement.room, port
+    );
+
+    let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = socket.send_to(&datagram, (GROUP, DISCOVERY_PORT)).await {
+            warn!("Stopped announcing the session: {}", e);
+            return;
+        }
+    }
+}
+
+// why others on the network couldn't reach a session bound to `addr` from an announcement, which
+// only carries the port and goes out over IPv4
+fn unreachable_reason(addr: &SocketAddr) -> Option<&'static str> {
+    if addr.ip().is_loopback() {
+        Some("it only takes connections from this machine")
+    } else if addr.is_ipv6() {
+        Some("announcements only reach IPv4 listeners")
+    } else {
+        None
+    }
+}
+
+// several `discover`s may listen at once, so the port has to be shared
+fn listen_socket() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT);
+    socket.bind(&addr.into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    socket.join_multicast_v4(GROUP, Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+/// Listens for `wait` and returns every session announced meanwhile, once each, ordered by host.
+pub async fn discover(wait: Duration) -> io::Result<Vec<Session>> {
+    let socket = listen_socket()?;
+    let mut found = BTreeMap::new();
+    let mut buffer = [0u8; MAX_DATAGRAM];
+    let deadline = tokio::time::sleep(wait);
+    tokio::pin!(deadline);
+
+    loop {
+        let (len, from) = tokio::select! {
+            received = socket.recv_from(&mut buffer) => received?,
+            _ = &mut deadline => break,
+        };
+        // anything else on the port, or an announcement format we don't know, is skipped
+        let Ok(announcement) = rmp_serde::from_slice::<Announcement>(&buffer[..len]) else {
+            debug!(
+                "Ignoring {} bytes from {} that are no announcement",
+                len, from
+            );
+            continue;
+        };
+        let address = SocketAddr::new(from.ip(), announcement.port);
+        let session = Session {
+            host: announcement.host,
+            room: announcement.room,
+            address,
+            crate_version: announcement.neo_live,
+        };
+        found.insert(
+            (session.host.clone(), session.room.clone(), address),
+            session,
+        );
+    }
+    Ok(found.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announcements_round_trip_without_the_token() {
+        let announcement = Announcement::new("lab-07".to_owned(), "default".to_owned(), 3248);
+        let bytes = rmp_serde::to_vec_named(&announcement).unwrap();
+        assert!(bytes.len() < MAX_DATAGRAM);
+        let decoded = rmp_serde::from_slice::<Announcement>(&bytes).unwrap();
+        assert_eq!(decoded, announcement);
+        assert_eq!(decoded.crate_version(), env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn other_datagrams_are_no_announcements() {
+        assert!(rmp_serde::from_slice::<Announcement>(b"hello").is_err());
+        let other = rmp_serde::to_vec_named(&crate::protocol::ListFiles::new(String::new()));
+        assert!(rmp_serde::from_slice::<Announcement>(&other.unwrap()).is_err());
+    }
+
+    #[test]
+    fn only_sessions_others_can_reach_are_announced() {
+        let reachable = |addr: &str| unreachable_reason(&addr.parse().unwrap()).is_none();
+        assert!(reachable("0.0.0.0:3248"));
+        assert!(reachable("192.168.1.20:3248"));
+        assert!(!reachable("127.0.0.1:3248"));
+        assert!(!reachable("[::1]:3248"));
+        assert!(!reachable("[::]:3248"));
+    }
+}
@@ -0,0 +1,242 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+use crate::protocol::Role;
+
+const SCHEME: &str = "neolive://";
+// bumped whenever the encoded fields change, so old binaries refuse new codes instead of
+// misreading them
+const VERSION: u8 = 2;
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Everything needed to join a session, packed into one string to paste around. The token in
+/// it is the one for the invite's role, so a viewer invite gives away nothing more. The
+/// fingerprint of the server's key keeps others holding that same token from posing as the
+/// server.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Invite {
+    addresses: Vec<String>,
+    port: u16,
+    room: String,
+    role: Role,
+    token: String,
+    fingerprint: Vec<u8>,
+}
+
+impl Invite {
+    /// Invite for `role` from the session token.
+    pub fn new(addresses: Vec<String>, port: u16, room: String, role: Role, token: &str) -> Self {
+        Self {
+            addresses,
+            port,
+            room,
+            role,
+            token: auth::role_token(token, role),
+            fingerprint: auth::server_fingerprint(token),
+        }
+    }
+
+    /// Addresses the server may be reached at, in the order to try them.
+    pub fn addresses(&self) -> &Vec<String> {
+        &self.addresses
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn room(&self) -> &String {
+        &self.room
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn token(&self) -> &String {
+        &self.token
+    }
+
+    pub fn fingerprint(&self) -> &Vec<u8> {
+        &self.fingerprint
+    }
+
+    /// `neolive://` followed by the fields in base32, which survives being pasted into chats,
+    /// URLs and shells alike.
+    pub fn encode(&self) -> String {
+        let mut bytes = vec![VERSION];
+        bytes.extend(rmp_serde::to_vec(self).expect("invites always serialize"));
+        format!("{}{}", SCHEME, base32_encode(&bytes))
+    }
+
+    /// Reads back what `encode` wrote, with or without the scheme and in any case. `None` if the
+    /// code is mangled or from a newer version.
+    pub fn decode(code: &str) -> Option<Self> {
+        let code = code.trim();
+        let code = code.strip_prefix(SCHEME).unwrap_or(code);
+        let bytes = base32_decode(code.trim_end_matches('/'))?;
+        let (&version, body) = bytes.split_first()?;
+        if version != VERSION {
+            return None;
+        }
+        let invite: Self = rmp_serde::from_slice(body).ok()?;
+        if invite.addresses.is_empty() {
+            return None;
+        }
+        Some(invite)
+    }
+}
+
+/// Addresses a server bound to `bound` can be reached at. A listener on every interface is
+/// reachable at each of the machine's addresses, with loopback last for guests on the same
+/// machine.
+pub fn candidates(bound: SocketAddr) -> Vec<String> {
+    let ip = bound.ip();
+    if !ip.is_unspecified() {
+        return vec![ip.to_string()];
+    }
+
+    let mut addresses: Vec<IpAddr> = local_ip_address::list_afinet_netifas()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(_, addr)| addr)
+        .filter(|addr| addr.is_ipv4() == ip.is_ipv4() && !addr.is_loopback())
+        // link-local IPv6 addresses need a scope id that means nothing on the guest's machine
+        .filter(|addr| match addr {
+            IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 != 0xfe80,
+            IpAddr::V4(_) => true,
+        })
+        .collect();
+    addresses.push(match ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+    });
+    addresses.dedup();
+    addresses.iter().map(|addr| addr.to_string()).collect()
+}
+
+// RFC 4648 base32 in lowercase without padding
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c.to_ascii_lowercase())?;
+        buffer = (buffer << 5) | value as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite() -> Invite {
+        Invite::new(
+            vec!["192.168.1.5".to_owned(), "::1".to_owned()],
+            3248,
+            "lab".to_owned(),
+            Role::Editor,
+            "secret",
+        )
+    }
+
+    #[test]
+    fn base32_round_trips() {
+        for len in 0..12 {
+            let bytes: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+            assert_eq!(base32_decode(&base32_encode(&bytes)), Some(bytes));
+        }
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+        assert_eq!(base32_decode("MZXW6YTBOI======"), Some(b"foobar".to_vec()));
+        assert_eq!(base32_decode("mzxw1"), None);
+    }
+
+    #[test]
+    fn invites_round_trip() {
+        let invite = invite();
+        let code = invite.encode();
+        assert!(code.starts_with("neolive://"));
+        assert_eq!(Invite::decode(&code), Some(invite.clone()));
+        assert_eq!(
+            Invite::decode(&format!(
+                "  {}/\n",
+                code.to_uppercase().replace("NEOLIVE://", "")
+            )),
+            Some(invite)
+        );
+    }
+
+    #[test]
+    fn invites_carry_the_role_token() {
+        let invite = invite();
+        assert_eq!(invite.token(), &auth::role_token("secret", Role::Editor));
+        assert_ne!(invite.token(), "secret");
+        let owner = Invite::new(
+            vec!["::1".to_owned()],
+            1,
+            "r".to_owned(),
+            Role::Owner,
+            "secret",
+        );
+        assert_eq!(owner.token(), "secret");
+    }
+
+    #[test]
+    fn invites_pin_the_server_key() {
+        let invite = invite();
+        assert_eq!(invite.fingerprint(), &auth::server_fingerprint("secret"));
+        assert_ne!(invite.fingerprint(), &auth::server_fingerprint("other"));
+    }
+
+    #[test]
+    fn mangled_invites_are_refused() {
+        let code = invite().encode();
+        assert_eq!(Invite::decode("neolive://"), None);
+        assert_eq!(Invite::decode(&code[..code.len() - 10]), None);
+        assert_eq!(
+            Invite::decode(&code.replace("neolive://", "neolive://!")),
+            None
+        );
+
+        let mut bytes = base32_decode(&code["neolive://".len()..]).unwrap();
+        bytes[0] = VERSION + 1;
+        assert_eq!(Invite::decode(&base32_encode(&bytes)), None);
+    }
+
+    #[test]
+    fn specific_binds_are_their_own_candidate() {
+        let bound: SocketAddr = "10.0.0.7:3248".parse().unwrap();
+        assert_eq!(candidates(bound), vec!["10.0.0.7".to_owned()]);
+        let bound: SocketAddr = "0.0.0.0:3248".parse().unwrap();
+        assert_eq!(
+            candidates(bound).last().map(String::as_str),
+            Some("127.0.0.1")
+        );
+    }
+}
@@ -1,6 +1,21 @@
+mod auth;
 pub mod client;
+mod diff;
+pub mod discovery;
+pub mod invite;
+mod persist;
 pub mod protocol;
+mod secure;
 pub mod server;
+mod share;
+#[cfg(test)]
+mod test_util;
+mod websocket;
+mod workspace;
 
-pub use client::connect;
-pub use server::serve;
+pub use auth::{generate_token, role_token};
+pub use client::{connect, connect_any, connect_command, connect_websocket};
+#[cfg(unix)]
+pub use client::connect_unix;
+pub use server::{serve, serve_on};
+pub use share::share;
@@ -1,10 +1,18 @@
 use std::fs::OpenOptions;
-use std::net::{Ipv4Addr, SocketAddrV4};
-use std::str::FromStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use env_logger::Target;
 use log::LevelFilter;
+use neo_live::client::ClientOptions;
+use neo_live::discovery;
+use neo_live::invite::{self, Invite};
+use neo_live::protocol::Role;
+use neo_live::server::{Listener, ServerOptions};
+
+const MIB: usize = 1024 * 1024;
 
 #[derive(Parser, Debug)]
 #[command(version, author, about)]
@@ -29,41 +37,190 @@ struct Cli {
     log_filters: String,
 }
 
+#[derive(Args, Debug)]
+struct ServeArgs {
+    // currently defaults to HostMode::Local for debugging
+    /// Interface to bind to
+    #[arg(long, value_enum, default_value_t = HostMode::Local)]
+    host_mode: HostMode,
+
+    /// Directory to persist the session to, restored on the next start
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+
+    /// Seconds between snapshots of the persisted session
+    #[arg(long, default_value = "30")]
+    snapshot_interval: u64,
+
+    /// Session token clients need to join. A random one is generated and printed if omitted
+    #[arg(long)]
+    token: Option<String>,
+
+    /// Directory whose files are shared with clients and kept in sync with the session
+    #[arg(long)]
+    root: Option<PathBuf>,
+
+    /// Let clients open and create hidden files and ones .gitignore excludes under --root too.
+    /// Nothing in .git is shared either way
+    #[arg(long, requires = "root")]
+    share_ignored: bool,
+
+    /// Buffer, or directory of buffers, that only owners may change. Can be repeated
+    #[arg(long)]
+    read_only: Vec<String>,
+
+    /// Largest message in MiB a client may send before it is disconnected. Documents are kept
+    /// small enough to be sent whole in one
+    #[arg(long, default_value = "32")]
+    max_frame_mib: usize,
+
+    /// MiB of outgoing messages that may queue up for one client before it is dropped
+    #[arg(long, default_value = "128")]
+    connection_budget_mib: usize,
+
+    /// Room the printed invites lead to
+    #[arg(long, default_value = neo_live::protocol::DEFAULT_ROOM)]
+    room: String,
+
+    /// Announce the room on the local network so `discover` finds it. The token is never announced
+    #[arg(long)]
+    announce: bool,
+
+    /// Also take WebSocket connections, on this port
+    #[arg(long)]
+    websocket_port: Option<u16>,
+
+    /// Let Yjs editors join over the WebSocket port through y-websocket, passing a token as the
+    /// token parameter. It travels unencrypted, unlike the neo-live handshake
+    #[arg(long, requires = "websocket_port")]
+    yjs: bool,
+}
+
 #[derive(Subcommand, Debug)]
 enum Command {
     /// Serve file to a socket
     Serve {
-        // currently defaults to HostMode::Local for debugging
-        /// Interface to bind to
-        #[arg(long, value_enum, default_value_t = HostMode::Local)]
-        host_mode: HostMode,
+        #[command(flatten)]
+        serve: ServeArgs,
+
+        /// Listen on a Unix domain socket at this path instead of a TCP port
+        #[arg(long, conflicts_with_all = ["host_mode", "announce", "websocket_port"])]
+        unix: Option<PathBuf>,
+
+        /// Serve the one peer on stdin and stdout, as `connect --stdio-remote` runs it over SSH.
+        /// With --unix, joins it to the session served there instead of starting one
+        #[arg(long)]
+        stdio: bool,
     },
     /// Connect to server at socket
     Connect {
-        /// Remote IPv4 address to connect to
+        /// Remote address (IPv4 or IPv6) or hostname to connect to
         #[arg(short, long, default_value = "127.0.0.1")]
         address: String,
+
+        /// Name shown to other peers next to your cursor
+        #[arg(long, default_value = "anonymous")]
+        name: String,
+
+        /// Color other peers use for your cursor, e.g. "#e06c75"
+        #[arg(long)]
+        color: Option<String>,
+
+        /// Session token printed by the server
+        #[arg(long, required_unless_present = "invite")]
+        token: Option<String>,
+
+        /// Room on the server to join
+        #[arg(long, default_value = neo_live::protocol::DEFAULT_ROOM)]
+        room: String,
+
+        /// Role the token was handed out for
+        #[arg(long, value_enum, default_value_t = RoleArg::Owner)]
+        role: RoleArg,
+
+        /// Largest message in MiB taken from the server or the editor
+        #[arg(long, default_value = "32")]
+        max_frame_mib: usize,
+
+        /// Invite printed by the server, standing in for the address, port, token, room and role
+        #[arg(long, value_parser = parse_invite, conflicts_with_all = ["address", "token", "room", "role"])]
+        invite: Option<Invite>,
+
+        /// Connect over a WebSocket at this ws:// URL instead, standing in for the address and port
+        #[arg(long, conflicts_with_all = ["address", "invite"])]
+        websocket: Option<String>,
+
+        /// Connect to a Unix domain socket at this path instead
+        #[arg(long, conflicts_with_all = ["address", "invite", "websocket"])]
+        unix: Option<PathBuf>,
+
+        /// Run this shell command and talk to the server through its stdin and stdout instead,
+        /// e.g. "ssh host neo-live serve --stdio --unix /tmp/neo-live.sock"
+        #[arg(long, conflicts_with_all = ["address", "invite", "websocket", "unix"])]
+        stdio_remote: Option<String>,
     },
+    /// Host a session and join it from the editor in one go
+    Share {
+        #[command(flatten)]
+        serve: ServeArgs,
+
+        /// Name shown to other peers next to your cursor
+        #[arg(long, default_value = "anonymous")]
+        name: String,
+
+        /// Color other peers use for your cursor, e.g. "#e06c75"
+        #[arg(long)]
+        color: Option<String>,
+    },
+    /// List sessions announced on the local network
+    Discover {
+        /// Seconds to listen for announcements
+        #[arg(long, default_value = "3")]
+        wait: u64,
+    },
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+pub enum RoleArg {
+    Owner,
+    Editor,
+    Viewer,
+}
+
+impl From<RoleArg> for Role {
+    fn from(role: RoleArg) -> Self {
+        match role {
+            RoleArg::Owner => Role::Owner,
+            RoleArg::Editor => Role::Editor,
+            RoleArg::Viewer => Role::Viewer,
+        }
+    }
 }
 
 #[derive(ValueEnum, Clone, Debug)]
 pub enum HostMode {
-    Local, // 127.0.0.1
-    Lan,   // Local IP like 192.168.x.x
-    All,   // 0.0.0.0
+    Local,  // 127.0.0.1
+    Lan,    // Local IP like 192.168.x.x
+    All,    // 0.0.0.0
+    Local6, // ::1
+    Lan6,   // Local IPv6 address
+    All6,   // ::
 }
 
-fn resolve_address(host_mode: HostMode, port: u16) -> SocketAddrV4 {
+fn resolve_address(host_mode: HostMode, port: u16) -> SocketAddr {
     let address = match host_mode {
-        HostMode::Local => Ipv4Addr::new(127, 0, 0, 1),
-        HostMode::All => Ipv4Addr::new(0, 0, 0, 0),
-        HostMode::Lan => local_ip_address::local_ip()
-            .expect("Failed to get local IP")
-            .to_string()
-            .parse()
-            .expect("Failed to parse local IP"),
+        HostMode::Local => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        HostMode::All => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        HostMode::Lan => local_ip_address::local_ip().expect("Failed to get local IP"),
+        HostMode::Local6 => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        HostMode::All6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        HostMode::Lan6 => local_ip_address::local_ipv6().expect("Failed to get local IPv6"),
     };
-    SocketAddrV4::new(address, port)
+    SocketAddr::new(address, port)
+}
+
+fn parse_invite(code: &str) -> Result<Invite, String> {
+    Invite::decode(code).ok_or_else(|| "not a neo-live invite, or from a newer version".to_owned())
 }
 
 fn init_logging(log_output: String, log_level: String, log_filters: String) {
@@ -98,22 +255,201 @@ fn init_logging(log_output: String, log_level: String, log_filters: String) {
     builder.init();
 }
 
+// what `serve` tells the user on startup. `share` and `serve --stdio` keep stdout for the plugin
+// or the peer, and invites lead to a TCP port
+#[derive(PartialEq)]
+enum Print {
+    Nothing,
+    Tokens,
+    Invites,
+}
+
+fn server_options(args: ServeArgs, port: u16, print: Print) -> (SocketAddr, ServerOptions) {
+    let addr = resolve_address(args.host_mode, port);
+    let token = args.token.unwrap_or_else(|| {
+        let token = neo_live::generate_token();
+        if print != Print::Nothing {
+            println!("Session token: {}", token);
+        }
+        token
+    });
+    if print != Print::Nothing {
+        // a role token only works along with its --role
+        for (name, role) in [("editor", Role::Editor), ("viewer", Role::Viewer)] {
+            let role_token = neo_live::role_token(&token, role);
+            println!("Token for --role {}: {}", name, role_token);
+        }
+    }
+    if print == Print::Invites {
+        let addresses = invite::candidates(addr);
+        for (name, role) in [
+            ("Owner", Role::Owner),
+            ("Editor", Role::Editor),
+            ("Viewer", Role::Viewer),
+        ] {
+            let invite = Invite::new(addresses.clone(), port, args.room.clone(), role, &token);
+            println!("{} invite: {}", name, invite.encode());
+        }
+    }
+    let options = ServerOptions {
+        token,
+        state_dir: args.state_dir,
+        snapshot_interval: Duration::from_secs(args.snapshot_interval),
+        root: args.root,
+        share_ignored: args.share_ignored,
+        read_only: args.read_only,
+        max_frame: args.max_frame_mib * MIB,
+        connection_budget: args.connection_budget_mib * MIB,
+        announce: args.announce.then_some(args.room),
+        websocket_port: args.websocket_port,
+        yjs: args.yjs,
+    };
+    (addr, options)
+}
+
+fn exit_with(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+#[cfg(not(unix))]
+const NO_UNIX_SOCKETS: &str = "Unix domain sockets are not supported on this platform";
+
+#[cfg(unix)]
+async fn serve_unix(path: PathBuf, stdio: bool, options: ServerOptions) {
+    if stdio {
+        if let Err(e) = neo_live::server::relay_stdio(&path).await {
+            let message = format!("Could not join the session at {}: {}", path.display(), e);
+            exit_with(message);
+        }
+        return;
+    }
+    match Listener::bind_unix(&path).await {
+        Ok(listener) => neo_live::serve_on(listener, options).await,
+        Err(e) => exit_with(format!("Could not listen on {}: {}", path.display(), e)),
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_path: PathBuf, _stdio: bool, _options: ServerOptions) {
+    exit_with(NO_UNIX_SOCKETS.to_owned())
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: PathBuf, options: ClientOptions) {
+    neo_live::connect_unix(path, options).await
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: PathBuf, _options: ClientOptions) {
+    exit_with(NO_UNIX_SOCKETS.to_owned())
+}
+
+async fn serve_command(args: ServeArgs, unix: Option<PathBuf>, stdio: bool, port: u16) {
+    if stdio && unix.is_none() && args.token.is_none() {
+        // nobody could read a generated token, stdout is the peer's stream
+        exit_with("--stdio needs the --token the peer connects with".to_owned());
+    }
+    let print = match (&unix, stdio) {
+        (_, true) => Print::Nothing,
+        (Some(_), false) => Print::Tokens,
+        (None, false) => Print::Invites,
+    };
+    let (addr, options) = server_options(args, port, print);
+    match unix {
+        Some(path) => serve_unix(path, stdio, options).await,
+        None if stdio => neo_live::serve_on(Listener::Stdio, options).await,
+        None => neo_live::serve(addr, options).await,
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
     init_logging(cli.log_output, cli.log_level, cli.log_filters);
 
     match cli.command {
-        Command::Serve { host_mode } => {
-            let addr = resolve_address(host_mode, cli.port);
-            neo_live::serve(addr).await
+        Command::Serve { serve, unix, stdio } => serve_command(serve, unix, stdio, cli.port).await,
+        Command::Connect {
+            address,
+            name,
+            color,
+            token,
+            room,
+            role,
+            max_frame_mib,
+            invite,
+            websocket,
+            unix,
+            stdio_remote,
+        } => {
+            let options = ClientOptions {
+                name,
+                color,
+                token: token.unwrap_or_default(),
+                room,
+                role: role.into(),
+                server_fingerprint: None,
+                max_frame: max_frame_mib * MIB,
+            };
+            if let Some(url) = websocket {
+                return neo_live::connect_websocket(url, options).await;
+            }
+            if let Some(path) = unix {
+                return connect_unix(path, options).await;
+            }
+            if let Some(command) = stdio_remote {
+                return neo_live::connect_command(command, options).await;
+            }
+            match invite {
+                Some(invite) => {
+                    let options = ClientOptions {
+                        token: invite.token().clone(),
+                        room: invite.room().clone(),
+                        role: invite.role(),
+                        server_fingerprint: Some(invite.fingerprint().clone()),
+                        ..options
+                    };
+                    let hosts = invite.addresses().clone();
+                    neo_live::connect_any(hosts, invite.port(), options).await
+                }
+                None => {
+                    // accept IPv6 literals in URL form too, like [::1]
+                    let host = address.trim_start_matches('[').trim_end_matches(']');
+                    neo_live::connect(host.to_owned(), cli.port, options).await
+                }
+            }
+        }
+        Command::Share { serve, name, color } => {
+            let max_frame = serve.max_frame_mib * MIB;
+            let room = serve.room.clone();
+            let (addr, server_options) = server_options(serve, cli.port, Print::Nothing);
+            let client_options = ClientOptions {
+                name,
+                color,
+                room,
+                max_frame,
+                ..ClientOptions::default()
+            };
+            neo_live::share(addr, server_options, client_options).await
         }
-        Command::Connect { address } => {
-            neo_live::connect(SocketAddrV4::new(
-                Ipv4Addr::from_str(&address).expect("Expected address"),
-                cli.port,
-            ))
-            .await
+        Command::Discover { wait } => {
+            let sessions = match discovery::discover(Duration::from_secs(wait)).await {
+                Ok(sessions) => sessions,
+                Err(e) => {
+                    eprintln!("Could not listen for announcements: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if sessions.is_empty() {
+                println!("No sessions found on the local network");
+            }
+            for session in sessions {
+                println!(
+                    "{}\troom {}\t{}\t(neo-live {})",
+                    session.host, session.room, session.address, session.crate_version
+                );
+            }
         }
     }
 }
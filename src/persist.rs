@@ -0,0 +1,206 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use yrs::updates::decoder::Decode;
+use yrs::{Doc, ReadTxn, StateVector, Transact, Update};
+
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+const WAL_FILE: &str = "wal.bin";
+
+// on-disk copy of the server's document. a snapshot holds the full state as of the last
+// compaction, the write-ahead log holds every update applied since, each prefixed with its
+// length the same way frames are on the wire
+pub struct Store {
+    dir: PathBuf,
+    wal: File,
+    dirty: bool,
+}
+
+impl Store {
+    pub async fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir).await?;
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(WAL_FILE))
+            .await?;
+        Ok(Self {
+            dir: dir.to_owned(),
+            wal,
+            dirty: false,
+        })
+    }
+
+    // applies the snapshot and then the log on top of it. a torn record at the end of the log
+    // (crash mid-write) is dropped
+    pub async fn load(&self, doc: &Doc) -> io::Result<()> {
+        let mut applied = 0;
+        match fs::read(self.dir.join(SNAPSHOT_FILE)).await {
+            Ok(snapshot) => {
+                apply(doc, &snapshot)?;
+                applied += 1;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let wal = fs::read(self.dir.join(WAL_FILE)).await?;
+        let mut rest = wal.as_slice();
+        while rest.len() >= 4 {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            if rest.len() < 4 + len {
+                warn!("Dropping torn record at the end of the write-ahead log");
+                break;
+            }
+            apply(doc, &rest[4..4 + len])?;
+            applied += 1;
+            rest = &rest[4 + len..];
+        }
+
+        info!("Restored {} records from {}", applied, self.dir.display());
+        Ok(())
+    }
+
+    pub async fn append(&mut self, update: &[u8]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(4 + update.len());
+        record.extend_from_slice(&(update.len() as u32).to_be_bytes());
+        record.extend_from_slice(update);
+        self.wal.write_all(&record).await?;
+        self.wal.flush().await?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    // writes the full state next to the old snapshot and renames it over, then empties the log
+    // since everything in it is now part of the snapshot
+    pub async fn snapshot(&mut self, doc: &Doc) -> io::Result<()> {
+        let state = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = File::create(&tmp).await?;
+        file.write_all(&state).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE)).await?;
+
+        self.wal.set_len(0).await?;
+        self.dirty = false;
+        info!("Wrote snapshot of {} bytes", state.len());
+        Ok(())
+    }
+}
+
+fn apply(doc: &Doc, data: &[u8]) -> io::Result<()> {
+    let update =
+        Update::decode_v1(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    doc.transact_mut()
+        .apply_update(update)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use yrs::{GetString, Text};
+
+    // edits `doc` and returns the update it produced
+    fn edit(doc: &Doc, buffer: &str, index: u32, chunk: &str) -> Vec<u8> {
+        let text = doc.get_or_insert_text(buffer);
+        let before = doc.transact().state_vector();
+        text.insert(&mut doc.transact_mut(), index, chunk);
+        doc.transact().encode_diff_v1(&before)
+    }
+
+    fn contents(doc: &Doc, buffer: &str) -> String {
+        let text = doc.get_or_insert_text(buffer);
+        let txn = doc.transact();
+        text.get_string(&txn)
+    }
+
+    #[tokio::test]
+    async fn log_is_replayed_on_open() {
+        let dir = temp_dir("wal");
+        let doc = Doc::new();
+        {
+            let mut store = Store::open(&dir).await.unwrap();
+            store.append(&edit(&doc, "a.rs", 0, "hello")).await.unwrap();
+            store
+                .append(&edit(&doc, "a.rs", 5, " world"))
+                .await
+                .unwrap();
+            assert!(store.is_dirty());
+        }
+
+        let restored = Doc::new();
+        Store::open(&dir)
+            .await
+            .unwrap()
+            .load(&restored)
+            .await
+            .unwrap();
+        assert_eq!(contents(&restored, "a.rs"), "hello world");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn snapshot_compacts_the_log() {
+        let dir = temp_dir("snapshot");
+        let doc = Doc::new();
+        let mut store = Store::open(&dir).await.unwrap();
+        store.append(&edit(&doc, "a.rs", 0, "one")).await.unwrap();
+        store.snapshot(&doc).await.unwrap();
+        assert!(!store.is_dirty());
+        assert_eq!(std::fs::metadata(dir.join(WAL_FILE)).unwrap().len(), 0);
+
+        store.append(&edit(&doc, "b.rs", 0, "two")).await.unwrap();
+        drop(store);
+
+        let restored = Doc::new();
+        Store::open(&dir)
+            .await
+            .unwrap()
+            .load(&restored)
+            .await
+            .unwrap();
+        assert_eq!(contents(&restored, "a.rs"), "one");
+        assert_eq!(contents(&restored, "b.rs"), "two");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn torn_record_is_dropped() {
+        let dir = temp_dir("torn");
+        let doc = Doc::new();
+        let mut store = Store::open(&dir).await.unwrap();
+        store.append(&edit(&doc, "a.rs", 0, "kept")).await.unwrap();
+        let update = edit(&doc, "a.rs", 4, " lost");
+        store
+            .wal
+            .write_all(&(update.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        store.wal.write_all(&update[..2]).await.unwrap();
+        store.wal.flush().await.unwrap();
+        drop(store);
+
+        let restored = Doc::new();
+        Store::open(&dir)
+            .await
+            .unwrap()
+            .load(&restored)
+            .await
+            .unwrap();
+        assert_eq!(contents(&restored, "a.rs"), "kept");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
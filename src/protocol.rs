@@ -2,14 +2,128 @@ use tokio::io::{AsyncReadExt, BufReader};
 use tokio::sync::mpsc::Sender;
 
 use log::{error, trace};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
+/// Bumped whenever the wire format changes in a way older peers can't understand.
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Optional features this build understands, advertised during the handshake.
+pub const CAPABILITIES: &[&str] = &["sync", "awareness", "rooms", "roles"];
+
+/// Room clients join when they don't name one, and the only room older clients know about.
+pub const DEFAULT_ROOM: &str = "default";
+const MAX_ROOM_LEN: usize = 64;
+const MAX_BUFFER_LEN: usize = 4096;
+
+/// Room names end up as directory names on the server, so they are kept to a safe set of
+/// characters.
+pub fn is_valid_room(room: &str) -> bool {
+    !room.is_empty()
+        && room.len() <= MAX_ROOM_LEN
+        && !room.starts_with('.')
+        && room
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Buffer names are paths or editor buffer names. They only have to be something a peer could
+/// have opened, so anything empty, huge or holding control characters is turned away.
+pub fn is_valid_buffer(buffer: &str) -> bool {
+    !buffer.is_empty() && buffer.len() <= MAX_BUFFER_LEN && !buffer.chars().any(char::is_control)
+}
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_owned()
+}
+
+/// What a peer may do in the session. Each role has a token of its own, so the role is settled
+/// by which token the peer proves it knows.
+#[repr(u8)]
+#[derive(Serialize_repr, Deserialize_repr, Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum Role {
+    /// Writes to every buffer. Peers from before there were roles join as owners
+    #[default]
+    Owner = 1,
+    /// Writes to every buffer that isn't read-only
+    Editor = 2,
+    /// Only follows along
+    Viewer = 3,
+}
+
+/// A peer's role along with the buffers the server keeps read-only for everyone but owners.
+/// Entries name a buffer or a directory of them.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct Permissions {
+    pub role: Role,
+    pub read_only: Vec<String>,
+}
+
+impl Permissions {
+    pub fn new(role: Role, read_only: Vec<String>) -> Self {
+        Self { role, read_only }
+    }
+
+    pub fn can_write(&self, buffer: &str) -> bool {
+        match self.role {
+            Role::Owner => true,
+            Role::Editor => !self.read_only.iter().any(|entry| covers(entry, buffer)),
+            Role::Viewer => false,
+        }
+    }
+}
+
+fn covers(entry: &str, buffer: &str) -> bool {
+    let directory = entry.trim_end_matches('/');
+    buffer == directory
+        || buffer
+            .strip_prefix(directory)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
 #[repr(u8)]
 #[derive(Serialize_repr, Deserialize_repr, Debug, PartialEq, Copy, Clone)]
 pub enum MessageKind {
     InitialSync = 1,
     Update = 2,
+    Hello = 3,
+    Welcome = 4,
+    Error = 5,
+    Awareness = 6,
+    // 7 was the challenge of the handshake from before it ran Noise
+    Authenticate = 8,
+    Authenticated = 9,
+    ListFiles = 10,
+    FileList = 11,
+    CreateBuffer = 12,
+    RenameBuffer = 13,
+    DeleteBuffer = 14,
+    ServerShutdown = 15,
+    // a y-protocols message for a peer that joined over y-websocket, never sent to any other
+    Yjs = 16,
+    // everything a client has, sent once it's back from being offline. unlike an update it may
+    // change any buffer the client may write
+    Resync = 17,
+}
+
+#[repr(u8)]
+#[derive(Serialize_repr, Deserialize_repr, Debug, PartialEq, Copy, Clone)]
+pub enum ErrorCode {
+    IncompatibleVersion = 1,
+    UnexpectedMessage = 2,
+    Unauthorized = 3,
+    InvalidRoom = 4,
+    InvalidPath = 5,
+    BufferExists = 6,
+    UnknownBuffer = 7,
+    FileError = 8,
+    ReadOnly = 9,
+    InvalidMessage = 10,
+    InvalidBuffer = 11,
+    InvalidUpdate = 12,
+    FrameTooLarge = 13,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -35,14 +149,388 @@ impl SyncMessage {
     pub fn is_update(&self) -> bool {
         self.kind == MessageKind::Update
     }
+
+    /// Builds a message whose payload is a msgpack-encoded body, used by the control messages.
+    pub fn with_body(kind: MessageKind, buffer: String, body: &impl Serialize) -> Option<Self> {
+        match rmp_serde::to_vec_named(body) {
+            Ok(payload) => Some(Self::new(kind, buffer, payload)),
+            Err(e) => {
+                error!("msgpack encode error: {e}");
+                None
+            }
+        }
+    }
+
+    pub fn body<T: DeserializeOwned>(&self) -> Option<T> {
+        match rmp_serde::from_slice(&self.payload) {
+            Ok(body) => Some(body),
+            Err(e) => {
+                error!("Failed to decode {:?} body: {e}", self.kind);
+                None
+            }
+        }
+    }
+}
+
+/// First message a client sends after connecting, naming the room to join and opening the Noise
+/// handshake that keys the connection. A reconnecting client asks for the id it had before so it
+/// keeps editing under the same Yrs client id, and proves it was given that id in its
+/// `Authenticate`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Hello {
+    protocol_version: u32,
+    crate_version: String,
+    capabilities: Vec<String>,
+    #[serde(default)]
+    client_id: Option<u64>,
+    #[serde(default = "default_room")]
+    room: String,
+    #[serde(default)]
+    role: Role,
+    #[serde(default)]
+    handshake: Vec<u8>,
+}
+
+impl Hello {
+    pub fn new(protocol_version: u32, crate_version: String, capabilities: Vec<String>) -> Self {
+        Self {
+            protocol_version,
+            crate_version,
+            capabilities,
+            client_id: None,
+            room: default_room(),
+            role: Role::default(),
+            handshake: Vec::new(),
+        }
+    }
+
+    pub fn in_room(mut self, room: String) -> Self {
+        self.room = room;
+        self
+    }
+
+    pub fn as_role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    pub fn resuming(mut self, client_id: Option<u64>) -> Self {
+        self.client_id = client_id;
+        self
+    }
+
+    pub fn opening(mut self, handshake: Vec<u8>) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    pub fn current() -> Self {
+        Self::new(
+            PROTOCOL_VERSION,
+            CRATE_VERSION.to_owned(),
+            CAPABILITIES.iter().map(|c| (*c).to_owned()).collect(),
+        )
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    pub fn crate_version(&self) -> &String {
+        &self.crate_version
+    }
+
+    pub fn capabilities(&self) -> &Vec<String> {
+        &self.capabilities
+    }
+
+    pub fn client_id(&self) -> Option<u64> {
+        self.client_id
+    }
+
+    pub fn room(&self) -> &String {
+        &self.room
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn handshake(&self) -> &Vec<u8> {
+        &self.handshake
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+/// Server's answer to an accepted `Hello`, carrying the capabilities both sides share and the
+/// server's half of the handshake. Everything after it is encrypted.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Welcome {
+    protocol_version: u32,
+    crate_version: String,
+    capabilities: Vec<String>,
+    #[serde(default)]
+    handshake: Vec<u8>,
+}
+
+impl Welcome {
+    pub fn for_hello(hello: &Hello) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            crate_version: CRATE_VERSION.to_owned(),
+            capabilities: negotiate_capabilities(hello.capabilities()),
+            handshake: Vec::new(),
+        }
+    }
+
+    pub fn answering(mut self, handshake: Vec<u8>) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    pub fn crate_version(&self) -> &String {
+        &self.crate_version
+    }
+
+    pub fn capabilities(&self) -> &Vec<String> {
+        &self.capabilities
+    }
+
+    pub fn handshake(&self) -> &Vec<u8> {
+        &self.handshake
+    }
+}
+
+pub fn negotiate_capabilities(theirs: &[String]) -> Vec<String> {
+    theirs
+        .iter()
+        .filter(|c| CAPABILITIES.contains(&c.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// First encrypted message from the client. One asking for its old id back shows it holds the
+/// resume secret for it, over the transcript of the handshake.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Authenticate {
+    #[serde(default)]
+    resume: Option<Vec<u8>>,
+}
+
+impl Authenticate {
+    pub fn new(resume: Option<Vec<u8>>) -> Self {
+        Self { resume }
+    }
+
+    pub fn resume(&self) -> Option<&Vec<u8>> {
+        self.resume.as_ref()
+    }
+}
+
+/// Server's answer to `Authenticate`: the id the connection goes by from now on, what it may
+/// change, and the secret to claim that id back with after a reconnect.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Authenticated {
+    client_id: u64,
+    #[serde(default)]
+    permissions: Permissions,
+    #[serde(default)]
+    resume_secret: Vec<u8>,
+}
+
+impl Authenticated {
+    pub fn new(client_id: u64, permissions: Permissions, resume_secret: Vec<u8>) -> Self {
+        Self {
+            client_id,
+            permissions,
+            resume_secret,
+        }
+    }
+
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    pub fn permissions(&self) -> &Permissions {
+        &self.permissions
+    }
+
+    pub fn resume_secret(&self) -> &Vec<u8> {
+        &self.resume_secret
+    }
+}
+
+/// What a peer shows of itself: who it is and where its cursor sits. Positions are encoded Yrs
+/// sticky indexes so they keep pointing at the same text while others edit around them.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PeerState {
+    pub name: String,
+    pub color: Option<String>,
+    pub anchor: Option<Vec<u8>>,
+    pub head: Option<Vec<u8>>,
+}
+
+/// Body of an `Awareness` message for the buffer it names. A missing state means the peer left.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AwarenessUpdate {
+    client_id: u64,
+    state: Option<PeerState>,
+}
+
+impl AwarenessUpdate {
+    pub fn new(client_id: u64, state: Option<PeerState>) -> Self {
+        Self { client_id, state }
+    }
+
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    pub fn state(&self) -> Option<&PeerState> {
+        self.state.as_ref()
+    }
+}
+
+/// Asks a server sharing a directory which files are under `directory`, relative to its root.
+/// An empty directory lists the whole tree.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ListFiles {
+    directory: String,
+}
+
+impl ListFiles {
+    pub fn new(directory: String) -> Self {
+        Self { directory }
+    }
+
+    pub fn directory(&self) -> &String {
+        &self.directory
+    }
+}
+
+/// Files the server shares under a directory, as buffer names that can be opened as they are.
+/// Ignored files are left out.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct FileList {
+    directory: String,
+    files: Vec<String>,
+}
+
+impl FileList {
+    pub fn new(directory: String, files: Vec<String>) -> Self {
+        Self { directory, files }
+    }
+
+    pub fn directory(&self) -> &String {
+        &self.directory
+    }
+
+    pub fn files(&self) -> &Vec<String> {
+        &self.files
+    }
+}
+
+/// Body of a `RenameBuffer` for the buffer it names. Clients only say where it goes, the server
+/// passes it on with the update that moved the text over.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct RenameBuffer {
+    to: String,
+    #[serde(default)]
+    update: Vec<u8>,
+}
+
+impl RenameBuffer {
+    pub fn new(to: String) -> Self {
+        Self {
+            to,
+            update: Vec::new(),
+        }
+    }
+
+    pub fn with_update(mut self, update: Vec<u8>) -> Self {
+        self.update = update;
+        self
+    }
+
+    pub fn to(&self) -> &String {
+        &self.to
+    }
+
+    pub fn update(&self) -> &Vec<u8> {
+        &self.update
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ErrorMessage {
+    code: ErrorCode,
+    message: String,
+    #[serde(default)]
+    update_refused: bool,
+}
+
+impl ErrorMessage {
+    pub fn new(code: ErrorCode, message: String) -> Self {
+        Self {
+            code,
+            message,
+            update_refused: false,
+        }
+    }
+
+    /// Marks the error as the answer to an update the server did not apply. The sender has it
+    /// applied already, so everything it sends after would build on changes the server never
+    /// saw.
+    pub fn refusing_update(mut self) -> Self {
+        self.update_refused = true;
+        self
+    }
+
+    pub fn update_refused(&self) -> bool {
+        self.update_refused
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub fn message(&self) -> &String {
+        &self.message
+    }
+}
+
+/// Last message of a server that is going away, sent to every client before it closes.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ServerShutdown {
+    reason: String,
+}
+
+impl ServerShutdown {
+    pub fn new(reason: String) -> Self {
+        Self { reason }
+    }
+
+    pub fn reason(&self) -> &String {
+        &self.reason
+    }
 }
 
+/// Whole contents of a buffer. Going to the plugin it also says whether the user may edit it.
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct PluginUpdate {
     cursor_row: u32,
     cursor_col: u32,
     buffer: String,
     text: String,
+    #[serde(default)]
+    read_only: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -60,6 +548,321 @@ impl PluginOpen {
     }
 }
 
+/// A single edit reported by the plugin, in byte offsets where every line ends with a newline
+/// (the way Neovim's `on_bytes` counts them).
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginEdit {
+    buffer: String,
+    start: u32,
+    removed: u32,
+    inserted: String,
+}
+
+impl PluginEdit {
+    pub fn new(buffer: String, start: u32, removed: u32, inserted: String) -> Self {
+        Self {
+            buffer,
+            start,
+            removed,
+            inserted,
+        }
+    }
+
+    pub fn buffer(&self) -> &String {
+        &self.buffer
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn removed(&self) -> u32 {
+        self.removed
+    }
+
+    pub fn inserted(&self) -> &String {
+        &self.inserted
+    }
+}
+
+/// A replacement between two (row, byte column) positions of the text before the edit.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct LineEdit {
+    pub start_row: u32,
+    pub start_col: u32,
+    pub end_row: u32,
+    pub end_col: u32,
+    pub text: String,
+}
+
+/// Remote changes to a buffer the plugin already has, to be applied in order.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginPatch {
+    buffer: String,
+    edits: Vec<LineEdit>,
+}
+
+impl PluginPatch {
+    pub fn new(buffer: String, edits: Vec<LineEdit>) -> Self {
+        Self { buffer, edits }
+    }
+
+    pub fn buffer(&self) -> &String {
+        &self.buffer
+    }
+
+    pub fn edits(&self) -> &Vec<LineEdit> {
+        &self.edits
+    }
+}
+
+/// Local cursor reported by the plugin, as byte offsets into the buffer text. `anchor` differs
+/// from `head` while something is selected.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginCursor {
+    buffer: String,
+    head: u32,
+    anchor: u32,
+}
+
+impl PluginCursor {
+    pub fn new(buffer: String, head: u32, anchor: u32) -> Self {
+        Self {
+            buffer,
+            head,
+            anchor,
+        }
+    }
+
+    pub fn buffer(&self) -> &String {
+        &self.buffer
+    }
+
+    pub fn head(&self) -> u32 {
+        self.head
+    }
+
+    pub fn anchor(&self) -> u32 {
+        self.anchor
+    }
+}
+
+/// A (row, byte column) position in a buffer.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct Position {
+    pub row: u32,
+    pub col: u32,
+}
+
+/// Where another peer's cursor is. Without a head the peer left the buffer.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginPeerCursor {
+    peer: u64,
+    buffer: String,
+    name: String,
+    color: Option<String>,
+    head: Option<Position>,
+    anchor: Option<Position>,
+}
+
+impl PluginPeerCursor {
+    pub fn new(
+        peer: u64,
+        buffer: String,
+        name: String,
+        color: Option<String>,
+        head: Option<Position>,
+        anchor: Option<Position>,
+    ) -> Self {
+        Self {
+            peer,
+            buffer,
+            name,
+            color,
+            head,
+            anchor,
+        }
+    }
+
+    pub fn peer(&self) -> u64 {
+        self.peer
+    }
+
+    pub fn buffer(&self) -> &String {
+        &self.buffer
+    }
+
+    pub fn head(&self) -> Option<Position> {
+        self.head
+    }
+
+    pub fn anchor(&self) -> Option<Position> {
+        self.anchor
+    }
+}
+
+/// Asks for the files the server shares under a directory. Opening one is a regular
+/// `PluginOpen`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginListFiles {
+    directory: String,
+}
+
+impl PluginListFiles {
+    pub fn new(directory: String) -> Self {
+        Self { directory }
+    }
+
+    pub fn directory(&self) -> &String {
+        &self.directory
+    }
+}
+
+/// The server's answer to a `PluginListFiles`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginFileList {
+    directory: String,
+    files: Vec<String>,
+}
+
+impl PluginFileList {
+    pub fn new(directory: String, files: Vec<String>) -> Self {
+        Self { directory, files }
+    }
+
+    pub fn directory(&self) -> &String {
+        &self.directory
+    }
+
+    pub fn files(&self) -> &Vec<String> {
+        &self.files
+    }
+}
+
+/// Asks for a new, empty buffer. Comes back to the plugin once the server created it.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginCreate {
+    create: String,
+}
+
+impl PluginCreate {
+    pub fn new(create: String) -> Self {
+        Self { create }
+    }
+
+    pub fn create(&self) -> &String {
+        &self.create
+    }
+}
+
+/// Asks for a buffer to be renamed. Comes back to the plugin once the server renamed it, which
+/// may have been asked for by another peer.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginRename {
+    rename: String,
+    to: String,
+}
+
+impl PluginRename {
+    pub fn new(rename: String, to: String) -> Self {
+        Self { rename, to }
+    }
+
+    pub fn rename(&self) -> &String {
+        &self.rename
+    }
+
+    pub fn to(&self) -> &String {
+        &self.to
+    }
+}
+
+/// Asks for a buffer to be deleted. Comes back to the plugin once the server deleted it.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginDelete {
+    delete: String,
+}
+
+impl PluginDelete {
+    pub fn new(delete: String) -> Self {
+        Self { delete }
+    }
+
+    pub fn delete(&self) -> &String {
+        &self.delete
+    }
+}
+
+/// First thing `share` tells the plugin: where others join the session it hosts, with which
+/// role and token, and an invite that carries all of it. The owner token never leaves the host.
+/// The role is spelled the way `connect --role` takes it, since the token only works with it.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginShare {
+    share: String,
+    role: String,
+    token: String,
+    invite: String,
+}
+
+impl PluginShare {
+    pub fn new(address: String, role: String, token: String, invite: String) -> Self {
+        Self {
+            share: address,
+            role,
+            token,
+            invite,
+        }
+    }
+
+    pub fn address(&self) -> &String {
+        &self.share
+    }
+
+    pub fn role(&self) -> &String {
+        &self.role
+    }
+
+    pub fn token(&self) -> &String {
+        &self.token
+    }
+
+    pub fn invite(&self) -> &String {
+        &self.invite
+    }
+}
+
+/// Tells the plugin the server shut down, and why.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginShutdown {
+    shutdown: String,
+}
+
+impl PluginShutdown {
+    pub fn new(reason: String) -> Self {
+        Self { shutdown: reason }
+    }
+
+    pub fn reason(&self) -> &String {
+        &self.shutdown
+    }
+}
+
+/// Tells the plugin something went wrong that the user should see.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PluginError {
+    error: String,
+}
+
+impl PluginError {
+    pub fn new(error: String) -> Self {
+        Self { error }
+    }
+
+    pub fn error(&self) -> &String {
+        &self.error
+    }
+}
+
 impl PluginUpdate {
     pub fn new(cursor_row: u32, cursor_col: u32, buffer: String, text: String) -> Self {
         PluginUpdate {
@@ -67,15 +870,22 @@ impl PluginUpdate {
             cursor_col,
             buffer,
             text,
+            read_only: false,
         }
     }
 
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
     pub fn from_text(text: String) -> Self {
         PluginUpdate {
             cursor_row: 0,
             cursor_col: 0,
             buffer: "".to_owned(),
             text,
+            read_only: false,
         }
     }
 
@@ -94,6 +904,10 @@ impl PluginUpdate {
     pub fn text(&self) -> &String {
         &self.text
     }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 }
 
 pub fn encode_frame(obj: &impl Serialize) -> Option<Vec<u8>> {
@@ -113,43 +927,101 @@ pub fn encode_frame(obj: &impl Serialize) -> Option<Vec<u8>> {
     Some(buf)
 }
 
+/// Largest frame a `FrameReader` takes unless told otherwise. The first sync of a buffer sends
+/// the whole document in one frame, so this leaves room for big files.
+pub const DEFAULT_MAX_FRAME: usize = 32 * 1024 * 1024;
+
+// how much of a frame is allocated before any of it has arrived
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Why `FrameReader::read_frame` came back without a frame.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The stream ended or failed
+    Closed,
+    /// The length prefix announced a frame of this many bytes, more than the reader takes
+    TooLarge(usize),
+}
+
 pub struct FrameReader<T> {
     reader: BufReader<T>,
+    max_frame: usize,
 }
 
 impl<R: tokio::io::AsyncRead + Unpin> FrameReader<R> {
     pub fn new(reader: R) -> FrameReader<R> {
         let reader = BufReader::new(reader);
-        Self { reader }
+        Self {
+            reader,
+            max_frame: DEFAULT_MAX_FRAME,
+        }
     }
-    pub async fn read_loop(mut self, sender: Sender<Vec<u8>>) {
+
+    /// Refuses frames longer than `max_frame` bytes instead of reading them.
+    pub fn with_max_frame(mut self, max_frame: usize) -> Self {
+        self.max_frame = max_frame;
+        self
+    }
+
+    pub fn max_frame(&self) -> usize {
+        self.max_frame
+    }
+
+    /// Gives back the underlying reader along with anything already buffered from it.
+    pub fn into_inner(self) -> BufReader<R> {
+        self.reader
+    }
+
+    /// Forwards frames until the stream ends, the channel closes, or a frame is refused. Only
+    /// the last returns an error worth telling the other side about.
+    pub async fn read_loop(mut self, sender: Sender<Vec<u8>>) -> Result<(), FrameError> {
         trace!("FrameReader read loop started");
         loop {
-            let Some(buf) = self.read_one().await else {
-                break;
+            let buf = match self.read_frame().await {
+                Ok(buf) => buf,
+                Err(FrameError::Closed) => break,
+                Err(e) => return Err(e),
             };
             if sender.send(buf).await.is_err() {
                 error!("Channel closed");
                 break;
             }
         }
-        error!("Read loop broke")
+        error!("Read loop broke");
+        Ok(())
     }
+
     pub async fn read_one(&mut self) -> Option<Vec<u8>> {
+        self.read_frame().await.ok()
+    }
+
+    pub async fn read_frame(&mut self) -> Result<Vec<u8>, FrameError> {
         let mut len_buf = [0u8; 4];
         if self.reader.read_exact(&mut len_buf).await.is_err() {
             error!("Connection closed");
-            return None;
+            return Err(FrameError::Closed);
         }
         let len = u32::from_be_bytes(len_buf) as usize;
+        if len > self.max_frame {
+            error!(
+                "Refusing a frame of {} bytes, the limit is {}",
+                len, self.max_frame
+            );
+            return Err(FrameError::TooLarge(len));
+        }
 
-        let mut buf = vec![0u8; len];
-        if self.reader.read_exact(&mut buf).await.is_err() {
+        // the buffer grows as bytes arrive, so a length prefix alone can't reserve the memory
+        let mut buf = Vec::with_capacity(len.min(READ_CHUNK));
+        let read = (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut buf)
+            .await;
+        if read.ok() != Some(len) {
             error!("Connection closed");
-            return None;
+            return Err(FrameError::Closed);
         }
 
-        Some(buf)
+        Ok(buf)
     }
 }
 
@@ -196,6 +1068,203 @@ mod tests {
         assert_eq!(open.buffers(), &vec!["src/main.rs".to_owned(), "src/lib.rs".to_owned()]);
     }
 
+    #[test]
+    fn sync_message_body_round_trips() {
+        let hello = Hello::current();
+        let msg = SyncMessage::with_body(MessageKind::Hello, String::new(), &hello).expect("msg");
+        assert_eq!(msg.body::<Hello>(), Some(hello));
+        assert_eq!(msg.body::<ErrorMessage>(), None);
+    }
+
+    #[test]
+    fn hello_compatibility_checks_protocol_version() {
+        assert!(Hello::current().is_compatible());
+        let old = Hello::new(PROTOCOL_VERSION + 1, "0.0.1".to_owned(), Vec::new());
+        assert!(!old.is_compatible());
+    }
+
+    #[test]
+    fn hello_without_room_joins_the_default_room() {
+        #[derive(Serialize)]
+        struct OldHello {
+            protocol_version: u32,
+            crate_version: String,
+            capabilities: Vec<String>,
+        }
+        let old = OldHello {
+            protocol_version: PROTOCOL_VERSION,
+            crate_version: "0.1.0".to_owned(),
+            capabilities: Vec::new(),
+        };
+        let bytes = rmp_serde::to_vec_named(&old).unwrap();
+        let hello = rmp_serde::from_slice::<Hello>(&bytes).unwrap();
+        assert_eq!(hello.room(), DEFAULT_ROOM);
+        assert_eq!(hello.client_id(), None);
+    }
+
+    #[test]
+    fn room_names_are_restricted() {
+        assert!(is_valid_room(DEFAULT_ROOM));
+        assert!(is_valid_room("pairing-2.monday_am"));
+        assert!(!is_valid_room(""));
+        assert!(!is_valid_room(".."));
+        assert!(!is_valid_room("../etc"));
+        assert!(!is_valid_room("a/b"));
+        assert!(!is_valid_room(&"a".repeat(MAX_ROOM_LEN + 1)));
+    }
+
+    #[test]
+    fn buffer_names_are_sane() {
+        assert!(is_valid_buffer("src/main.rs"));
+        assert!(is_valid_buffer("/home/me/notes 2.md"));
+        assert!(!is_valid_buffer(""));
+        assert!(!is_valid_buffer("a\0b"));
+        assert!(!is_valid_buffer("line\nbreak"));
+        assert!(!is_valid_buffer(&"a".repeat(MAX_BUFFER_LEN + 1)));
+    }
+
+    #[test]
+    fn hello_without_role_joins_as_owner() {
+        assert_eq!(Hello::current().role(), Role::Owner);
+        let msg = SyncMessage::with_body(
+            MessageKind::Hello,
+            String::new(),
+            &Hello::current().as_role(Role::Viewer),
+        )
+        .expect("msg");
+        assert_eq!(msg.body::<Hello>().map(|h| h.role()), Some(Role::Viewer));
+    }
+
+    #[test]
+    fn permissions_follow_role_and_read_only_buffers() {
+        let read_only = vec!["docs/".to_owned(), "Cargo.toml".to_owned()];
+        let owner = Permissions::new(Role::Owner, read_only.clone());
+        let editor = Permissions::new(Role::Editor, read_only.clone());
+        let viewer = Permissions::new(Role::Viewer, Vec::new());
+
+        assert!(owner.can_write("docs/intro.md"));
+        assert!(editor.can_write("src/main.rs"));
+        assert!(editor.can_write("docs.md"));
+        assert!(editor.can_write("Cargo.toml.bak"));
+        assert!(!editor.can_write("docs/intro.md"));
+        assert!(!editor.can_write("Cargo.toml"));
+        assert!(!viewer.can_write("src/main.rs"));
+    }
+
+    #[test]
+    fn welcome_keeps_only_shared_capabilities() {
+        let hello = Hello::new(
+            PROTOCOL_VERSION,
+            "9.9.9".to_owned(),
+            vec!["sync".to_owned(), "teleport".to_owned()],
+        );
+        let welcome = Welcome::for_hello(&hello);
+        assert_eq!(welcome.capabilities(), &vec!["sync".to_owned()]);
+        assert_eq!(welcome.crate_version(), CRATE_VERSION);
+    }
+
+    #[test]
+    fn plugin_messages_do_not_decode_as_each_other() {
+        let edit = PluginEdit::new("a.rs".to_owned(), 3, 1, "x".to_owned());
+        let bytes = rmp_serde::to_vec_named(&edit).unwrap();
+        assert!(rmp_serde::from_slice::<PluginOpen>(&bytes).is_err());
+        assert!(rmp_serde::from_slice::<PluginUpdate>(&bytes).is_err());
+        assert_eq!(rmp_serde::from_slice::<PluginEdit>(&bytes).unwrap(), edit);
+
+        let update = PluginUpdate::new(0, 0, "a.rs".to_owned(), "x".to_owned());
+        let bytes = rmp_serde::to_vec_named(&update).unwrap();
+        assert!(rmp_serde::from_slice::<PluginEdit>(&bytes).is_err());
+    }
+
+    #[test]
+    fn plugin_list_files_does_not_decode_as_other_requests() {
+        let list = PluginListFiles::new("src".to_owned());
+        let bytes = rmp_serde::to_vec_named(&list).unwrap();
+        assert!(rmp_serde::from_slice::<PluginOpen>(&bytes).is_err());
+        assert!(rmp_serde::from_slice::<PluginCursor>(&bytes).is_err());
+        assert!(rmp_serde::from_slice::<PluginEdit>(&bytes).is_err());
+        assert!(rmp_serde::from_slice::<PluginUpdate>(&bytes).is_err());
+        assert_eq!(rmp_serde::from_slice::<PluginListFiles>(&bytes).unwrap(), list);
+    }
+
+    #[test]
+    fn plugin_lifecycle_requests_do_not_decode_as_each_other() {
+        let rename = PluginRename::new("a.rs".to_owned(), "b.rs".to_owned());
+        let bytes = rmp_serde::to_vec_named(&rename).unwrap();
+        assert!(rmp_serde::from_slice::<PluginCreate>(&bytes).is_err());
+        assert!(rmp_serde::from_slice::<PluginDelete>(&bytes).is_err());
+        assert!(rmp_serde::from_slice::<PluginOpen>(&bytes).is_err());
+        assert!(rmp_serde::from_slice::<PluginCursor>(&bytes).is_err());
+        assert_eq!(rmp_serde::from_slice::<PluginRename>(&bytes).unwrap(), rename);
+
+        let delete = PluginDelete::new("a.rs".to_owned());
+        let bytes = rmp_serde::to_vec_named(&delete).unwrap();
+        assert!(rmp_serde::from_slice::<PluginCreate>(&bytes).is_err());
+        assert!(rmp_serde::from_slice::<PluginRename>(&bytes).is_err());
+        assert!(rmp_serde::from_slice::<PluginUpdate>(&bytes).is_err());
+        assert_eq!(rmp_serde::from_slice::<PluginDelete>(&bytes).unwrap(), delete);
+    }
+
+    #[test]
+    fn shutdown_notice_carries_its_reason() {
+        let notice = ServerShutdown::new("server received SIGTERM".to_owned());
+        let msg = SyncMessage::with_body(MessageKind::ServerShutdown, String::new(), &notice)
+            .expect("message");
+        let bytes = rmp_serde::to_vec_named(&msg).unwrap();
+        let msg = rmp_serde::from_slice::<SyncMessage>(&bytes).unwrap();
+        assert_eq!(msg.kind, MessageKind::ServerShutdown);
+        assert_eq!(msg.body::<ServerShutdown>(), Some(notice));
+    }
+
+    #[test]
+    fn share_notice_is_told_apart_from_other_plugin_messages() {
+        let share = PluginShare::new(
+            "192.168.1.5:3248".to_owned(),
+            "editor".to_owned(),
+            "secret".to_owned(),
+            "neolive://abc".to_owned(),
+        );
+        let bytes = rmp_serde::to_vec_named(&share).unwrap();
+        assert!(rmp_serde::from_slice::<PluginShutdown>(&bytes).is_err());
+        assert!(rmp_serde::from_slice::<PluginError>(&bytes).is_err());
+        let decoded = rmp_serde::from_slice::<PluginShare>(&bytes).unwrap();
+        assert_eq!(decoded.address(), "192.168.1.5:3248");
+        assert_eq!(decoded.role(), "editor");
+        assert_eq!(decoded.token(), "secret");
+        assert_eq!(decoded.invite(), "neolive://abc");
+    }
+
+    #[test]
+    fn rename_request_decodes_without_update() {
+        #[derive(Serialize)]
+        struct Request {
+            to: String,
+        }
+        let bytes = rmp_serde::to_vec_named(&Request { to: "b.rs".to_owned() }).unwrap();
+        let rename = rmp_serde::from_slice::<RenameBuffer>(&bytes).unwrap();
+        assert_eq!(rename, RenameBuffer::new("b.rs".to_owned()));
+        assert!(rename.update().is_empty());
+    }
+
+    #[test]
+    fn plugin_cursor_does_not_decode_as_edit() {
+        let cursor = PluginCursor::new("a.rs".to_owned(), 4, 2);
+        let bytes = rmp_serde::to_vec_named(&cursor).unwrap();
+        assert!(rmp_serde::from_slice::<PluginEdit>(&bytes).is_err());
+        assert!(rmp_serde::from_slice::<PluginUpdate>(&bytes).is_err());
+        assert_eq!(rmp_serde::from_slice::<PluginCursor>(&bytes).unwrap(), cursor);
+    }
+
+    #[test]
+    fn awareness_without_state_means_peer_left() {
+        let left = AwarenessUpdate::new(7, None);
+        let msg = SyncMessage::with_body(MessageKind::Awareness, "a.rs".to_owned(), &left)
+            .expect("msg");
+        let decoded = msg.body::<AwarenessUpdate>().expect("body");
+        assert_eq!(decoded.client_id(), 7);
+        assert!(decoded.state().is_none());
+    }
+
     #[test]
     fn encode_frame_prefix_matches_payload_len() {
         let msg = SyncMessage::new(MessageKind::Update, "buffer".to_owned(), vec![1, 2, 3]);
@@ -233,4 +1302,29 @@ mod tests {
         assert_eq!(read_a, payload_a);
         assert_eq!(read_b, payload_b);
     }
+
+    #[tokio::test]
+    async fn frame_reader_refuses_oversized_frames() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut frame_reader = FrameReader::new(reader).with_max_frame(4);
+
+        // nothing behind the prefix: the reader must not wait for, or allocate, the body
+        writer.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        let read = frame_reader.read_frame().await;
+        assert_eq!(read, Err(FrameError::TooLarge(u32::MAX as usize)));
+    }
+
+    #[tokio::test]
+    async fn frame_reader_takes_frames_up_to_the_limit() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut frame_reader = FrameReader::new(reader).with_max_frame(4);
+
+        write_frame(&mut writer, &[1, 2, 3, 4]).await;
+        write_frame(&mut writer, &[1, 2]).await;
+        drop(writer);
+
+        assert_eq!(frame_reader.read_frame().await, Ok(vec![1, 2, 3, 4]));
+        assert_eq!(frame_reader.read_frame().await, Ok(vec![1, 2]));
+        assert_eq!(frame_reader.read_frame().await, Err(FrameError::Closed));
+    }
 }
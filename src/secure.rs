@@ -0,0 +1,251 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// plaintext bytes sealed per record, and what that becomes with the tag on
+const MAX_RECORD: usize = 16 * 1024;
+const TAG_LEN: usize = 16;
+
+// every record uses the next value of a per-direction counter as its nonce. each direction has
+// its own key so the two counters never collide
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Decrypts a stream of length-prefixed ChaCha20-Poly1305 records written by a `SecureWriter`.
+/// A record that fails to authenticate ends the stream with an error.
+pub struct SecureReader<R> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    record: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R> SecureReader<R> {
+    pub fn new(inner: R, key: &[u8; 32]) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+            record: Vec::new(),
+            plain: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    // how many bytes the record being read needs in total, once its length is known
+    fn record_len(&self) -> io::Result<usize> {
+        if self.record.len() < 4 {
+            return Ok(4);
+        }
+        let len = u32::from_be_bytes([
+            self.record[0],
+            self.record[1],
+            self.record[2],
+            self.record[3],
+        ]) as usize;
+        if !(TAG_LEN..=MAX_RECORD + TAG_LEN).contains(&len) {
+            return Err(invalid("encrypted record has an invalid length"));
+        }
+        Ok(4 + len)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SecureReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.plain.len() {
+                let n = (this.plain.len() - this.pos).min(buf.remaining());
+                buf.put_slice(&this.plain[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            let needed = this.record_len()?;
+            if this.record.len() < needed {
+                let mut chunk = [0u8; 4096];
+                let want = (needed - this.record.len()).min(chunk.len());
+                let mut read = ReadBuf::new(&mut chunk[..want]);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+                if read.filled().is_empty() {
+                    if this.record.is_empty() {
+                        return Poll::Ready(Ok(()));
+                    }
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.record.extend_from_slice(read.filled());
+                continue;
+            }
+            if needed == 4 {
+                continue;
+            }
+
+            this.plain = this
+                .cipher
+                .decrypt(&nonce(this.counter), &this.record[4..])
+                .map_err(|_| invalid("encrypted record failed to authenticate"))?;
+            this.counter += 1;
+            this.record.clear();
+            this.pos = 0;
+        }
+    }
+}
+
+/// Seals everything written into length-prefixed ChaCha20-Poly1305 records.
+pub struct SecureWriter<W> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<W> SecureWriter<W> {
+    pub fn new(inner: W, key: &[u8; 32]) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+            pending: Vec::new(),
+            written: 0,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> SecureWriter<W> {
+    // pushes out whatever sealed records the inner writer hasn't taken yet
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for SecureWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+
+        let n = buf.len().min(MAX_RECORD);
+        let sealed = this
+            .cipher
+            .encrypt(&nonce(this.counter), &buf[..n])
+            .map_err(|_| io::Error::other("failed to encrypt record"))?;
+        this.counter += 1;
+        this.pending
+            .extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+        this.pending.extend_from_slice(&sealed);
+
+        // the record is ours now. get it moving but don't make the caller wait for it
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{self, FrameReader, MessageKind, SyncMessage};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn frames_round_trip_through_records() {
+        let key = [7u8; 32];
+        let (client, server) = tokio::io::duplex(64);
+        let mut writer = SecureWriter::new(client, &key);
+        let mut reader = FrameReader::new(SecureReader::new(server, &key));
+
+        let big = SyncMessage::new(MessageKind::Update, "a.rs".to_owned(), vec![1; 40_000]);
+        let small = SyncMessage::new(MessageKind::Update, "b.rs".to_owned(), vec![2; 3]);
+        let task = tokio::spawn(async move {
+            for msg in [&big, &small] {
+                writer
+                    .write_all(&protocol::encode_frame(msg).unwrap())
+                    .await
+                    .unwrap();
+            }
+            writer.flush().await.unwrap();
+            (big, small)
+        });
+
+        let first = reader.read_one().await.unwrap();
+        let second = reader.read_one().await.unwrap();
+        let (big, small) = task.await.unwrap();
+        assert_eq!(rmp_serde::from_slice::<SyncMessage>(&first).unwrap(), big);
+        assert_eq!(
+            rmp_serde::from_slice::<SyncMessage>(&second).unwrap(),
+            small
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_key_is_rejected() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut writer = SecureWriter::new(client, &[1u8; 32]);
+        let mut reader = SecureReader::new(server, &[2u8; 32]);
+
+        writer.write_all(b"hello").await.unwrap();
+        writer.flush().await.unwrap();
+        let mut buf = [0u8; 5];
+        let err = reader.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn ciphertext_does_not_contain_plaintext() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut writer = SecureWriter::new(client, &[3u8; 32]);
+        writer.write_all(b"secret buffer contents").await.unwrap();
+        writer.flush().await.unwrap();
+        drop(writer);
+
+        let mut raw = Vec::new();
+        server.read_to_end(&mut raw).await.unwrap();
+        assert!(!raw
+            .windows(b"secret".len())
+            .any(|window| window == b"secret"));
+    }
+}
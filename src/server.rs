@@ -1,237 +1,2571 @@
-use std::net::{SocketAddr, SocketAddrV4};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use log::{debug, error, info, trace};
-use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc::{self, Sender};
-use tokio::sync::RwLock;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, info, trace, warn};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::WebSocketStream;
 
+use yrs::sync::{
+    AwarenessUpdate as YjsAwareness, Message as YjsMessage, SyncMessage as YjsSyncMessage,
+};
 use yrs::updates::decoder::Decode;
-use yrs::{Doc, ReadTxn, StateVector, Transact, Update};
+use yrs::{BranchID, Doc, GetString, ReadTxn, StateVector, Text, Transact, Update};
 
-use crate::protocol::{self, FrameReader, MessageKind, SyncMessage};
+use crate::auth::{self, SessionKeys};
+use crate::discovery;
+use crate::persist::Store;
+use crate::protocol::{
+    self, Authenticate, Authenticated, AwarenessUpdate, ErrorCode, ErrorMessage, FileList,
+    FrameError, FrameReader, Hello, ListFiles, MessageKind, Permissions, RenameBuffer,
+    ServerShutdown, SyncMessage, Welcome,
+};
+use crate::secure::{SecureReader, SecureWriter};
+use crate::websocket::{self, Outgoing, Upgrade, YjsSink};
+use crate::workspace::Workspace;
 
 const CHANNEL_SIZE: usize = 5;
+// frames a client may have waiting before it counts as too slow to keep up
+const OUTBOUND_QUEUE: usize = 256;
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+// peers that haven't authenticated yet only ever send a few small messages
+const MAX_HANDSHAKE_FRAME: usize = 64 * 1024;
+// how long a peer gets to send each of its handshake messages, so ones that connect and go quiet
+// don't hold a connection open forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// how long edits to a shared file may sit in the doc before they are written back
+const WRITE_BACK_INTERVAL: Duration = Duration::from_millis(500);
+// how long a stopping server waits for its shutdown notice to reach the clients
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+// Yjs clients one peer may show cursors for. an editor has one per open document
+const MAX_YJS_CLIENTS: usize = 16;
+// what a frame carries besides the update in it: the message around it, the buffer name and
+// the encryption
+const FRAME_HEADROOM: usize = 16 * 1024;
+// refused updates a peer may send within REJECTION_WINDOW before it is dropped
+const MAX_REJECTIONS: u32 = 10;
+const REJECTION_WINDOW: Duration = Duration::from_secs(60);
+
+pub struct ServerOptions {
+    /// Secret clients have to prove they know before they can join
+    pub token: String,
+    /// Directory the document is persisted to. Without one the session only lives in memory
+    pub state_dir: Option<PathBuf>,
+    /// How often a snapshot is written while there are unsnapshotted updates
+    pub snapshot_interval: Duration,
+    /// Directory whose files are shared through the default room
+    pub root: Option<PathBuf>,
+    /// Shares hidden files and ones .gitignore and friends exclude under `root` as well
+    pub share_ignored: bool,
+    /// Buffers, or directories of them, only owners may change
+    pub read_only: Vec<String>,
+    /// Largest frame in bytes a client may send. Larger ones get an error and the connection
+    /// closed. Updates that would grow a room's document past it are refused
+    pub max_frame: usize,
+    /// Bytes of outgoing frames that may wait for one client before it is dropped as too slow
+    pub connection_budget: usize,
+    /// Room to announce on the local network for `discover` to find. Without one the server
+    /// keeps quiet
+    pub announce: Option<String>,
+    /// Port for a WebSocket listener next to the TCP one, on the same address
+    pub websocket_port: Option<u16>,
+    /// Lets Yjs editors join over the WebSocket listener with y-websocket. They pass the token
+    /// in the URL, so it is only as safe as the connection it goes over
+    pub yjs: bool,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            token: auth::generate_token(),
+            state_dir: None,
+            snapshot_interval: Duration::from_secs(30),
+            root: None,
+            share_ignored: false,
+            read_only: Vec::new(),
+            max_frame: protocol::DEFAULT_MAX_FRAME,
+            connection_budget: 4 * protocol::DEFAULT_MAX_FRAME,
+            announce: None,
+            websocket_port: None,
+            yjs: false,
+        }
+    }
+}
+
+/// Server-assigned id of a connection, which is also the Yrs client id the peer edits under.
+pub type ClientId = u64;
 
 #[derive(Debug)]
 struct IncomingMessage {
-    from: SocketAddrV4,
+    room: String,
+    from: ClientId,
     content: Vec<u8>,
 }
 
-#[derive(Clone)]
-struct ClientPool {
-    clients: Arc<RwLock<Vec<OwnedWriteHalf>>>,
+#[derive(Debug)]
+enum Incoming {
+    Message(IncomingMessage),
+    // y-protocols messages from a Yjs peer, as many as came in one WebSocket message
+    Yjs(IncomingMessage),
+    Disconnected {
+        room: String,
+        from: ClientId,
+        connection: u64,
+    },
+    FileChanged {
+        room: String,
+        path: PathBuf,
+    },
+}
+
+// whatever a peer connected over, TCP or anything carrying the same byte stream
+type StreamReader = Box<dyn AsyncRead + Send + Unpin>;
+type StreamWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+type ClientWriter = SecureWriter<StreamWriter>;
+
+// encoded frames waiting to go out to one client. shared so a broadcast encodes once
+type Frame = Arc<[u8]>;
+
+// one client's frames waiting to go out, and how many bytes they come to
+struct Outbound {
+    frames: Sender<Frame>,
+    queued: Arc<AtomicUsize>,
+    budget: usize,
+    writer: JoinHandle<()>,
+    // the frames are translated to y-protocols on the way out
+    yjs: bool,
+}
+
+impl Outbound {
+    fn start(id: ClientId, writer: ClientWriter, budget: usize) -> Self {
+        let (frames, queue) = mpsc::channel(OUTBOUND_QUEUE);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let writer = tokio::spawn(run_writer(id, writer, queue, queued.clone()));
+        Self {
+            frames,
+            queued,
+            budget,
+            writer,
+            yjs: false,
+        }
+    }
+
+    fn start_yjs(id: ClientId, sink: YjsSink, budget: usize) -> Self {
+        let (frames, queue) = mpsc::channel(OUTBOUND_QUEUE);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let writer = tokio::spawn(run_yjs_writer(id, sink, queue, queued.clone()));
+        Self {
+            frames,
+            queued,
+            budget,
+            writer,
+            yjs: true,
+        }
+    }
+}
+
+struct Peer {
+    // tells this connection apart from a later one that resumed the same client id
+    connection: u64,
+    permissions: Permissions,
+    queue: Outbound,
+    // dropped along with the peer, which tells its reader to stop
+    _dropped: oneshot::Sender<()>,
+}
+
+#[derive(Clone)]
+struct ClientPool {
+    clients: Arc<RwLock<HashMap<ClientId, Peer>>>,
+    next_connection: Arc<AtomicU64>,
+}
+
+impl ClientPool {
+    fn new() -> Self {
+        Self {
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            next_connection: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // returns the number identifying this connection, to hand back to `remove`, and what
+    // resolves once the connection is dropped from the pool by whatever means
+    async fn add(
+        &self,
+        id: ClientId,
+        permissions: Permissions,
+        queue: Outbound,
+    ) -> (u64, oneshot::Receiver<()>) {
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        let (dropped, on_drop) = oneshot::channel();
+        let peer = Peer {
+            connection,
+            permissions,
+            queue,
+            _dropped: dropped,
+        };
+        self.clients.write().await.insert(id, peer);
+        (connection, on_drop)
+    }
+
+    // removes the client unless its id has since been taken over by a newer connection
+    async fn remove(&self, id: ClientId, connection: u64) -> bool {
+        let mut clients = self.clients.write().await;
+        match clients.get(&id) {
+            Some(peer) if peer.connection == connection => {
+                clients.remove(&id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // drops the client whichever connection it's on
+    async fn evict(&self, id: ClientId) {
+        self.clients.write().await.remove(&id);
+    }
+
+    async fn contains(&self, id: ClientId) -> bool {
+        self.clients.read().await.contains_key(&id)
+    }
+
+    async fn can_write(&self, id: ClientId, buffer: &str) -> bool {
+        let clients = self.clients.read().await;
+        clients
+            .get(&id)
+            .is_some_and(|peer| peer.permissions.can_write(buffer))
+    }
+
+    // drops every client. their writers still send what was queued, and are handed back so
+    // that can be waited for
+    async fn close(&self) -> Vec<JoinHandle<()>> {
+        let mut clients = self.clients.write().await;
+        clients.drain().map(|(_, peer)| peer.queue.writer).collect()
+    }
+
+    // queues data for all clients but one. never waits on a socket, a client whose queue is
+    // full is dropped instead
+    async fn broadcast(&self, data: &[u8], ignore: Option<ClientId>) {
+        let frame: Frame = Arc::from(data);
+        let mut clients = self.clients.write().await;
+        clients.retain(|id, peer| {
+            if Some(*id) == ignore {
+                trace!("Skipping {}", id);
+                return true;
+            }
+            enqueue(*id, peer, frame.clone())
+        });
+    }
+
+    // like `broadcast`, for what only Yjs peers understand
+    async fn broadcast_yjs(&self, data: &[u8], ignore: Option<ClientId>) {
+        let frame: Frame = Arc::from(data);
+        let mut clients = self.clients.write().await;
+        clients.retain(|id, peer| {
+            if Some(*id) == ignore || !peer.queue.yjs {
+                return true;
+            }
+            enqueue(*id, peer, frame.clone())
+        });
+    }
+
+    async fn send_to(&self, data: &[u8], id: ClientId) {
+        let mut clients = self.clients.write().await;
+        let Some(peer) = clients.get(&id) else {
+            return;
+        };
+        if !enqueue(id, peer, Arc::from(data)) {
+            clients.remove(&id);
+        }
+    }
+}
+
+// false if the client has to go. one that fell this far behind gets disconnected, and
+// resyncs by state vector when it reconnects
+fn enqueue(id: ClientId, peer: &Peer, frame: Frame) -> bool {
+    let queue = &peer.queue;
+    let queued = queue.queued.fetch_add(frame.len(), Ordering::Relaxed) + frame.len();
+    if queued > queue.budget {
+        warn!(
+            "Client {} has over {} bytes waiting, disconnecting it",
+            id, queue.budget
+        );
+        return false;
+    }
+    match queue.frames.try_send(frame) {
+        Ok(()) => {
+            trace!("Queued frame for client {}", id);
+            true
+        }
+        Err(TrySendError::Full(_)) => {
+            warn!("Client {} fell too far behind, disconnecting it", id);
+            false
+        }
+        Err(TrySendError::Closed(_)) => {
+            info!("Client {} disconnected", id);
+            false
+        }
+    }
+}
+
+// drains one client's queue onto its socket, so a slow link only ever holds up itself. the
+// socket is closed once the client is dropped from the pool or a write fails
+async fn run_writer(
+    id: ClientId,
+    mut writer: ClientWriter,
+    mut queue: Receiver<Frame>,
+    queued: Arc<AtomicUsize>,
+) {
+    while let Some(frame) = queue.recv().await {
+        queued.fetch_sub(frame.len(), Ordering::Relaxed);
+        let written = tokio::time::timeout(WRITE_TIMEOUT, async {
+            writer.write_all(&frame).await?;
+            writer.flush().await
+        })
+        .await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                info!("Failed to write to client {}: {}", id, e);
+                return;
+            }
+            Err(_) => {
+                warn!("Timed out writing to client {}", id);
+                return;
+            }
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+// `run_writer` for a Yjs peer, which gets each frame in y-protocols or not at all
+async fn run_yjs_writer(
+    id: ClientId,
+    mut sink: YjsSink,
+    mut queue: Receiver<Frame>,
+    queued: Arc<AtomicUsize>,
+) {
+    while let Some(frame) = queue.recv().await {
+        queued.fetch_sub(frame.len(), Ordering::Relaxed);
+        let data = match websocket::translate(&frame) {
+            Some(Outgoing::Message(data)) => data,
+            Some(Outgoing::Close(reason)) => {
+                websocket::close(&mut sink, CloseCode::Away, reason).await;
+                return;
+            }
+            None => continue,
+        };
+        let sent = tokio::time::timeout(WRITE_TIMEOUT, sink.send(WsMessage::Binary(data.into())));
+        match sent.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                info!("Failed to write to client {}: {}", id, e);
+                return;
+            }
+            Err(_) => {
+                warn!("Timed out writing to client {}", id);
+                return;
+            }
+        }
+    }
+    let _ = sink.close().await;
+}
+
+// one shared document and the peers editing it. rooms never see each other's buffers or cursors
+struct Room {
+    name: String,
+    doc: Doc,
+    pool: ClientPool,
+    // last cursor each peer shared, with the buffer it was in, so newcomers see everyone and
+    // the others can be told when a peer leaves
+    awareness: HashMap<ClientId, (String, AwarenessUpdate)>,
+    // the same for Yjs peers, in their own format. one peer may speak for several Yjs clients
+    yjs_awareness: HashMap<ClientId, YjsAwareness>,
+    // buffers that were opened or created and not deleted since
+    buffers: HashSet<String>,
+    store: Option<Mutex<Store>>,
+    workspace: Option<Mutex<Workspace>>,
+    trial: Mutex<Trial>,
+    // refused updates per peer, with when it started counting
+    rejections: Mutex<HashMap<ClientId, (u32, Instant)>>,
+    size: DocSize,
+    // every id handed out or found in the document. fresh ids avoid these so no two peers ever
+    // edit under the same Yrs client id
+    known_ids: HashSet<ClientId>,
+    // connections that joined and haven't left yet, counting ones still authenticating
+    members: usize,
+}
+
+impl Room {
+    async fn open(name: &str, server: &ServerState) -> Self {
+        let doc = Doc::new();
+        let store = match &server.state_dir {
+            Some(dir) => open_store(&doc, &room_dir(dir, name)).await.map(Mutex::new),
+            None => None,
+        };
+        let workspace = match &server.root {
+            Some(root) if name == protocol::DEFAULT_ROOM => {
+                let workspace = open_workspace(root, name, server.events.clone());
+                let workspace = workspace.map(|w| w.sharing_ignored(server.share_ignored));
+                workspace.map(Mutex::new)
+            }
+            _ => None,
+        };
+        // the server edits under its own id when merging changes made on disk
+        let mut known_ids: HashSet<ClientId> = doc
+            .transact()
+            .state_vector()
+            .iter()
+            .map(|(id, _)| *id)
+            .collect();
+        known_ids.insert(doc.client_id());
+        let buffers = live_buffers(&doc);
+        let size = DocSize::new(&doc, server.max_frame);
+
+        Self {
+            name: name.to_owned(),
+            doc,
+            pool: ClientPool::new(),
+            awareness: HashMap::new(),
+            yjs_awareness: HashMap::new(),
+            buffers,
+            store,
+            workspace,
+            trial: Mutex::new(Trial::new()),
+            rejections: Mutex::new(HashMap::new()),
+            size,
+            known_ids,
+            members: 0,
+        }
+    }
+
+    // gives a reconnecting client that proved the id was issued to it its old id back, taking it
+    // over from a connection that hasn't noticed it's gone yet. anyone else gets one nobody has
+    // used in this document
+    async fn assign_client_id(&mut self, resumed: Option<ClientId>) -> ClientId {
+        if let Some(id) = resumed {
+            if self.pool.contains(id).await {
+                info!("Client {} resumed, dropping its stale connection", id);
+            }
+            self.pool.evict(id).await;
+            self.known_ids.insert(id);
+            return id;
+        }
+
+        loop {
+            let id = auth::client_id();
+            if self.known_ids.insert(id) {
+                return id;
+            }
+        }
+    }
+}
+
+// buffers in a restored doc. yrs can't drop a root, so deleted buffers are left behind empty and
+// don't count
+fn live_buffers(doc: &Doc) -> HashSet<String> {
+    let names: Vec<String> = doc
+        .transact()
+        .root_refs()
+        .map(|(name, _)| name.to_owned())
+        .collect();
+    names
+        .into_iter()
+        .filter(|name| {
+            let text = doc.get_or_insert_text(name.as_str());
+            let txn = doc.transact();
+            text.len(&txn) > 0
+        })
+        .collect()
+}
+
+// changes to the files are handed to the main loop, which merges them into the room's doc
+fn open_workspace(root: &Path, room: &str, events: Sender<Incoming>) -> Option<Workspace> {
+    let room = room.to_owned();
+    let on_change = move |path| {
+        let changed = Incoming::FileChanged {
+            room: room.clone(),
+            path,
+        };
+        let _ = events.blocking_send(changed);
+    };
+    match Workspace::open(root, on_change) {
+        Ok(workspace) => Some(workspace),
+        Err(e) => {
+            error!("Failed to share {}: {}", root.display(), e);
+            None
+        }
+    }
+}
+
+// the default room lives at the top of the state directory, where the whole session was kept
+// before there were rooms
+fn room_dir(state_dir: &Path, name: &str) -> PathBuf {
+    if name == protocol::DEFAULT_ROOM {
+        state_dir.to_owned()
+    } else {
+        state_dir.join("rooms").join(name)
+    }
+}
+
+struct ServerState {
+    rooms: HashMap<String, Arc<RwLock<Room>>>,
+    token: String,
+    state_dir: Option<PathBuf>,
+    root: Option<PathBuf>,
+    share_ignored: bool,
+    read_only: Vec<String>,
+    max_frame: usize,
+    connection_budget: usize,
+    yjs: bool,
+    // where rooms report changes to their shared files
+    events: Sender<Incoming>,
+}
+
+impl ServerState {
+    // counts the connection into the room, opening it first if nobody is in it
+    async fn join(&mut self, name: &str) -> Arc<RwLock<Room>> {
+        let room = match self.rooms.get(name) {
+            Some(room) => room.clone(),
+            None => {
+                info!("Opening room {}", name);
+                let room = Arc::new(RwLock::new(Room::open(name, self).await));
+                self.rooms.insert(name.to_owned(), room.clone());
+                room
+            }
+        };
+        room.write().await.members += 1;
+        room
+    }
+
+    fn room(&self, name: &str) -> Option<Arc<RwLock<Room>>> {
+        self.rooms.get(name).cloned()
+    }
+}
+
+// closes the room once its last connection is gone. without a state directory whatever
+// was in it goes too, reconnecting clients bring their copy back with them. the flush happens
+// outside the state lock so the other rooms don't wait on this one's disk
+async fn leave(state: &Arc<RwLock<ServerState>>, name: &str) {
+    let Some(room) = state.read().await.room(name) else {
+        return;
+    };
+    {
+        let mut room = room.write().await;
+        room.members = room.members.saturating_sub(1);
+        if room.members > 0 {
+            return;
+        }
+    }
+    info!("Closing empty room {}", name);
+    write_back(&room).await;
+    snapshot(&room).await;
+
+    // joining happens under the state lock, so nobody can sneak in between the check and the
+    // removal. whoever joined during the flush keeps the room open
+    let mut state = state.write().await;
+    if room.read().await.members > 0 {
+        return;
+    }
+    // or another leave got here first and a fresh room took the name
+    let same = state
+        .room(name)
+        .is_some_and(|open| Arc::ptr_eq(&open, &room));
+    if same {
+        state.rooms.remove(name);
+    }
+}
+
+/// Where a server takes its peers from.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// A Unix domain socket, for peers on the same machine. The socket file is removed again on
+    /// shutdown
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+    /// The one peer on the other end of stdin and stdout, such as `connect --stdio-remote`
+    /// running `ssh host neo-live serve --stdio`. The session ends once it leaves
+    Stdio,
+}
+
+impl Listener {
+    /// Binds a Unix domain socket at `path`. A socket file left behind by a server that is gone
+    /// is replaced, one that still answers is an error.
+    #[cfg(unix)]
+    pub async fn bind_unix(path: &Path) -> io::Result<Self> {
+        if UnixStream::connect(path).await.is_ok() {
+            let message = format!("a session is already served at {}", path.display());
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, message));
+        }
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        Ok(Self::Unix(UnixListener::bind(path)?, path.to_owned()))
+    }
+}
+
+async fn run_listener(listener: Listener, tx: Sender<Incoming>, state: Arc<RwLock<ServerState>>) {
+    match listener {
+        Listener::Tcp(listener) => loop {
+            match listener.accept().await {
+                Ok((stream, address)) => {
+                    info!("{} connected to server", address);
+                    let (read_half, write_half) = stream.into_split();
+                    tokio::spawn(accept_client(
+                        Box::new(read_half),
+                        Box::new(write_half),
+                        address.to_string(),
+                        tx.clone(),
+                        state.clone(),
+                    ));
+                }
+                Err(e) => {
+                    error!("Listener error: {:?}", e);
+                    break;
+                }
+            }
+        },
+        #[cfg(unix)]
+        Listener::Unix(listener, path) => loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    // peers on a socket have no address of their own
+                    let address = path.display().to_string();
+                    info!("Peer connected to server on {}", address);
+                    let (read_half, write_half) = stream.into_split();
+                    tokio::spawn(accept_client(
+                        Box::new(read_half),
+                        Box::new(write_half),
+                        address,
+                        tx.clone(),
+                        state.clone(),
+                    ));
+                }
+                Err(e) => {
+                    error!("Listener error: {:?}", e);
+                    break;
+                }
+            }
+        },
+        Listener::Stdio => {
+            info!("Serving the peer on stdio");
+            let (read_half, write_half) = (tokio::io::stdin(), tokio::io::stdout());
+            let (read_half, write_half) = (Box::new(read_half), Box::new(write_half));
+            accept_client(read_half, write_half, "stdio".to_owned(), tx, state).await;
+        }
+    }
+}
+
+/// Passes stdin and stdout through to the session served on the Unix socket at `path`, so a
+/// peer reaching this machine over SSH joins the same session as everyone else.
+#[cfg(unix)]
+pub async fn relay_stdio(path: &Path) -> io::Result<()> {
+    let (mut from_server, mut to_server) = UnixStream::connect(path).await?.into_split();
+    let (mut stdin, mut stdout) = (tokio::io::stdin(), tokio::io::stdout());
+    // either side closing ends the relay, the other notices once its stream closes
+    tokio::select! {
+        copied = tokio::io::copy(&mut stdin, &mut to_server) => copied?,
+        copied = tokio::io::copy(&mut from_server, &mut stdout) => copied?,
+    };
+    Ok(())
+}
+
+async fn send_error<W>(write_half: &mut W, code: ErrorCode, message: String)
+where
+    W: AsyncWrite + Unpin,
+{
+    let error = ErrorMessage::new(code, message);
+    let Some(msg) = SyncMessage::with_body(MessageKind::Error, String::new(), &error) else {
+        return;
+    };
+    let Some(framed) = protocol::encode_frame(&msg) else {
+        return;
+    };
+    if write_half.write_all(&framed).await.is_ok() {
+        let _ = write_half.flush().await;
+    }
+}
+
+// frames a peer can't send without being told so before the connection closes
+fn too_large(len: usize, max_frame: usize) -> String {
+    format!("frame of {} bytes is over the limit of {}", len, max_frame)
+}
+
+async fn read_handshake_frame<R, W>(
+    reader: &mut FrameReader<R>,
+    write_half: &mut W,
+    address: &str,
+) -> Option<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let Ok(read) = tokio::time::timeout(HANDSHAKE_TIMEOUT, reader.read_frame()).await else {
+        warn!("{} went quiet during the handshake", address);
+        return None;
+    };
+    match read {
+        Ok(bytes) => Some(bytes),
+        Err(FrameError::Closed) => None,
+        Err(FrameError::TooLarge(len)) => {
+            warn!("{} sent an oversized frame during the handshake", address);
+            let message = too_large(len, reader.max_frame());
+            send_error(write_half, ErrorCode::FrameTooLarge, message).await;
+            None
+        }
+    }
+}
+
+// waits for the client's Hello and answers with Welcome, or an error frame if the peer can't be
+// served. Welcome carries our half of the Noise handshake for the role asked for, so both sides
+// come out with the keys for everything after it
+async fn handshake(
+    reader: &mut FrameReader<StreamReader>,
+    write_half: &mut StreamWriter,
+    address: &str,
+    session_token: &str,
+) -> Option<(Hello, SessionKeys)> {
+    let bytes = read_handshake_frame(reader, write_half, address).await?;
+    let hello = match rmp_serde::from_slice::<SyncMessage>(&bytes) {
+        Ok(msg) if msg.kind == MessageKind::Hello => msg.body::<Hello>(),
+        _ => None,
+    };
+    let Some(hello) = hello else {
+        warn!("{} did not start with a Hello", address);
+        let message = "expected Hello as the first message".to_owned();
+        send_error(write_half, ErrorCode::UnexpectedMessage, message).await;
+        return None;
+    };
+
+    if !hello.is_compatible() {
+        warn!(
+            "Rejecting {}: protocol {} (neo-live {}), server speaks {}",
+            address,
+            hello.protocol_version(),
+            hello.crate_version(),
+            protocol::PROTOCOL_VERSION
+        );
+        let message = format!(
+            "incompatible protocol version {} (neo-live {}), server speaks {} (neo-live {})",
+            hello.protocol_version(),
+            hello.crate_version(),
+            protocol::PROTOCOL_VERSION,
+            protocol::CRATE_VERSION
+        );
+        send_error(write_half, ErrorCode::IncompatibleVersion, message).await;
+        return None;
+    }
+
+    if !protocol::is_valid_room(hello.room()) {
+        warn!(
+            "Rejecting {}: invalid room name {:?}",
+            address,
+            hello.room()
+        );
+        let message = format!("invalid room name {:?}", hello.room());
+        send_error(write_half, ErrorCode::InvalidRoom, message).await;
+        return None;
+    }
+
+    let mut noise = auth::Handshake::server(session_token, hello.room(), hello.role())?;
+    let Some(reply) = noise.read(hello.handshake()).and_then(|()| noise.write()) else {
+        warn!("{} opened a handshake we can't answer", address);
+        let message = "the handshake in Hello doesn't decode".to_owned();
+        send_error(write_half, ErrorCode::InvalidMessage, message).await;
+        return None;
+    };
+
+    let welcome = Welcome::for_hello(&hello).answering(reply);
+    let msg = SyncMessage::with_body(MessageKind::Welcome, String::new(), &welcome)?;
+    write_message(write_half, &msg).await?;
+
+    info!(
+        "{} speaks neo-live {} with capabilities {:?}",
+        address,
+        hello.crate_version(),
+        welcome.capabilities()
+    );
+    Some((hello, noise.finish()?))
+}
+
+async fn write_message<W>(write_half: &mut W, msg: &SyncMessage) -> Option<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let framed = protocol::encode_frame(msg)?;
+    write_half.write_all(&framed).await.ok()?;
+    write_half.flush().await.ok()
+}
+
+// reads the client's first encrypted message. one that decrypts shows the client holds the
+// token of the role it asked for, nothing about the room is touched until then
+async fn authenticate<R, W>(
+    reader: &mut FrameReader<R>,
+    write_half: &mut W,
+    address: &str,
+) -> Option<Authenticate>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let Some(bytes) = read_handshake_frame(reader, write_half, address).await else {
+        warn!("{} did not finish the handshake", address);
+        return None;
+    };
+    let authenticate = match rmp_serde::from_slice::<SyncMessage>(&bytes) {
+        Ok(msg) if msg.kind == MessageKind::Authenticate => msg.body::<Authenticate>(),
+        _ => None,
+    };
+    if authenticate.is_none() {
+        warn!("{} did not authenticate", address);
+        let message = "expected Authenticate after Welcome".to_owned();
+        send_error(write_half, ErrorCode::UnexpectedMessage, message).await;
+    }
+    authenticate
+}
+
+// the id asked for in `hello` if the client proved it was issued to it
+fn resumed(
+    hello: &Hello,
+    authenticate: &Authenticate,
+    keys: &SessionKeys,
+    session_token: &str,
+) -> Option<ClientId> {
+    let id = hello.client_id()?;
+    let proof = authenticate.resume()?;
+    let secret = auth::resume_secret(session_token, hello.room(), id);
+    if auth::verify_resume(&secret, &keys.transcript, proof) {
+        return Some(id);
+    }
+    warn!("Client failed to prove it was given id {}", id);
+    None
+}
+
+// tells the client who it is in the room and what it may change
+async fn admit<W>(
+    write_half: &mut W,
+    address: &str,
+    hello: &Hello,
+    session_token: &str,
+    client_id: ClientId,
+    permissions: Permissions,
+) -> Option<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let resume_secret = auth::resume_secret(session_token, hello.room(), client_id);
+    let authenticated = Authenticated::new(client_id, permissions, resume_secret);
+    let msg = SyncMessage::with_body(MessageKind::Authenticated, String::new(), &authenticated)?;
+    write_message(write_half, &msg).await?;
+    info!(
+        "{} authenticated as client {} with role {:?}",
+        address,
+        client_id,
+        hello.role()
+    );
+    Some(())
+}
+
+async fn accept_client(
+    read_half: StreamReader,
+    mut write_half: StreamWriter,
+    address: String,
+    tx: Sender<Incoming>,
+    state: Arc<RwLock<ServerState>>,
+) {
+    let mut reader = FrameReader::new(read_half).with_max_frame(MAX_HANDSHAKE_FRAME);
+    let (token, read_only, max_frame, budget) = {
+        let state = state.read().await;
+        (
+            state.token.clone(),
+            state.read_only.clone(),
+            state.max_frame,
+            state.connection_budget,
+        )
+    };
+
+    let handshake = handshake(&mut reader, &mut write_half, &address, &token).await;
+    let Some((hello, keys)) = handshake else {
+        info!("Closing connection to {}", address);
+        return;
+    };
+    let room_name = hello.room().clone();
+
+    // everything past Welcome is encrypted
+    let mut reader = FrameReader::new(SecureReader::new(
+        reader.into_inner(),
+        &keys.client_to_server,
+    ))
+    .with_max_frame(MAX_HANDSHAKE_FRAME);
+    let mut write_half = SecureWriter::new(write_half, &keys.server_to_client);
+
+    // unauthenticated peers never open a room, let alone make it into the pool
+    let Some(authenticate) = authenticate(&mut reader, &mut write_half, &address).await else {
+        info!("Closing connection to {}", address);
+        return;
+    };
+
+    let room = state.write().await.join(&room_name).await;
+    let resumed = resumed(&hello, &authenticate, &keys, &token);
+    let client_id = room.write().await.assign_client_id(resumed).await;
+    let permissions = Permissions::new(hello.role(), read_only);
+    let admitted = admit(
+        &mut write_half,
+        &address,
+        &hello,
+        &token,
+        client_id,
+        permissions.clone(),
+    )
+    .await;
+    if admitted.is_none() {
+        info!("Closing connection to {}", address);
+        leave(&state, &room_name).await;
+        return;
+    }
+    info!("Client {} joined room {}", client_id, room_name);
+
+    let mut reader = reader.with_max_frame(max_frame);
+    let queue = Outbound::start(client_id, write_half, budget);
+
+    // add the client's queue to the pool for broadcasting
+    let (connection, mut dropped) = {
+        let room = room.read().await;
+        let (connection, dropped) = room.pool.add(client_id, permissions, queue).await;
+
+        // catch the newcomer up on where everyone is
+        for (buffer, update) in room.awareness.values() {
+            let Some(msg) = SyncMessage::with_body(MessageKind::Awareness, buffer.clone(), update)
+            else {
+                continue;
+            };
+            if let Some(framed) = protocol::encode_frame(&msg) {
+                room.pool.send_to(&framed, client_id).await;
+            }
+        }
+        (connection, dropped)
+    };
+
+    // when the stream sends messages, add "from" id so when it gets broadcasted
+    // it doesn't get sent back to the same guy
+    loop {
+        let read = tokio::select! {
+            read = reader.read_frame() => read,
+            // fell behind, or a reconnect took over the id. nothing more from here is ours
+            _ = &mut dropped => break,
+        };
+        let msg = match read {
+            Ok(msg) => msg,
+            Err(FrameError::Closed) => break,
+            // the error goes out ahead of the close, since the writer drains the queue first
+            Err(FrameError::TooLarge(len)) => {
+                warn!(
+                    "Client {} sent an oversized frame, disconnecting it",
+                    client_id
+                );
+                let message = too_large(len, max_frame);
+                let room = room.read().await;
+                reply_error(&room, client_id, "", ErrorCode::FrameTooLarge, message).await;
+                break;
+            }
+        };
+        let msg = IncomingMessage {
+            room: room_name.clone(),
+            from: client_id,
+            content: msg,
+        };
+        if tx.send(Incoming::Message(msg)).await.is_err() {
+            return;
+        }
+    }
+    let disconnected = Incoming::Disconnected {
+        room: room_name,
+        from: client_id,
+        connection,
+    };
+    let _ = tx.send(disconnected).await;
+}
+
+async fn run_websocket_listener(
+    listener: TcpListener,
+    tx: Sender<Incoming>,
+    state: Arc<RwLock<ServerState>>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                info!("{} connected over WebSocket", address);
+                tokio::spawn(accept_websocket(
+                    stream,
+                    address.to_string(),
+                    tx.clone(),
+                    state.clone(),
+                ));
+            }
+            Err(e) => {
+                error!("WebSocket listener error: {:?}", e);
+                break;
+            }
+        }
+    }
+}
+
+async fn accept_websocket(
+    stream: TcpStream,
+    address: String,
+    tx: Sender<Incoming>,
+    state: Arc<RwLock<ServerState>>,
+) {
+    let (max_frame, yjs) = {
+        let state = state.read().await;
+        (state.max_frame, state.yjs)
+    };
+    let Some((ws, upgrade)) = websocket::accept(stream, &address, max_frame, yjs).await else {
+        return;
+    };
+    match upgrade {
+        Upgrade::NeoLive => {
+            let (read_half, write_half) = tokio::io::split(websocket::into_stream(ws));
+            let (read_half, write_half) = (Box::new(read_half), Box::new(write_half));
+            accept_client(read_half, write_half, address, tx, state).await;
+        }
+        Upgrade::Yjs { room, token } => accept_yjs(ws, address, room, token, tx, state).await,
+    }
+}
+
+// a Yjs editor joins as a peer like any other, its messages are only translated both ways. it
+// hands over its token instead of going through the handshake, and the sync is y-protocols' own
+async fn accept_yjs(
+    ws: WebSocketStream<TcpStream>,
+    address: String,
+    room_name: String,
+    token: String,
+    tx: Sender<Incoming>,
+    state: Arc<RwLock<ServerState>>,
+) {
+    let (mut sink, mut stream) = ws.split();
+    let (session_token, read_only, budget) = {
+        let state = state.read().await;
+        (
+            state.token.clone(),
+            state.read_only.clone(),
+            state.connection_budget,
+        )
+    };
+    let refusal = if protocol::is_valid_room(&room_name) {
+        auth::role_for_token(&session_token, &token).ok_or("invalid session token".to_owned())
+    } else {
+        Err(format!("invalid room name {:?}", room_name))
+    };
+    let role = match refusal {
+        Ok(role) => role,
+        Err(message) => {
+            warn!("Rejecting Yjs peer {}: {}", address, message);
+            let denied = websocket::denied(message.clone());
+            let _ = sink.send(WsMessage::Binary(denied.into())).await;
+            websocket::close(&mut sink, CloseCode::Policy, message).await;
+            return;
+        }
+    };
+
+    let room = state.write().await.join(&room_name).await;
+    let (client_id, connection, mut dropped) = {
+        let mut room = room.write().await;
+        let client_id = room.assign_client_id(None).await;
+        let permissions = Permissions::new(role, read_only);
+        let queue = Outbound::start_yjs(client_id, sink, budget);
+        let (connection, dropped) = room.pool.add(client_id, permissions, queue).await;
+
+        // the server asks for what it's missing right away, and shows who else is around
+        let state_vector = room.doc.transact().state_vector();
+        send_yjs(&room, client_id, websocket::sync_step1(state_vector)).await;
+        if let Some(everyone) = yjs_awareness_of(&room) {
+            send_yjs(&room, client_id, websocket::awareness(everyone)).await;
+        }
+        (client_id, connection, dropped)
+    };
+    info!(
+        "{} joined room {} as Yjs client {} with role {:?}",
+        address, room_name, client_id, role
+    );
+
+    loop {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = &mut dropped => break,
+        };
+        let Some(message) = message else {
+            break;
+        };
+        let data = match message {
+            Ok(WsMessage::Binary(data)) => data,
+            Ok(WsMessage::Close(_)) => break,
+            Ok(_) => continue,
+            // too large a message ends up here too
+            Err(e) => {
+                warn!("Yjs client {} errored, disconnecting it: {}", client_id, e);
+                break;
+            }
+        };
+        let msg = IncomingMessage {
+            room: room_name.clone(),
+            from: client_id,
+            content: data.to_vec(),
+        };
+        if tx.send(Incoming::Yjs(msg)).await.is_err() {
+            return;
+        }
+    }
+    let disconnected = Incoming::Disconnected {
+        room: room_name,
+        from: client_id,
+        connection,
+    };
+    let _ = tx.send(disconnected).await;
+}
+
+// in a room sharing a directory every buffer is a file under it. anything else gets an error
+// back instead of a reply
+async fn check_buffer(room: &Room, from: ClientId, buffer: &str) -> bool {
+    let Some(workspace) = &room.workspace else {
+        return true;
+    };
+    if workspace.lock().await.path_for(buffer).await.is_some() {
+        return true;
+    }
+
+    warn!(
+        "Client {} asked for {:?} outside of the shared root",
+        from, buffer
+    );
+    let message = format!("{} is outside of the shared directory", buffer);
+    reply_error(room, from, buffer, ErrorCode::InvalidPath, message).await;
+    false
+}
+
+// peers that may not change the buffer are told so instead of having their change applied
+async fn check_write(room: &Room, from: ClientId, buffer: &str) -> bool {
+    if room.pool.can_write(from, buffer).await {
+        return true;
+    }
+    warn!("Client {} may not change {:?}", from, buffer);
+    let message = format!("{} is read-only", buffer);
+    reply_error(room, from, "", ErrorCode::ReadOnly, message).await;
+    false
+}
+
+// why `from` may not change the buffer, if it may not
+async fn write_refusal(room: &Room, from: ClientId, buffer: &str) -> Option<(ErrorCode, String)> {
+    if let Some(workspace) = &room.workspace {
+        if workspace.lock().await.path_for(buffer).await.is_none() {
+            let message = format!("{} is outside of the shared directory", buffer);
+            return Some((ErrorCode::InvalidPath, message));
+        }
+    }
+    if !room.pool.can_write(from, buffer).await {
+        return Some((ErrorCode::ReadOnly, format!("{} is read-only", buffer)));
+    }
+    None
+}
+
+// tells the sender its update wasn't applied. it has the update in its own doc already, so the
+// error says as much for it to start over from ours
+async fn refuse_update(
+    room: &Room,
+    from: ClientId,
+    buffer: &str,
+    code: ErrorCode,
+    message: String,
+) {
+    warn!("Refused update from client {}: {}", from, message);
+    let error = ErrorMessage::new(code, message).refusing_update();
+    let msg = SyncMessage::with_body(MessageKind::Error, buffer.to_owned(), &error);
+    if let Some(framed) = msg.as_ref().and_then(protocol::encode_frame) {
+        room.pool.send_to(&framed, from).await;
+    }
+    count_rejection(room, from).await;
+}
+
+// tells one client its request failed. errors naming a buffer make the client stop syncing it
+async fn reply_error(room: &Room, to: ClientId, buffer: &str, code: ErrorCode, message: String) {
+    let error = ErrorMessage::new(code, message);
+    let msg = SyncMessage::with_body(MessageKind::Error, buffer.to_owned(), &error);
+    if let Some(framed) = msg.as_ref().and_then(protocol::encode_frame) {
+        room.pool.send_to(&framed, to).await;
+    }
+}
+
+// tells the sender its message doesn't decode as what its kind says it is
+async fn reject(room: &Arc<RwLock<Room>>, from: ClientId, kind: MessageKind) {
+    warn!("Client {} sent a malformed {:?} message", from, kind);
+    let message = format!("malformed {:?} message", kind);
+    let room = room.read().await;
+    reply_error(&room, from, "", ErrorCode::InvalidMessage, message).await;
+}
+
+// buffer names go into the doc, into paths and back out to every peer, so ones nobody could
+// have opened are turned away before any handler sees them
+async fn check_buffer_name(room: &Arc<RwLock<Room>>, from: ClientId, buffer: &str) -> bool {
+    if protocol::is_valid_buffer(buffer) {
+        return true;
+    }
+    warn!("Client {} sent an invalid buffer name", from);
+    let message = "invalid buffer name".to_owned();
+    let room = room.read().await;
+    reply_error(&room, from, "", ErrorCode::InvalidBuffer, message).await;
+    false
+}
+
+async fn log_update(room: &Room, update: &[u8]) {
+    if let Some(store) = &room.store {
+        if let Err(e) = store.lock().await.append(update).await {
+            error!("Failed to append update to the write-ahead log: {}", e);
+        }
+    }
+}
+
+// logs an update the server made itself and sends it to everyone but `ignore`
+async fn publish(room: &Room, buffer: String, update: Vec<u8>, ignore: Option<ClientId>) {
+    room.size.grow(update.len());
+    log_update(room, &update).await;
+    let msg = SyncMessage::new(MessageKind::Update, buffer, update);
+    if let Some(framed) = protocol::encode_frame(&msg) {
+        room.pool.broadcast(&framed, ignore).await;
+    }
+}
+
+// the first peer to open a shared file brings it in from disk, everyone but `ignore` who has it
+// open gets it as a regular update
+async fn open_buffer(
+    room: &Arc<RwLock<Room>>,
+    from: ClientId,
+    buffer: &str,
+    ignore: Option<ClientId>,
+) -> bool {
+    let mut room = room.write().await;
+    if !check_buffer(&room, from, buffer).await {
+        return false;
+    }
+    room.buffers.insert(buffer.to_owned());
+    if let Some(workspace) = &room.workspace {
+        let loaded = workspace.lock().await.load(&room.doc, buffer).await;
+        if let Some(update) = loaded {
+            publish(&room, buffer.to_owned(), update, ignore).await;
+        }
+    }
+    true
+}
+
+async fn handle_initial_sync(room: &Arc<RwLock<Room>>, from: ClientId, msg: SyncMessage) {
+    let buffer_name = msg.buffer.clone();
+    let state_vector = if msg.payload.is_empty() {
+        StateVector::default()
+    } else if let Ok(sv) = StateVector::decode_v1(&msg.payload) {
+        sv
+    } else {
+        warn!("Client {} sent a state vector that doesn't decode", from);
+        let message = format!("the state vector for {} doesn't decode", buffer_name);
+        let room = room.read().await;
+        reply_error(
+            &room,
+            from,
+            &buffer_name,
+            ErrorCode::InvalidMessage,
+            message,
+        )
+        .await;
+        return;
+    };
+
+    if !open_buffer(room, from, &buffer_name, Some(from)).await {
+        return;
+    }
+
+    let updates = {
+        let room_guard = room.read().await;
+        let _text = room_guard.doc.get_or_insert_text(buffer_name.as_str());
+        let updates = room_guard.doc.transact().encode_diff_v1(&state_vector);
+        updates
+    };
+
+    let sync_response = SyncMessage::new(MessageKind::Update, buffer_name, updates);
+    let Some(framed) = protocol::encode_frame(&sync_response) else {
+        return;
+    };
+
+    let room = room.read().await;
+    room.pool.send_to(&framed, from).await;
+    info!("Sent sync response to client {}", from);
+}
+
+// a copy of the room's doc that updates are tried on first, since yrs can't take back one that
+// turns out to change buffers other than the one it was sent for. it catches up with the doc
+// before every try. updates that don't decode or plainly build on changes the server doesn't
+// have are turned away before it's touched, the copy is only thrown away when a try that got
+// that far goes wrong
+struct Trial {
+    doc: Doc,
+    // roots known to be texts. yrs only reports changes to roots whose type it knows
+    texts: HashSet<String>,
+}
+
+impl Trial {
+    fn new() -> Self {
+        Self {
+            doc: Doc::new(),
+            texts: HashSet::new(),
+        }
+    }
+
+    // applies the update to `doc` if it decodes and changes nothing but `buffer`
+    fn apply(&mut self, doc: &Doc, buffer: &str, data: &[u8]) -> Result<(), String> {
+        let touched = self.touched(doc, data)?;
+        if let Some(other) = touched.into_iter().find(|name| name != buffer) {
+            *self = Self::new();
+            return Err(format!("the update for {} also changes {}", buffer, other));
+        }
+        apply_update(doc, data)
+    }
+
+    // the buffers the update changes, for peers that don't say. the trial keeps the update,
+    // so it has to be reset if the doc doesn't take it too
+    fn roots(&mut self, doc: &Doc, data: &[u8]) -> Result<Vec<String>, String> {
+        self.touched(doc, data)
+    }
+
+    fn touched(&mut self, doc: &Doc, data: &[u8]) -> Result<Vec<String>, String> {
+        let update = Update::decode_v1(data).map_err(|e| e.to_string())?;
+        let known = doc.transact().state_vector();
+        let gap = update
+            .state_vector_lower()
+            .iter()
+            .any(|(client, clock)| *clock > known.get(client));
+        if gap {
+            return Err("the update builds on changes the server doesn't have".to_owned());
+        }
+
+        let result = self.try_update(doc, update);
+        if result.is_err() {
+            *self = Self::new();
+        }
+        result
+    }
+
+    fn try_update(&mut self, doc: &Doc, update: Update) -> Result<Vec<String>, String> {
+        let behind = self.doc.transact().state_vector();
+        let missing = doc.transact().encode_diff_v1(&behind);
+        let missing = Update::decode_v1(&missing).map_err(|e| e.to_string())?;
+        self.doc
+            .transact_mut()
+            .apply_update(missing)
+            .map_err(|e| e.to_string())?;
+
+        let names: Vec<String> = self
+            .doc
+            .transact()
+            .root_refs()
+            .map(|(name, _)| name.to_owned())
+            .filter(|name| !self.texts.contains(name))
+            .collect();
+        for name in names {
+            self.doc.get_or_insert_text(name.as_str());
+            self.texts.insert(name);
+        }
+
+        let mut txn = self.doc.transact_mut();
+        txn.apply_update(update).map_err(|e| e.to_string())?;
+        // held back changes would land wherever they belong once their dependencies show up
+        if txn.has_missing_updates() {
+            return Err("the update builds on changes the server doesn't have".to_owned());
+        }
+        txn.commit();
+        let changed = txn.changed_parent_types().iter().map(|root| root.id());
+        let created = txn.root_refs().map(|(name, _)| name.to_owned());
+        let mut touched: Vec<String> = changed
+            .filter_map(|id| match id {
+                BranchID::Root(name) => Some(name.to_string()),
+                BranchID::Nested(_) => None,
+            })
+            .chain(created.filter(|name| !self.texts.contains(name)))
+            .collect();
+        touched.sort();
+        touched.dedup();
+        Ok(touched)
+    }
+}
+
+// a peer whose updates keep getting refused could have the trial rebuilt over and over, so one
+// that runs into too many refusals in a short while is dropped
+async fn count_rejection(room: &Room, from: ClientId) {
+    let now = Instant::now();
+    let mut rejections = room.rejections.lock().await;
+    let (count, since) = rejections.entry(from).or_insert((0, now));
+    if now.duration_since(*since) > REJECTION_WINDOW {
+        (*count, *since) = (0, now);
+    }
+    *count += 1;
+    if *count > MAX_REJECTIONS {
+        warn!("Client {} sent too many refused updates, dropping it", from);
+        rejections.remove(&from);
+        room.pool.evict(from).await;
+    }
+}
+
+// keeps a room's document small enough to go out whole in one frame, which is what a peer that
+// has none of it yet is sent
+struct DocSize {
+    limit: usize,
+    // the encoded size of the doc, or more. it grows by each update that goes in, and the doc is
+    // only measured again once that reaches the limit
+    bound: AtomicUsize,
+}
+
+impl DocSize {
+    fn new(doc: &Doc, max_frame: usize) -> Self {
+        Self {
+            limit: max_frame.saturating_sub(FRAME_HEADROOM),
+            bound: AtomicUsize::new(encoded_size(doc)),
+        }
+    }
+
+    // whether an update of `len` bytes still fits, counting it in if so
+    fn admit(&self, doc: &Doc, len: usize) -> bool {
+        let bound = self.bound.load(Ordering::Relaxed) + len;
+        if bound <= self.limit {
+            self.bound.store(bound, Ordering::Relaxed);
+            return true;
+        }
+        let exact = encoded_size(doc);
+        let fits = exact + len <= self.limit;
+        let bound = if fits { exact + len } else { exact };
+        self.bound.store(bound, Ordering::Relaxed);
+        fits
+    }
+
+    // whether the doc still fits once it took an update already tried on `merged`, a copy that
+    // holds both. a resync repeats most of what the doc has, so its length says little
+    fn admit_merged(&self, merged: &Doc) -> bool {
+        let exact = encoded_size(merged);
+        let fits = exact <= self.limit;
+        if fits {
+            self.bound.store(exact, Ordering::Relaxed);
+        }
+        fits
+    }
+
+    // counts in an update the server made itself, which goes in either way
+    fn grow(&self, len: usize) {
+        self.bound.fetch_add(len, Ordering::Relaxed);
+    }
+}
+
+fn encoded_size(doc: &Doc) -> usize {
+    doc.transact()
+        .encode_state_as_update_v1(&StateVector::default())
+        .len()
+}
+
+async fn handle_update(room: &Arc<RwLock<Room>>, from: ClientId, msg: SyncMessage) {
+    let buffer_name = msg.buffer.clone();
+    let update_data = msg.payload;
+
+    {
+        let room = room.read().await;
+        if let Some((code, message)) = write_refusal(&room, from, &buffer_name).await {
+            refuse_update(&room, from, &buffer_name, code, message).await;
+            return;
+        }
+    }
+
+    if !update_data.is_empty() {
+        let room_guard = room.read().await;
+        if !room_guard.size.admit(&room_guard.doc, update_data.len()) {
+            let message = format!(
+                "the document would grow past {} bytes, the most that fits in one frame",
+                room_guard.size.limit
+            );
+            let code = ErrorCode::FrameTooLarge;
+            refuse_update(&room_guard, from, &buffer_name, code, message).await;
+            return;
+        }
+        let applied = {
+            let mut trial = room_guard.trial.lock().await;
+            trial.apply(&room_guard.doc, &buffer_name, &update_data)
+        };
+        if let Err(message) = applied {
+            let code = ErrorCode::InvalidUpdate;
+            refuse_update(&room_guard, from, &buffer_name, code, message).await;
+            return;
+        }
+
+        log_update(&room_guard, &update_data).await;
+        if let Some(workspace) = &room_guard.workspace {
+            workspace.lock().await.mark_dirty(&buffer_name);
+        }
+    }
+
+    let msg = SyncMessage::new(MessageKind::Update, buffer_name.clone(), update_data);
+    let Some(framed) = protocol::encode_frame(&msg) else {
+        return;
+    };
+
+    let room = room.read().await;
+    room.pool.broadcast(&framed, Some(from)).await;
+    trace!("Broadcasted update for buffer {}", buffer_name);
+}
+
+// a client back from being offline sends everything it has in one go, since the room may have
+// reopened empty meanwhile. that spans every buffer it edited, so each one the update touches
+// has to be open and writable for it
+async fn handle_resync(room: &Arc<RwLock<Room>>, from: ClientId, msg: SyncMessage) {
+    let update_data = msg.payload;
+    let room = room.read().await;
+    let mut trial = room.trial.lock().await;
+    let roots = match trial.roots(&room.doc, &update_data) {
+        Ok(roots) => roots,
+        Err(message) => {
+            drop(trial);
+            refuse_update(&room, from, "", ErrorCode::InvalidUpdate, message).await;
+            return;
+        }
+    };
+    let applied = match resync_refusal(&room, from, &roots, &trial.doc).await {
+        Some(refusal) => Err(refusal),
+        None => apply_update(&room.doc, &update_data)
+            .map_err(|message| (ErrorCode::InvalidUpdate, message)),
+    };
+    if let Err((code, message)) = applied {
+        *trial = Trial::new();
+        drop(trial);
+        refuse_update(&room, from, "", code, message).await;
+        return;
+    }
+    drop(trial);
+    // the server had all of it already
+    if roots.is_empty() {
+        return;
+    }
+
+    log_update(&room, &update_data).await;
+    if let Some(workspace) = &room.workspace {
+        let mut workspace = workspace.lock().await;
+        for root in &roots {
+            workspace.mark_dirty(root);
+        }
+    }
+    let msg = SyncMessage::new(MessageKind::Resync, String::new(), update_data);
+    if let Some(framed) = protocol::encode_frame(&msg) {
+        room.pool.broadcast(&framed, Some(from)).await;
+    }
+    debug!("Applied resync from client {}", from);
 }
 
-impl ClientPool {
-    fn new() -> Self {
-        Self {
-            clients: Arc::new(RwLock::new(Vec::new())),
+// why a resync touching `roots` is turned down, if it is. `merged` holds it on top of the doc
+async fn resync_refusal(
+    room: &Room,
+    from: ClientId,
+    roots: &[String],
+    merged: &Doc,
+) -> Option<(ErrorCode, String)> {
+    for root in roots {
+        if !protocol::is_valid_buffer(root) {
+            let message = format!("the resync changes {:?}, which isn't a buffer", root);
+            return Some((ErrorCode::InvalidBuffer, message));
+        }
+        // ones nobody opened have no file loaded into them yet, which would come on top
+        if !room.buffers.contains(root) {
+            let message = format!("the resync changes {}, which isn't open", root);
+            return Some((ErrorCode::UnknownBuffer, message));
         }
+        if let Some(refusal) = write_refusal(room, from, root).await {
+            return Some(refusal);
+        }
+    }
+    if !room.size.admit_merged(merged) {
+        let message = format!(
+            "the document would grow past {} bytes, the most that fits in one frame",
+            room.size.limit
+        );
+        return Some((ErrorCode::FrameTooLarge, message));
     }
+    None
+}
+
+fn apply_update(doc: &Doc, data: &[u8]) -> Result<(), String> {
+    let update = Update::decode_v1(data).map_err(|e| e.to_string())?;
+    doc.transact_mut()
+        .apply_update(update)
+        .map_err(|e| e.to_string())
+}
+
+// answers with the files under the requested directory of the shared root
+async fn handle_list_files(room: &Arc<RwLock<Room>>, from: ClientId, msg: SyncMessage) {
+    let Some(request) = msg.body::<ListFiles>() else {
+        reject(room, from, msg.kind).await;
+        return;
+    };
+    let directory = request.directory();
+    let room = room.read().await;
+    let Some(workspace) = &room.workspace else {
+        let message = "this room doesn't share a directory".to_owned();
+        reply_error(&room, from, "", ErrorCode::UnexpectedMessage, message).await;
+        return;
+    };
+
+    let files = workspace.lock().await.list(directory).await;
+    let Some(files) = files else {
+        let message = format!("{} is outside of the shared directory", directory);
+        reply_error(&room, from, "", ErrorCode::InvalidPath, message).await;
+        return;
+    };
+    debug!(
+        "Listing {} files under {:?} for client {}",
+        files.len(),
+        directory,
+        from
+    );
 
-    async fn add(&self, stream: OwnedWriteHalf) {
-        self.clients.write().await.push(stream);
+    let list = FileList::new(directory.clone(), files);
+    let msg = SyncMessage::with_body(MessageKind::FileList, String::new(), &list);
+    if let Some(framed) = msg.as_ref().and_then(protocol::encode_frame) {
+        room.pool.send_to(&framed, from).await;
     }
+}
 
-    // writes data to all clients, removing any that error out.
-    async fn broadcast(&self, data: &[u8], ignore: &SocketAddrV4) {
-        let mut clients = self.clients.write().await;
+// whether the buffer is open in the room, or has a file that could be opened
+async fn buffer_exists(room: &Room, buffer: &str) -> bool {
+    if room.buffers.contains(buffer) {
+        return true;
+    }
+    let Some(workspace) = &room.workspace else {
+        return false;
+    };
+    let Some(path) = workspace.lock().await.path_for(buffer).await else {
+        return false;
+    };
+    tokio::fs::try_exists(path).await.unwrap_or(false)
+}
 
-        // retain only clients that successfully accept the write
-        let mut i = 0;
-        while i < clients.len() {
-            let client = &clients[i];
-            let addr_string = client
-                .peer_addr()
-                .map(|s| format!("{}", s))
-                .unwrap_or("<bad peer_addr>".to_owned());
-
-            trace!("Handling client {}", addr_string);
-            if let Ok(SocketAddr::V4(addr)) = client.peer_addr() {
-                if &addr == ignore {
-                    trace!("Skipping {}", addr);
-                    i += 1;
-                    continue;
-                }
-            }
+async fn reply_file_error(room: &Room, to: ClientId, buffer: &str, e: io::Error) {
+    error!("Failed to change the file of {}: {}", buffer, e);
+    let code = match e.kind() {
+        io::ErrorKind::AlreadyExists => ErrorCode::BufferExists,
+        io::ErrorKind::InvalidInput => ErrorCode::InvalidPath,
+        _ => ErrorCode::FileError,
+    };
+    reply_error(room, to, "", code, format!("{}: {}", buffer, e)).await;
+}
 
-            match clients[i].write_all(data).await {
-                Ok(_) => {
-                    trace!("Broadcasted to client with peer_addr {}", addr_string,);
-                    i += 1;
-                }
-                Err(_) => {
-                    // client disconnected or error, remove them
-                    info!("Client at {} disconnected", addr_string);
-                    clients.swap_remove(i);
-                }
-            }
+// moves the buffer's text under a new name, or only empties it without one
+fn move_text(doc: &Doc, from: &str, to: Option<&str>) {
+    let source = doc.get_or_insert_text(from);
+    let target = to.map(|to| doc.get_or_insert_text(to));
+    let mut txn = doc.transact_mut();
+    let content = source.get_string(&txn);
+    let len = source.len(&txn);
+    if len > 0 {
+        source.remove_range(&mut txn, 0, len);
+    }
+    if let Some(target) = target {
+        // whatever a deleted buffer of the same name left behind
+        let stale = target.len(&txn);
+        if stale > 0 {
+            target.remove_range(&mut txn, 0, stale);
         }
+        target.insert(&mut txn, 0, &content);
+    }
+}
 
-        for client in &mut *clients {
-            client.flush().await.unwrap();
+// peers in a buffer that went away have to move their cursor before it means anything again
+fn forget_cursors(room: &mut Room, buffer: &str) {
+    room.awareness.retain(|_, (name, _)| name != buffer);
+}
+
+// lifecycle changes go to every peer, the one asking included, which takes it as the go-ahead
+async fn announce(room: &Room, msg: SyncMessage) {
+    if let Some(framed) = protocol::encode_frame(&msg) {
+        room.pool.broadcast(&framed, None).await;
+    }
+}
+
+async fn handle_create_buffer(room: &Arc<RwLock<Room>>, from: ClientId, msg: SyncMessage) {
+    let buffer = msg.buffer;
+    let mut room = room.write().await;
+    if !check_buffer(&room, from, &buffer).await || !check_write(&room, from, &buffer).await {
+        return;
+    }
+    if buffer_exists(&room, &buffer).await {
+        let message = format!("{} already exists", buffer);
+        reply_error(&room, from, "", ErrorCode::BufferExists, message).await;
+        return;
+    }
+    if let Some(workspace) = &room.workspace {
+        if let Err(e) = workspace.lock().await.create(&buffer).await {
+            reply_file_error(&room, from, &buffer, e).await;
+            return;
         }
     }
 
-    async fn send_to(&self, data: &[u8], addr: &SocketAddrV4) {
-        let mut clients = self.clients.write().await;
+    info!("Client {} created {}", from, buffer);
+    room.buffers.insert(buffer.clone());
+    announce(
+        &room,
+        SyncMessage::new(MessageKind::CreateBuffer, buffer, Vec::new()),
+    )
+    .await;
+}
 
-        for client in &mut *clients {
-            if let Ok(SocketAddr::V4(client_addr)) = client.peer_addr() {
-                if &client_addr == addr {
-                    client.write_all(data).await.unwrap();
-                    client.flush().await.unwrap();
-                    break;
-                }
+// moves the text over in the doc, so the rename reaches peers as one update along with the new
+// name. the old root stays behind empty
+async fn handle_rename_buffer(room: &Arc<RwLock<Room>>, from: ClientId, msg: SyncMessage) {
+    let Some(request) = msg.body::<RenameBuffer>() else {
+        reject(room, from, msg.kind).await;
+        return;
+    };
+    let old = msg.buffer;
+    let new = request.to().clone();
+    if !check_buffer_name(room, from, &new).await {
+        return;
+    }
+    let mut room = room.write().await;
+    if !check_buffer(&room, from, &old).await || !check_buffer(&room, from, &new).await {
+        return;
+    }
+    if !check_write(&room, from, &old).await || !check_write(&room, from, &new).await {
+        return;
+    }
+    if !buffer_exists(&room, &old).await {
+        let message = format!("{} doesn't exist", old);
+        reply_error(&room, from, "", ErrorCode::UnknownBuffer, message).await;
+        return;
+    }
+    if old == new || buffer_exists(&room, &new).await {
+        let message = format!("{} already exists", new);
+        reply_error(&room, from, "", ErrorCode::BufferExists, message).await;
+        return;
+    }
+
+    let before = room.doc.transact().state_vector();
+    if let Some(workspace) = &room.workspace {
+        let mut workspace = workspace.lock().await;
+        // a file nobody opened yet has to be in the doc for its text to move along
+        let loaded = workspace.load(&room.doc, &old).await;
+        if let Err(e) = workspace.rename(&old, &new).await {
+            drop(workspace);
+            if let Some(update) = loaded {
+                publish(&room, old.clone(), update, None).await;
             }
+            reply_file_error(&room, from, &old, e).await;
+            return;
         }
     }
+    move_text(&room.doc, &old, Some(&new));
+    let update = room.doc.transact().encode_diff_v1(&before);
+    log_update(&room, &update).await;
+
+    info!("Client {} renamed {} to {}", from, old, new);
+    room.buffers.remove(&old);
+    room.buffers.insert(new.clone());
+    forget_cursors(&mut room, &old);
+    let body = RenameBuffer::new(new).with_update(update);
+    if let Some(msg) = SyncMessage::with_body(MessageKind::RenameBuffer, old, &body) {
+        announce(&room, msg).await;
+    }
 }
 
-struct ServerState {
-    doc: Doc,
-    pool: ClientPool,
+// empties the buffer in the doc and passes that on as the payload
+async fn handle_delete_buffer(room: &Arc<RwLock<Room>>, from: ClientId, msg: SyncMessage) {
+    let buffer = msg.buffer;
+    let mut room = room.write().await;
+    if !check_buffer(&room, from, &buffer).await || !check_write(&room, from, &buffer).await {
+        return;
+    }
+    if !buffer_exists(&room, &buffer).await {
+        let message = format!("{} doesn't exist", buffer);
+        reply_error(&room, from, "", ErrorCode::UnknownBuffer, message).await;
+        return;
+    }
+    if let Some(workspace) = &room.workspace {
+        if let Err(e) = workspace.lock().await.remove(&buffer).await {
+            reply_file_error(&room, from, &buffer, e).await;
+            return;
+        }
+    }
+
+    let before = room.doc.transact().state_vector();
+    move_text(&room.doc, &buffer, None);
+    let update = room.doc.transact().encode_diff_v1(&before);
+    log_update(&room, &update).await;
+
+    info!("Client {} deleted {}", from, buffer);
+    room.buffers.remove(&buffer);
+    forget_cursors(&mut room, &buffer);
+    announce(
+        &room,
+        SyncMessage::new(MessageKind::DeleteBuffer, buffer, update),
+    )
+    .await;
 }
 
-async fn run_listener(
-    addr: SocketAddrV4,
-    tx: Sender<IncomingMessage>,
-    state: Arc<RwLock<ServerState>>,
-) {
-    let listener = TcpListener::bind(addr)
-        .await
-        .expect("Failed to bind listener");
+// merges a change made on disk into the doc and passes it on to every peer
+async fn handle_file_change(room: &Arc<RwLock<Room>>, path: PathBuf) {
+    let room = room.read().await;
+    let Some(workspace) = &room.workspace else {
+        return;
+    };
+    let (buffer, update) = {
+        let mut workspace = workspace.lock().await;
+        let Some(buffer) = workspace.buffer_for(&path) else {
+            return;
+        };
+        let Some(update) = workspace.reload(&room.doc, &buffer).await else {
+            return;
+        };
+        (buffer, update)
+    };
+    publish(&room, buffer, update, None).await;
+}
 
-    loop {
-        let tx_ref = tx.clone();
-        match listener.accept().await {
-            Ok((stream, SocketAddr::V4(address))) => {
-                info!("{} connected to server", address);
-                let (read_half, write_half) = stream.into_split();
-
-                // add stream to the pool for broadcasting
-                {
-                    let state = state.read().await;
-                    state.pool.add(write_half).await;
-                }
+// remembers the peer's cursor and relays it to everyone else
+async fn handle_awareness(room: &Arc<RwLock<Room>>, from: ClientId, msg: SyncMessage) {
+    let Some(update) = msg.body::<AwarenessUpdate>() else {
+        reject(room, from, msg.kind).await;
+        return;
+    };
+    // peers only speak for themselves
+    if update.client_id() != from {
+        warn!(
+            "Client {} sent awareness for client {}",
+            from,
+            update.client_id()
+        );
+        return;
+    }
+    let Some(framed) = protocol::encode_frame(&msg) else {
+        return;
+    };
+
+    let mut room = room.write().await;
+    if update.state().is_some() {
+        room.awareness.insert(from, (msg.buffer.clone(), update));
+    } else {
+        room.awareness.remove(&from);
+    }
+    room.pool.broadcast(&framed, Some(from)).await;
+}
 
-                // when the stream sends messages, add "from" address so when it gets broadcasted
-                // it doesn't get sent back to the same guy
-                let mut reader = FrameReader::new(read_half);
-                tokio::spawn(async move {
-                    while let Some(msg) = reader.read_one().await {
-                        let msg = IncomingMessage {
-                            from: address,
-                            content: msg,
-                        };
-                        tx_ref.send(msg).await.unwrap();
-                    }
-                });
-            }
-            Ok((_, SocketAddr::V6(addr))) => {
-                error!("i don't wanna think about ipv6 yet {}", addr)
+async fn send_yjs(room: &Room, to: ClientId, data: Vec<u8>) {
+    let msg = SyncMessage::new(MessageKind::Yjs, String::new(), data);
+    if let Some(framed) = protocol::encode_frame(&msg) {
+        room.pool.send_to(&framed, to).await;
+    }
+}
+
+// what every Yjs peer last said about itself, in one update
+fn yjs_awareness_of(room: &Room) -> Option<YjsAwareness> {
+    let clients: HashMap<_, _> = room
+        .yjs_awareness
+        .values()
+        .flat_map(|update| update.clients.clone())
+        .collect();
+    if clients.is_empty() {
+        return None;
+    }
+    Some(YjsAwareness { clients })
+}
+
+async fn handle_yjs(room: &Arc<RwLock<Room>>, from: ClientId, content: &[u8]) {
+    let messages = match websocket::read_messages(content) {
+        Ok(messages) => messages,
+        Err(e) => {
+            warn!(
+                "Yjs client {} sent a message that doesn't decode: {}",
+                from, e
+            );
+            let message = "message doesn't decode".to_owned();
+            let room = room.read().await;
+            reply_error(&room, from, "", ErrorCode::InvalidMessage, message).await;
+            return;
+        }
+    };
+    for message in messages {
+        match message {
+            YjsMessage::Sync(YjsSyncMessage::SyncStep1(state_vector)) => {
+                let room = room.read().await;
+                let update = room.doc.transact().encode_diff_v1(&state_vector);
+                send_yjs(&room, from, websocket::sync_step2(update)).await;
             }
-            Err(e) => {
-                error!("Listener error: {:?}", e);
-                break;
+            YjsMessage::Sync(YjsSyncMessage::SyncStep2(update))
+            | YjsMessage::Sync(YjsSyncMessage::Update(update)) => {
+                handle_yjs_update(room, from, update).await;
+            }
+            YjsMessage::Awareness(update) => handle_yjs_awareness(room, from, update).await,
+            YjsMessage::AwarenessQuery => {
+                let room = room.read().await;
+                if let Some(everyone) = yjs_awareness_of(&room) {
+                    send_yjs(&room, from, websocket::awareness(everyone)).await;
+                }
             }
+            // only servers send auth messages, and custom ones mean nothing here
+            YjsMessage::Auth(_) | YjsMessage::Custom(..) => {}
         }
     }
 }
 
-async fn handle_initial_sync(
-    state: &Arc<RwLock<ServerState>>,
-    from: &SocketAddrV4,
-    msg: SyncMessage,
-) {
-    let buffer_name = msg.buffer.clone();
+// Yjs peers don't say which buffer an update is for, so that's worked out on the trial doc. past
+// that it goes through like any other update, as long as it sticks to one buffer
+async fn handle_yjs_update(room: &Arc<RwLock<Room>>, from: ClientId, update: Vec<u8>) {
+    let roots = {
+        let room = room.read().await;
+        let mut trial = room.trial.lock().await;
+        trial.roots(&room.doc, &update)
+    };
+    let mut roots = match roots {
+        Ok(roots) => roots,
+        Err(message) => {
+            warn!("Rejected update from Yjs client {}: {}", from, message);
+            let room = room.read().await;
+            reply_error(&room, from, "", ErrorCode::InvalidUpdate, message).await;
+            count_rejection(&room, from).await;
+            return;
+        }
+    };
+    let Some(buffer) = roots.pop() else {
+        return;
+    };
 
-    let updates = {
-        let state_guard = state.read().await;
-        let _text = state_guard.doc.get_or_insert_text(buffer_name.as_str());
-
-        if msg.payload.is_empty() {
-            let sv = StateVector::default();
-            state_guard.doc.transact().encode_diff_v1(&sv)
-        } else if let Ok(sv) = StateVector::decode_v1(&msg.payload) {
-            state_guard.doc.transact().encode_diff_v1(&sv)
+    let accepted = if !roots.is_empty() {
+        warn!("Yjs client {} changed several buffers at once", from);
+        let message = format!(
+            "an update may only change one buffer, this one changes {}",
+            buffer
+        );
+        let room = room.read().await;
+        reply_error(&room, from, "", ErrorCode::InvalidUpdate, message).await;
+        false
+    } else {
+        // viewers don't get to open buffers, or load files, by sending updates to them
+        check_buffer_name(room, from, &buffer).await
+            && check_write(&*room.read().await, from, &buffer).await
+            && open_buffer(room, from, &buffer, None).await
+    };
+    if !accepted {
+        let room = room.read().await;
+        *room.trial.lock().await = Trial::new();
+        count_rejection(&room, from).await;
+        return;
+    }
+    handle_update(
+        room,
+        from,
+        SyncMessage::new(MessageKind::Update, buffer, update),
+    )
+    .await;
+}
+
+// relayed to the other Yjs peers only, neo-live peers keep to their own cursors. the editor picks
+// its Yjs client ids, so a peer owns whichever ones it spoke for first
+async fn handle_yjs_awareness(room: &Arc<RwLock<Room>>, from: ClientId, update: YjsAwareness) {
+    let mut room = room.write().await;
+    let update = claim_yjs_clients(&mut room.yjs_awareness, from, update);
+    if update.clients.is_empty() {
+        return;
+    }
+
+    let msg = SyncMessage::new(
+        MessageKind::Yjs,
+        String::new(),
+        websocket::awareness(update),
+    );
+    if let Some(framed) = protocol::encode_frame(&msg) {
+        room.pool.broadcast_yjs(&framed, Some(from)).await;
+    }
+}
+
+// records what `from` says about the Yjs clients it may speak for: ones no other peer owns, up
+// to MAX_YJS_CLIENTS of them. returns the part of the update that holds
+fn claim_yjs_clients(
+    known: &mut HashMap<ClientId, YjsAwareness>,
+    from: ClientId,
+    mut update: YjsAwareness,
+) -> YjsAwareness {
+    let mut claimed = known.get(&from).map_or(0, |mine| mine.clients.len());
+    update.clients.retain(|client, entry| {
+        let owner = known
+            .iter()
+            .find(|(_, awareness)| awareness.clients.contains_key(client));
+        match owner {
+            Some((&owner, _)) if owner != from => {
+                warn!(
+                    "Client {} spoke for Yjs client {}, which is client {}'s",
+                    from, client, owner
+                );
+                false
+            }
+            Some(_) => true,
+            // gone before we ever heard of it
+            None if &*entry.json == "null" => false,
+            None if claimed == MAX_YJS_CLIENTS => {
+                warn!("Client {} spoke for too many Yjs clients", from);
+                false
+            }
+            None => {
+                claimed += 1;
+                true
+            }
+        }
+    });
+
+    let mine = known.entry(from).or_insert_with(|| YjsAwareness {
+        clients: HashMap::new(),
+    });
+    for (client, entry) in &update.clients {
+        if &*entry.json == "null" {
+            mine.clients.remove(client);
         } else {
-            Vec::new()
+            mine.clients.insert(*client, entry.clone());
         }
+    }
+    if mine.clients.is_empty() {
+        known.remove(&from);
+    }
+    update
+}
+
+// the Yjs clients a peer spoke for are gone with it, which its editor never got to say
+async fn forget_yjs_peer(room: &mut Room, from: ClientId) {
+    let Some(mut update) = room.yjs_awareness.remove(&from) else {
+        return;
     };
+    for entry in update.clients.values_mut() {
+        entry.clock += 1;
+        entry.json = "null".into();
+    }
+    let msg = SyncMessage::new(
+        MessageKind::Yjs,
+        String::new(),
+        websocket::awareness(update),
+    );
+    if let Some(framed) = protocol::encode_frame(&msg) {
+        room.pool.broadcast_yjs(&framed, Some(from)).await;
+    }
+}
 
-    let sync_response = SyncMessage::new(MessageKind::Update, buffer_name, updates);
-    let framed = protocol::encode_frame(&sync_response).unwrap();
+async fn handle_disconnect(room: &Arc<RwLock<Room>>, from: ClientId, connection: u64) {
+    info!("Client {} left the session", from);
+    let mut room = room.write().await;
+    // the client may already be back on a new connection, which keeps its cursor
+    if !room.pool.remove(from, connection).await && room.pool.contains(from).await {
+        return;
+    }
+    forget_yjs_peer(&mut room, from).await;
+    room.rejections.lock().await.remove(&from);
+    let Some((buffer, update)) = room.awareness.remove(&from) else {
+        return;
+    };
 
-    let state = state.read().await;
-    state.pool.send_to(&framed, from).await;
-    info!("Sent sync response to {}", from);
+    let gone = AwarenessUpdate::new(update.client_id(), None);
+    let Some(msg) = SyncMessage::with_body(MessageKind::Awareness, buffer, &gone) else {
+        return;
+    };
+    if let Some(framed) = protocol::encode_frame(&msg) {
+        room.pool.broadcast(&framed, Some(from)).await;
+    }
 }
 
-async fn handle_update(state: &Arc<RwLock<ServerState>>, from: &SocketAddrV4, msg: SyncMessage) {
-    let buffer_name = msg.buffer.clone();
-    let update_data = msg.payload;
+// writes a snapshot if anything changed since the last one
+async fn snapshot(room: &Arc<RwLock<Room>>) {
+    let room = room.read().await;
+    let Some(store) = &room.store else {
+        return;
+    };
 
-    if !update_data.is_empty() {
-        let update = Update::decode_v1(&update_data).unwrap();
-        let state_guard = state.read().await;
-        let mut txn = state_guard.doc.transact_mut();
-        let _ = txn.apply_update(update);
+    // hold the store lock while encoding so no update lands in the log after the snapshot
+    // was taken but before the log is emptied
+    let mut store = store.lock().await;
+    if !store.is_dirty() {
+        return;
+    }
+    if let Err(e) = store.snapshot(&room.doc).await {
+        error!("Failed to write snapshot of room {}: {}", room.name, e);
     }
+}
 
-    let framed = protocol::encode_frame(&SyncMessage::new(
-        MessageKind::Update,
-        buffer_name.clone(),
-        update_data,
-    ))
-    .unwrap();
+// writes edits to shared files back to disk
+async fn write_back(room: &Arc<RwLock<Room>>) {
+    let room = room.read().await;
+    if let Some(workspace) = &room.workspace {
+        workspace.lock().await.flush(&room.doc).await;
+    }
+}
 
-    let state = state.read().await;
-    state.pool.broadcast(&framed, from).await;
-    trace!("Broadcasted update for buffer {}", buffer_name);
+async fn write_back_all(state: &Arc<RwLock<ServerState>>) {
+    let rooms: Vec<_> = state.read().await.rooms.values().cloned().collect();
+    for room in rooms {
+        write_back(&room).await;
+    }
+}
+
+// snapshots every open room. rooms that closed were snapshotted on their way out
+async fn snapshot_all(state: &Arc<RwLock<ServerState>>) {
+    let rooms: Vec<_> = state.read().await.rooms.values().cloned().collect();
+    for room in rooms {
+        snapshot(&room).await;
+    }
+}
+
+// tells every client why the server is going away and drops it, then gives the notice and
+// whatever was queued before it a moment to go out
+async fn notify_shutdown(state: &Arc<RwLock<ServerState>>, reason: String) {
+    let notice = ServerShutdown::new(reason);
+    let msg = SyncMessage::with_body(MessageKind::ServerShutdown, String::new(), &notice);
+    let framed = msg.as_ref().and_then(protocol::encode_frame);
+
+    let rooms: Vec<_> = state.read().await.rooms.values().cloned().collect();
+    let mut writers = Vec::new();
+    for room in rooms {
+        let room = room.read().await;
+        if let Some(framed) = &framed {
+            room.pool.broadcast(framed, None).await;
+        }
+        writers.extend(room.pool.close().await);
+    }
+
+    let flushed = tokio::time::timeout(SHUTDOWN_GRACE, async {
+        for writer in writers {
+            let _ = writer.await;
+        }
+    })
+    .await;
+    if flushed.is_err() {
+        warn!("Not every client took the shutdown notice in time");
+    }
+}
+
+/// Resolves once the process is asked to stop, with the name of the signal that asked.
+pub async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => return "SIGINT",
+                _ = terminate.recv() => return "SIGTERM",
+            },
+            Err(e) => error!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
+}
+
+async fn open_store(doc: &Doc, dir: &Path) -> Option<Store> {
+    let store = match Store::open(dir).await {
+        Ok(store) => store,
+        Err(e) => {
+            error!("Failed to open state directory {}: {}", dir.display(), e);
+            return None;
+        }
+    };
+    if let Err(e) = store.load(doc).await {
+        error!("Failed to restore state from {}: {}", dir.display(), e);
+    }
+    Some(store)
 }
 
 // server acts as a relay to send buffer contents
 // later will relay CRDT operations instead
-// later check whether the messages are valid so it doesn't relay junk
+// messages that don't decode, name odd buffers or carry updates reaching past their buffer are
+// answered with an error instead of being relayed
 //
 // uses TcpListener to add streams to ClientPool
 // both reads and writes to streams
-pub async fn serve(addr: SocketAddrV4) {
-    let doc = Doc::new();
-    let pool = ClientPool::new();
+pub async fn serve(addr: SocketAddr, options: ServerOptions) {
+    let listener = TcpListener::bind(addr)
+        .await
+        .expect("Failed to bind listener");
+    serve_on(Listener::Tcp(listener), options).await
+}
 
-    let state = Arc::new(RwLock::new(ServerState { doc, pool }));
-    let state_ref = state.clone();
+/// Like `serve`, on any kind of listener.
+pub async fn serve_on(listener: Listener, options: ServerOptions) {
+    let shutdown = async { format!("received {}", shutdown_signal().await) };
+    serve_listener(listener, options, shutdown).await
+}
 
+/// Serves on a listener that is already bound until `shutdown` resolves with the reason given
+/// to the clients.
+pub async fn serve_listener(
+    listener: Listener,
+    options: ServerOptions,
+    shutdown: impl Future<Output = String>,
+) {
     // listen for connections
     // also set up input reading from clients here
     let (tx, mut rx) = mpsc::channel(CHANNEL_SIZE);
 
-    tokio::task::spawn(async move {
+    let persistent = options.state_dir.is_some();
+    let sharing = options.root.is_some();
+    let state = Arc::new(RwLock::new(ServerState {
+        rooms: HashMap::new(),
+        token: options.token,
+        state_dir: options.state_dir,
+        root: options.root,
+        share_ignored: options.share_ignored,
+        read_only: options.read_only,
+        max_frame: options.max_frame,
+        connection_budget: options.connection_budget,
+        yjs: options.yjs,
+        events: tx.clone(),
+    }));
+    let state_ref = state.clone();
+
+    if persistent {
+        let state_ref = state.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(options.snapshot_interval);
+            loop {
+                interval.tick().await;
+                snapshot_all(&state_ref).await;
+            }
+        });
+    }
+
+    if sharing {
+        let state_ref = state.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(WRITE_BACK_INTERVAL);
+            loop {
+                interval.tick().await;
+                write_back_all(&state_ref).await;
+            }
+        });
+    }
+
+    let mut announcer = None;
+    let mut websocket = None;
+    let bound = match &listener {
+        Listener::Tcp(listener) => listener.local_addr().ok(),
+        _ => None,
+    };
+    if let Some(addr) = bound {
         debug!("Starting listener with address {}", addr);
-        run_listener(addr, tx, state_ref).await;
+        if let Some(room) = options.announce {
+            announcer = Some(tokio::task::spawn(discovery::announce(addr, room)));
+        }
+        if let Some(port) = options.websocket_port {
+            let addr = SocketAddr::new(addr.ip(), port);
+            match TcpListener::bind(addr).await {
+                Ok(listener) => {
+                    info!("Taking WebSocket connections on {}", addr);
+                    let run = run_websocket_listener(listener, tx.clone(), state.clone());
+                    websocket = Some(tokio::task::spawn(run));
+                }
+                Err(e) => error!("Failed to bind the WebSocket listener to {}: {}", addr, e),
+            }
+        }
+    }
+    #[cfg(unix)]
+    let socket = match &listener {
+        Listener::Unix(_, path) => Some(path.clone()),
+        _ => None,
+    };
+    let stdio = matches!(listener, Listener::Stdio);
+    let (left, peer_left) = oneshot::channel::<()>();
+    let listener = tokio::task::spawn(async move {
+        run_listener(listener, tx, state_ref).await;
+        let _ = left.send(());
     });
+    // a stdio peer is the only one there is, so the session is over once it leaves
+    let shutdown = async move {
+        tokio::select! {
+            reason = shutdown => reason,
+            Ok(()) = peer_left, if stdio => "the peer on stdio left".to_owned(),
+        }
+    };
+
+    tokio::pin!(shutdown);
+    loop {
+        let incoming = tokio::select! {
+            incoming = rx.recv() => incoming,
+            reason = &mut shutdown => {
+                info!("Shutting down, {}", reason);
+                listener.abort();
+                for task in announcer.iter().chain(websocket.iter()) {
+                    task.abort();
+                }
+                write_back_all(&state).await;
+                snapshot_all(&state).await;
+                notify_shutdown(&state, reason).await;
+                #[cfg(unix)]
+                if let Some(path) = &socket {
+                    let _ = tokio::fs::remove_file(path).await;
+                }
+                return;
+            }
+        };
+        let Some(incoming) = incoming else {
+            break;
+        };
+
+        let incoming = match incoming {
+            Incoming::Message(incoming) => incoming,
+            Incoming::Yjs(incoming) => {
+                let handle = state.read().await.room(&incoming.room);
+                if let Some(handle) = handle {
+                    handle_yjs(&handle, incoming.from, &incoming.content).await;
+                }
+                continue;
+            }
+            Incoming::Disconnected {
+                room,
+                from,
+                connection,
+            } => {
+                let handle = state.read().await.room(&room);
+                if let Some(handle) = handle {
+                    handle_disconnect(&handle, from, connection).await;
+                }
+                leave(&state, &room).await;
+                continue;
+            }
+            Incoming::FileChanged { room, path } => {
+                let handle = state.read().await.room(&room);
+                if let Some(handle) = handle {
+                    handle_file_change(&handle, path).await;
+                }
+                continue;
+            }
+        };
+
+        // a connection keeps its room open until its disconnect is handled, so this shouldn't
+        // miss
+        let Some(room) = state.read().await.room(&incoming.room) else {
+            warn!("Dropping message for closed room {}", incoming.room);
+            continue;
+        };
 
-    while let Some(incoming) = rx.recv().await {
         let Ok(msg) = rmp_serde::from_slice::<SyncMessage>(&incoming.content) else {
-            error!("Failed to deserialize message");
+            warn!(
+                "Failed to deserialize message from client {}",
+                incoming.from
+            );
+            let message = "message doesn't decode".to_owned();
+            let room = room.read().await;
+            reply_error(&room, incoming.from, "", ErrorCode::InvalidMessage, message).await;
             continue;
         };
+        // file listings and resyncs are the only requests not about one buffer
+        if msg.kind != MessageKind::ListFiles
+            && msg.kind != MessageKind::Resync
+            && !check_buffer_name(&room, incoming.from, &msg.buffer).await
+        {
+            continue;
+        }
 
         if msg.kind == MessageKind::InitialSync {
             debug!("Received initial sync request for buffer: {}", msg.buffer);
-            handle_initial_sync(&state, &incoming.from, msg).await;
+            handle_initial_sync(&room, incoming.from, msg).await;
         } else if msg.kind == MessageKind::Update {
             debug!("Received update for buffer: {}", msg.buffer);
-            handle_update(&state, &incoming.from, msg).await;
+            handle_update(&room, incoming.from, msg).await;
+        } else if msg.kind == MessageKind::Resync {
+            debug!("Received resync");
+            handle_resync(&room, incoming.from, msg).await;
+        } else if msg.kind == MessageKind::Awareness {
+            trace!("Received awareness for buffer: {}", msg.buffer);
+            handle_awareness(&room, incoming.from, msg).await;
+        } else if msg.kind == MessageKind::ListFiles {
+            debug!("Received file listing request");
+            handle_list_files(&room, incoming.from, msg).await;
+        } else if msg.kind == MessageKind::CreateBuffer {
+            debug!("Received create request for buffer: {}", msg.buffer);
+            handle_create_buffer(&room, incoming.from, msg).await;
+        } else if msg.kind == MessageKind::RenameBuffer {
+            debug!("Received rename request for buffer: {}", msg.buffer);
+            handle_rename_buffer(&room, incoming.from, msg).await;
+        } else if msg.kind == MessageKind::DeleteBuffer {
+            debug!("Received delete request for buffer: {}", msg.buffer);
+            handle_delete_buffer(&room, incoming.from, msg).await;
         } else {
-            error!("Unknown message kind: {:?}", msg.kind);
+            warn!(
+                "Unexpected {:?} message from client {}",
+                msg.kind, incoming.from
+            );
+            let message = format!("{:?} messages aren't expected here", msg.kind);
+            let room = room.read().await;
+            reply_error(
+                &room,
+                incoming.from,
+                "",
+                ErrorCode::UnexpectedMessage,
+                message,
+            )
+            .await;
         }
     }
     error!("done with err {:?}", rx.recv().await);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yrs::sync::awareness::AwarenessUpdateEntry;
+
+    // a peer holding everything in `doc`, editing `buffer`
+    fn edit(doc: &Doc, buffer: &str, text: &str) -> Vec<u8> {
+        let peer = Doc::new();
+        let state = doc.transact().encode_diff_v1(&StateVector::default());
+        peer.transact_mut()
+            .apply_update(Update::decode_v1(&state).unwrap())
+            .unwrap();
+        let before = peer.transact().state_vector();
+        let root = peer.get_or_insert_text(buffer);
+        root.push(&mut peer.transact_mut(), text);
+        let update = peer.transact().encode_diff_v1(&before);
+        update
+    }
+
+    fn text(doc: &Doc, buffer: &str) -> String {
+        let root = doc.get_or_insert_text(buffer);
+        let txn = doc.transact();
+        root.get_string(&txn)
+    }
+
+    #[test]
+    fn updates_may_only_change_their_own_buffer() {
+        let doc = Doc::new();
+        let a = doc.get_or_insert_text("a.txt");
+        let b = doc.get_or_insert_text("b.txt");
+        a.push(&mut doc.transact_mut(), "alpha");
+        b.push(&mut doc.transact_mut(), "beta");
+        let mut trial = Trial::new();
+
+        let sneaky = edit(&doc, "b.txt", "!");
+        assert!(trial.apply(&doc, "a.txt", &sneaky).is_err());
+        assert_eq!(text(&doc, "b.txt"), "beta");
+
+        let fine = edit(&doc, "a.txt", "!");
+        assert!(trial.apply(&doc, "a.txt", &fine).is_ok());
+        assert_eq!(text(&doc, "a.txt"), "alpha!");
+
+        let fresh = edit(&doc, "c.txt", "new");
+        assert!(trial.apply(&doc, "c.txt", &fresh).is_ok());
+        assert_eq!(text(&doc, "c.txt"), "new");
+
+        // the trial copy keeps up with changes the server makes itself
+        b.push(&mut doc.transact_mut(), "?");
+        let later = edit(&doc, "b.txt", "!");
+        assert!(trial.apply(&doc, "b.txt", &later).is_ok());
+        assert_eq!(text(&doc, "b.txt"), "beta?!");
+    }
+
+    #[test]
+    fn junk_updates_are_refused() {
+        let doc = Doc::new();
+        assert!(Trial::new()
+            .apply(&doc, "a.txt", &[0xff, 0x13, 0x37])
+            .is_err());
+
+        // depends on an edit the server never got
+        let peer = Doc::new();
+        let root = peer.get_or_insert_text("a.txt");
+        root.push(&mut peer.transact_mut(), "one");
+        let after_one = peer.transact().state_vector();
+        root.push(&mut peer.transact_mut(), "two");
+        let second = peer.transact().encode_diff_v1(&after_one);
+        assert!(Trial::new().apply(&doc, "a.txt", &second).is_err());
+        assert_eq!(text(&doc, "a.txt"), "");
+
+        // neither is worth throwing away the trial's copy for
+        let mut trial = Trial::new();
+        let fine = edit(&doc, "a.txt", "x");
+        assert!(trial.apply(&doc, "a.txt", &fine).is_ok());
+        assert!(trial.apply(&doc, "a.txt", &[0xff, 0x13, 0x37]).is_err());
+        assert!(trial.apply(&doc, "a.txt", &second).is_err());
+        assert_eq!(text(&trial.doc, "a.txt"), "x");
+    }
+
+    #[test]
+    fn documents_stay_within_one_frame() {
+        let doc = Doc::new();
+        let size = DocSize::new(&doc, FRAME_HEADROOM + 100);
+        assert_eq!(size.limit, 100);
+
+        let update = edit(&doc, "a.txt", &"a".repeat(60));
+        assert!(size.admit(&doc, update.len()));
+        doc.transact_mut()
+            .apply_update(Update::decode_v1(&update).unwrap())
+            .unwrap();
+        let update = edit(&doc, "a.txt", &"b".repeat(60));
+        assert!(!size.admit(&doc, update.len()));
+        // measured again, it still has room for something small
+        assert!(size.admit(&doc, 10));
+    }
+
+    #[test]
+    fn yjs_clients_belong_to_whoever_spoke_for_them_first() {
+        let entry = |json: &str| AwarenessUpdateEntry {
+            clock: 1,
+            json: json.into(),
+        };
+        let update = |clients: Vec<(u64, &str)>| YjsAwareness {
+            clients: clients
+                .into_iter()
+                .map(|(id, json)| (id, entry(json)))
+                .collect(),
+        };
+        let mut known = HashMap::new();
+
+        let kept = claim_yjs_clients(&mut known, 1, update(vec![(10, "{}")]));
+        assert_eq!(kept.clients.len(), 1);
+        let kept = claim_yjs_clients(&mut known, 2, update(vec![(10, "{}"), (20, "{}")]));
+        assert_eq!(kept.clients.keys().collect::<Vec<_>>(), vec![&20]);
+        let kept = claim_yjs_clients(&mut known, 2, update(vec![(10, "null")]));
+        assert!(kept.clients.is_empty());
+        assert!(known[&1].clients.contains_key(&10));
+
+        let many = (100..200).map(|id| (id, "{}")).collect();
+        let kept = claim_yjs_clients(&mut known, 3, update(many));
+        assert_eq!(kept.clients.len(), MAX_YJS_CLIENTS);
+        assert_eq!(known[&3].clients.len(), MAX_YJS_CLIENTS);
+
+        claim_yjs_clients(&mut known, 1, update(vec![(10, "null")]));
+        assert!(!known.contains_key(&1));
+        let kept = claim_yjs_clients(&mut known, 2, update(vec![(10, "{}")]));
+        assert_eq!(kept.clients.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_sockets_replace_only_stale_files() {
+        let dir = crate::test_util::temp_dir("socket");
+        let path = dir.join("neo-live.sock");
+
+        // left behind by a server that was killed
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = Listener::bind_unix(&path).await.unwrap();
+        assert!(matches!(listener, Listener::Unix(..)));
+
+        let taken = Listener::bind_unix(&path).await;
+        assert!(matches!(taken, Err(e) if e.kind() == io::ErrorKind::AddrInUse));
+        drop(listener);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,116 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use log::{error, info};
+use tokio::io::{self, AsyncWriteExt, Stdout};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+use crate::auth;
+use crate::client::{self, ClientOptions};
+use crate::invite::{self, Invite};
+use crate::protocol::{self, PluginError, PluginShare, Role};
+use crate::server::{self, Listener, ServerOptions};
+
+async fn write_plugin_message(stdout: &mut Stdout, msg: &impl serde::Serialize) {
+    let Some(framed) = protocol::encode_frame(msg) else {
+        return;
+    };
+    if stdout.write_all(&framed).await.is_ok() {
+        let _ = stdout.flush().await;
+    }
+}
+
+// where other peers reach a listener bound to every interface
+fn join_address(bound: SocketAddr) -> SocketAddr {
+    let ip = match bound.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            local_ip_address::local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            local_ip_address::local_ipv6().unwrap_or(IpAddr::V6(Ipv6Addr::LOCALHOST))
+        }
+        ip => ip,
+    };
+    SocketAddr::new(ip, bound.port())
+}
+
+// where the host's own client reaches it, which never has to leave the machine
+fn local_address(bound: SocketAddr) -> SocketAddr {
+    let ip = match bound.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, bound.port())
+}
+
+/// Hosts a session and joins it over stdin and stdout in the same process. Before anything else
+/// the plugin is told where others can join and with which token. The session ends for everyone
+/// once the host leaves.
+pub async fn share(addr: SocketAddr, server_options: ServerOptions, client_options: ClientOptions) {
+    let mut stdout = io::stdout();
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind {}: {}", addr, e);
+            let error = PluginError::new(format!("Could not share on {}: {}", addr, e));
+            write_plugin_message(&mut stdout, &error).await;
+            return;
+        }
+    };
+    // the port is only known now if the OS picked it
+    let bound = match listener.local_addr() {
+        Ok(bound) => bound,
+        Err(e) => {
+            error!("Failed to read the bound address: {}", e);
+            return;
+        }
+    };
+
+    let token = server_options.token.clone();
+    let (left, host_left) = oneshot::channel::<()>();
+    let shutdown = async move {
+        tokio::select! {
+            signal = server::shutdown_signal() => format!("received {}", signal),
+            _ = host_left => "the host left the session".to_owned(),
+        }
+    };
+    let mut server = tokio::spawn(server::serve_listener(
+        Listener::Tcp(listener),
+        server_options,
+        shutdown,
+    ));
+
+    let join = join_address(bound);
+    info!("Sharing on {}", join);
+    let invite = Invite::new(
+        invite::candidates(bound),
+        bound.port(),
+        client_options.room.clone(),
+        Role::Editor,
+        &token,
+    );
+    // guests join as editors, the owner token stays with the host
+    let guest_token = auth::role_token(&token, Role::Editor);
+    let notice = PluginShare::new(
+        join.to_string(),
+        "editor".to_owned(),
+        guest_token,
+        invite.encode(),
+    );
+    write_plugin_message(&mut stdout, &notice).await;
+
+    let local = local_address(bound);
+    let options = ClientOptions {
+        token,
+        ..client_options
+    };
+    tokio::select! {
+        _ = client::connect(local.ip().to_string(), local.port(), options) => {
+            let _ = left.send(());
+            let _ = server.await;
+        }
+        // stopped by a signal. the client already passed the notice on to the plugin
+        _ = &mut server => {}
+    }
+}
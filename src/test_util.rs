@@ -0,0 +1,13 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// a fresh, empty directory for one test, named after it so leftovers are easy to trace
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = std::env::temp_dir().join(format!("neo-live-{}-{}", name, nanos));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
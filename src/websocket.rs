@@ -0,0 +1,393 @@
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::frame::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use yrs::sync::{self, MessageReader};
+use yrs::updates::decoder::DecoderV1;
+use yrs::updates::encoder::Encode;
+use yrs::StateVector;
+
+use crate::protocol::{self, ErrorMessage, MessageKind, RenameBuffer, ServerShutdown, SyncMessage};
+
+/// Subprotocol neo-live peers ask for. Connections without it are taken for y-websocket ones.
+pub const SUBPROTOCOL: &str = "neo-live";
+// bytes read from the stream before they go out as one message
+const PIPE_SIZE: usize = 64 * 1024;
+
+pub(crate) type YjsSink = SplitSink<WebSocketStream<TcpStream>, Message>;
+
+/// What a WebSocket connection turned out to be, going by its upgrade request.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Upgrade {
+    /// A neo-live peer, sending the same byte stream as over TCP
+    NeoLive,
+    /// A Yjs editor through y-websocket, which names its room in the path and passes its token
+    /// as a query parameter
+    Yjs { room: String, token: String },
+}
+
+fn config(max_frame: usize) -> WebSocketConfig {
+    WebSocketConfig::default()
+        .max_message_size(Some(max_frame))
+        .max_frame_size(Some(max_frame))
+}
+
+fn refuse(status: StatusCode, reason: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(reason.to_owned()));
+    *response.status_mut() = status;
+    response
+}
+
+// `%xx` escapes as browsers put them in URLs. `None` if that doesn't leave UTF-8
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn parse_upgrade(request: &Request, yjs: bool) -> Result<Upgrade, &'static str> {
+    let neo_live = request
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == SUBPROTOCOL);
+    if neo_live {
+        return Ok(Upgrade::NeoLive);
+    }
+    if !yjs {
+        return Err("ask for the neo-live subprotocol, this server doesn't take Yjs editors");
+    }
+
+    let uri = request.uri();
+    let room = percent_decode(uri.path().trim_start_matches('/')).ok_or("malformed room name")?;
+    let room = if room.is_empty() {
+        protocol::DEFAULT_ROOM.to_owned()
+    } else {
+        room
+    };
+    let token = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .and_then(percent_decode)
+        .ok_or("the session token is missing, pass it as the token parameter")?;
+    Ok(Upgrade::Yjs { room, token })
+}
+
+/// Takes the WebSocket upgrade and tells apart neo-live peers from Yjs editors. Requests that
+/// are neither get an HTTP error back.
+pub(crate) async fn accept(
+    stream: TcpStream,
+    address: &str,
+    max_frame: usize,
+    yjs: bool,
+) -> Option<(WebSocketStream<TcpStream>, Upgrade)> {
+    let mut upgrade = None;
+    // the error type is tungstenite's, a whole HTTP response
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| {
+        match parse_upgrade(request, yjs) {
+            Ok(Upgrade::NeoLive) => {
+                let protocol = HeaderValue::from_static(SUBPROTOCOL);
+                response
+                    .headers_mut()
+                    .insert("Sec-WebSocket-Protocol", protocol);
+                upgrade = Some(Upgrade::NeoLive);
+            }
+            Ok(yjs) => upgrade = Some(yjs),
+            Err(reason) => return Err(refuse(StatusCode::BAD_REQUEST, reason)),
+        }
+        Ok(response)
+    };
+    let accepted =
+        tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(config(max_frame)))
+            .await;
+    match accepted {
+        Ok(ws) => Some((ws, upgrade?)),
+        Err(e) => {
+            info!("WebSocket upgrade from {} failed: {}", address, e);
+            None
+        }
+    }
+}
+
+/// Opens a WebSocket to a neo-live server at a `ws://` URL and asks for the neo-live
+/// subprotocol.
+pub(crate) async fn connect(url: &str, max_frame: usize) -> io::Result<WebSocketStream<TcpStream>> {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let mut request = url.into_client_request().map_err(io::Error::other)?;
+    let uri = request.uri();
+    if uri.scheme_str() != Some("ws") {
+        let message = "only ws:// URLs are supported, put TLS in front of the server instead";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    let host = uri.host().unwrap_or_default();
+    // IPv6 literals keep their brackets in the URL
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    let port = uri.port_u16().unwrap_or(80);
+
+    let protocol = HeaderValue::from_static(SUBPROTOCOL);
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", protocol);
+    let stream = TcpStream::connect((host.as_str(), port)).await?;
+    let (ws, _) =
+        tokio_tungstenite::client_async_with_config(request, stream, Some(config(max_frame)))
+            .await
+            .map_err(io::Error::other)?;
+    Ok(ws)
+}
+
+/// Turns the WebSocket back into a plain byte stream, so everything that works over TCP works
+/// over it unchanged. Message boundaries carry no meaning, frames may span several.
+pub(crate) fn into_stream<S>(ws: WebSocketStream<S>) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (ours, theirs) = io::duplex(PIPE_SIZE);
+    let (mut sink, mut stream) = ws.split();
+    let (mut from_pipe, mut into_pipe) = io::split(theirs);
+
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = stream.next().await {
+            let data = match message {
+                Message::Binary(data) => data,
+                Message::Close(_) => break,
+                // pings are answered by tungstenite itself, and text has no place here
+                _ => continue,
+            };
+            if into_pipe.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = into_pipe.shutdown().await;
+    });
+
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; PIPE_SIZE];
+        loop {
+            match from_pipe.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    let data = buffer[..n].to_vec();
+                    if sink.send(Message::Binary(data.into())).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    ours
+}
+
+/// The server's state vector, which makes a Yjs peer send back what the server is missing.
+pub(crate) fn sync_step1(state_vector: StateVector) -> Vec<u8> {
+    sync::Message::Sync(sync::SyncMessage::SyncStep1(state_vector)).encode_v1()
+}
+
+/// Everything a Yjs peer is missing, in answer to its state vector.
+pub(crate) fn sync_step2(update: Vec<u8>) -> Vec<u8> {
+    sync::Message::Sync(sync::SyncMessage::SyncStep2(update)).encode_v1()
+}
+
+/// Tells a Yjs peer it may not do what it tried, which y-websocket shows as denied permission.
+pub(crate) fn denied(reason: String) -> Vec<u8> {
+    sync::Message::Auth(Some(reason)).encode_v1()
+}
+
+pub(crate) fn awareness(update: sync::AwarenessUpdate) -> Vec<u8> {
+    sync::Message::Awareness(update).encode_v1()
+}
+
+/// The y-protocols messages in one WebSocket message, which may hold several back to back.
+pub(crate) fn read_messages(data: &[u8]) -> Result<Vec<sync::Message>, String> {
+    let mut decoder = DecoderV1::from(data);
+    MessageReader::new(&mut decoder)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())
+}
+
+/// What becomes of a frame queued for a Yjs peer.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Outgoing {
+    Message(Vec<u8>),
+    Close(String),
+}
+
+/// Translates a neo-live frame for a Yjs peer. Updates, resyncs, renames and deletes come
+/// through as document updates and errors as denied permissions. The rest, like cursors and file
+/// listings, has no y-protocols counterpart and is dropped.
+pub(crate) fn translate(frame: &[u8]) -> Option<Outgoing> {
+    let msg = rmp_serde::from_slice::<SyncMessage>(frame.get(4..)?).ok()?;
+    let update = |update: Vec<u8>| {
+        if update.is_empty() {
+            return None;
+        }
+        let msg = sync::Message::Sync(sync::SyncMessage::Update(update));
+        Some(Outgoing::Message(msg.encode_v1()))
+    };
+    match msg.kind {
+        MessageKind::Yjs => Some(Outgoing::Message(msg.payload)),
+        MessageKind::Update | MessageKind::Resync | MessageKind::DeleteBuffer => {
+            update(msg.payload)
+        }
+        MessageKind::RenameBuffer => update(msg.body::<RenameBuffer>()?.update().clone()),
+        MessageKind::Error => {
+            let error = msg.body::<ErrorMessage>()?;
+            debug!("Telling a Yjs peer: {}", error.message());
+            Some(Outgoing::Message(denied(error.message().clone())))
+        }
+        MessageKind::ServerShutdown => {
+            let notice = msg.body::<ServerShutdown>()?;
+            Some(Outgoing::Close(notice.reason().clone()))
+        }
+        _ => None,
+    }
+}
+
+/// Closes the WebSocket with `reason`, which browsers hand to the page.
+pub(crate) async fn close(sink: &mut YjsSink, code: CloseCode, reason: String) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    if let Err(e) = sink.send(Message::Close(Some(frame))).await {
+        warn!("Failed to close a WebSocket: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yrs::updates::decoder::Decode;
+    use yrs::{Doc, GetString, ReadTxn, Text, Transact, Update};
+
+    fn request(uri: &str, protocol: Option<&str>) -> Request {
+        let mut request = Request::builder().uri(uri);
+        if let Some(protocol) = protocol {
+            request = request.header("Sec-WebSocket-Protocol", protocol);
+        }
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn upgrades_are_told_apart() {
+        let neo_live = request("/", Some("chat, neo-live"));
+        assert_eq!(parse_upgrade(&neo_live, false), Ok(Upgrade::NeoLive));
+
+        let yjs = request("/lab%2001?foo=1&token=abc", None);
+        assert!(parse_upgrade(&yjs, false).is_err());
+        assert_eq!(
+            parse_upgrade(&yjs, true),
+            Ok(Upgrade::Yjs {
+                room: "lab 01".to_owned(),
+                token: "abc".to_owned()
+            })
+        );
+
+        let lobby = request("/?token=abc", None);
+        let Ok(Upgrade::Yjs { room, .. }) = parse_upgrade(&lobby, true) else {
+            panic!("expected a Yjs upgrade");
+        };
+        assert_eq!(room, protocol::DEFAULT_ROOM);
+        assert!(parse_upgrade(&request("/lab", None), true).is_err());
+    }
+
+    #[test]
+    fn percent_escapes_decode() {
+        assert_eq!(percent_decode("a%2Fb%20c").as_deref(), Some("a/b c"));
+        assert_eq!(percent_decode("caf%C3%A9").as_deref(), Some("café"));
+        assert_eq!(percent_decode("bad%2"), None);
+        assert_eq!(percent_decode("bad%zz"), None);
+        assert_eq!(percent_decode("%ff"), None);
+    }
+
+    #[test]
+    fn updates_reach_yjs_peers_as_updates() {
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("a.rs");
+        text.insert(&mut doc.transact_mut(), 0, "hi");
+        let update = doc.transact().encode_diff_v1(&StateVector::default());
+
+        let msg = SyncMessage::new(MessageKind::Update, "a.rs".to_owned(), update.clone());
+        let frame = protocol::encode_frame(&msg).unwrap();
+        let Some(Outgoing::Message(data)) = translate(&frame) else {
+            panic!("expected a message");
+        };
+        let messages = read_messages(&data).unwrap();
+        assert_eq!(
+            messages,
+            vec![sync::Message::Sync(sync::SyncMessage::Update(
+                update.clone()
+            ))]
+        );
+
+        let copy = Doc::new();
+        let copied = copy.get_or_insert_text("a.rs");
+        let decoded = Update::decode_v1(&update).unwrap();
+        copy.transact_mut().apply_update(decoded).unwrap();
+        assert_eq!(copied.get_string(&copy.transact()), "hi");
+
+        let empty = SyncMessage::new(MessageKind::Update, "a.rs".to_owned(), Vec::new());
+        assert_eq!(translate(&protocol::encode_frame(&empty).unwrap()), None);
+    }
+
+    #[test]
+    fn errors_and_shutdowns_are_translated() {
+        let error = ErrorMessage::new(protocol::ErrorCode::ReadOnly, "a.rs is read-only".into());
+        let msg = SyncMessage::with_body(MessageKind::Error, String::new(), &error).unwrap();
+        let Some(Outgoing::Message(data)) = translate(&protocol::encode_frame(&msg).unwrap())
+        else {
+            panic!("expected a message");
+        };
+        assert_eq!(
+            read_messages(&data).unwrap(),
+            vec![sync::Message::Auth(Some("a.rs is read-only".to_owned()))]
+        );
+
+        let notice = ServerShutdown::new("received SIGINT".to_owned());
+        let msg = SyncMessage::with_body(MessageKind::ServerShutdown, String::new(), &notice);
+        let frame = protocol::encode_frame(&msg.unwrap()).unwrap();
+        assert_eq!(
+            translate(&frame),
+            Some(Outgoing::Close("received SIGINT".to_owned()))
+        );
+    }
+
+    #[test]
+    fn several_messages_fit_in_one() {
+        let mut data = sync_step1(StateVector::default());
+        data.extend(sync_step2(vec![0, 0]));
+        let messages = read_messages(&data).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(read_messages(&[0, 9]).is_err());
+    }
+}
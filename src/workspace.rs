@@ -0,0 +1,692 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{Match, WalkBuilder};
+use log::{debug, error, info, warn};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::fs;
+
+use yrs::{Doc, GetString, ReadTxn, Transact};
+
+use crate::diff;
+
+// cap on the files in one listing, so a huge tree can't turn into a huge frame
+const MAX_LISTED_FILES: usize = 10_000;
+
+// saving a file tends to fire a burst of events. a path is only reported once it has been quiet
+// for this long
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+// the editor treats the last line as newline terminated while the shared text leaves that
+// newline off, so files gain and lose it on the way in and out
+fn from_disk(mut contents: String) -> String {
+    if contents.ends_with('\n') {
+        contents.pop();
+    }
+    contents
+}
+
+fn to_disk(text: &str) -> String {
+    if text.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", text)
+    }
+}
+
+/// Directory shared by `serve --root`. Files are loaded into the doc the first time a buffer
+/// syncs, changes made to them on disk are merged in as edits, and the converged text is
+/// written back.
+pub struct Workspace {
+    root: PathBuf,
+    // each tracked buffer's file as it was when we last read or wrote it, in the shared text's
+    // form. external changes are merged against this
+    files: HashMap<String, String>,
+    dirty: HashSet<String>,
+    // whether buffers may be hidden or ignored files, which `list` leaves out
+    share_ignored: bool,
+    _watcher: RecommendedWatcher,
+}
+
+impl Workspace {
+    /// Starts watching `root`, calling `on_change` from a thread of its own with every path
+    /// that was created or modified under it. Paths `list` would leave out are skipped, and a
+    /// burst of changes to one path is reported once it settles.
+    pub fn open(root: &Path, on_change: impl Fn(PathBuf) + Send + 'static) -> io::Result<Self> {
+        let root = std::fs::canonicalize(root)?;
+        let (changes, changed) = mpsc::channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                    for path in event.paths {
+                        let _ = changes.send(path);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("File watcher error: {}", e),
+            })
+            .map_err(io::Error::other)?;
+        watcher
+            .watch(&root, RecursiveMode::Recursive)
+            .map_err(io::Error::other)?;
+        let rules = IgnoreRules::new(root.clone());
+        thread::Builder::new()
+            .name("neo-live-watcher".to_owned())
+            .spawn(move || debounce(changed, rules, on_change))?;
+
+        info!("Sharing {}", root.display());
+        Ok(Self {
+            root,
+            files: HashMap::new(),
+            dirty: HashSet::new(),
+            share_ignored: false,
+            _watcher: watcher,
+        })
+    }
+
+    /// Lets buffers be hidden files and ones the ignore rules exclude, which are refused
+    /// otherwise. Nothing under .git is ever shared, since writing there runs code on the host.
+    pub fn sharing_ignored(mut self, share: bool) -> Self {
+        self.share_ignored = share;
+        self
+    }
+
+    /// Where the buffer lives on disk, or None if its name would leave the root or point at a
+    /// file that isn't shared.
+    pub async fn path_for(&self, buffer: &str) -> Option<PathBuf> {
+        let path = self.resolve(buffer).await?;
+        let relative = path.strip_prefix(&self.root).ok()?;
+        if relative.components().any(|c| c.as_os_str() == ".git") {
+            return None;
+        }
+        if self.share_ignored {
+            return Some(path);
+        }
+        let mut rules = IgnoreRules::new(self.root.clone());
+        let checked = path.clone();
+        let ignored = tokio::task::spawn_blocking(move || rules.is_ignored(&checked));
+        match ignored.await {
+            Ok(false) => Some(path),
+            _ => None,
+        }
+    }
+
+    // the buffer's path with symlinks resolved, or None if it leaves the root
+    async fn resolve(&self, buffer: &str) -> Option<PathBuf> {
+        let relative = Path::new(buffer);
+        if buffer.is_empty() || relative.is_absolute() {
+            return None;
+        }
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return None;
+        }
+
+        // a symlink inside the root can still point out of it, and so can one on the way to a
+        // file that doesn't exist yet. whatever part of the path exists has to resolve under
+        // the root, the rest is created under that
+        let path = self.root.join(relative);
+        let mut existing = path.as_path();
+        let mut missing = Vec::new();
+        loop {
+            match fs::canonicalize(existing).await {
+                Ok(real) if real.starts_with(&self.root) => {
+                    return Some(missing.into_iter().rev().fold(real, |path, c| path.join(c)));
+                }
+                Ok(_) => return None,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    // a dangling symlink, which a write would follow wherever it points
+                    if fs::symlink_metadata(existing).await.is_ok() {
+                        return None;
+                    }
+                    missing.push(existing.file_name()?);
+                    existing = existing.parent()?;
+                }
+                Err(_) => return None,
+            }
+        }
+    }
+
+    pub fn buffer_for(&self, path: &Path) -> Option<String> {
+        buffer_name(&self.root, path)
+    }
+
+    /// Files under `directory`, or the whole tree when it's empty, as buffer names. Whatever
+    /// .gitignore and friends exclude is left out, even when the root isn't a git repository.
+    /// None if the directory would leave the root.
+    pub async fn list(&self, directory: &str) -> Option<Vec<String>> {
+        let start = if directory.is_empty() {
+            self.root.clone()
+        } else {
+            self.path_for(directory).await?
+        };
+        let root = self.root.clone();
+
+        let walk = tokio::task::spawn_blocking(move || {
+            let mut files = Vec::new();
+            for entry in WalkBuilder::new(&start).require_git(false).build() {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        debug!("Skipping entry while listing files: {}", e);
+                        continue;
+                    }
+                };
+                if !entry.file_type().is_some_and(|t| t.is_file()) {
+                    continue;
+                }
+                if files.len() == MAX_LISTED_FILES {
+                    warn!(
+                        "Listing of {} cut off at {} files",
+                        start.display(),
+                        MAX_LISTED_FILES
+                    );
+                    break;
+                }
+                if let Some(name) = buffer_name(&root, entry.path()) {
+                    files.push(name);
+                }
+            }
+            files.sort();
+            files
+        });
+        walk.await.ok()
+    }
+
+    /// Merges the buffer's file into the doc the first time the buffer is asked for. The file
+    /// wins over whatever the doc held for it. Returns the update this made, if any.
+    pub async fn load(&mut self, doc: &Doc, buffer: &str) -> Option<Vec<u8>> {
+        if self.files.contains_key(buffer) {
+            return None;
+        }
+        let contents = self.read(buffer).await?;
+        debug!("Loaded {} from disk", buffer);
+
+        let text = doc.get_or_insert_text(buffer);
+        let current = text.get_string(&doc.transact());
+        let update = replace(doc, buffer, &current, &contents);
+        self.files.insert(buffer.to_owned(), contents);
+        update
+    }
+
+    /// Merges a change made to the buffer's file outside of the session with whatever the
+    /// editors did since the file was last read or written. Returns the update this made, if
+    /// any.
+    pub async fn reload(&mut self, doc: &Doc, buffer: &str) -> Option<Vec<u8>> {
+        let base = self.files.get(buffer)?.clone();
+        let contents = self.read(buffer).await?;
+        // our own write coming back around, or nothing changed
+        if contents == base {
+            return None;
+        }
+        info!("{} changed on disk, merging", buffer);
+
+        // the change on disk goes in the way an editor's concurrent edit would, so what the
+        // editors did since keeps its place in the CRDT
+        let change = diff::diff(&base, &contents)?;
+        let text = doc.get_or_insert_text(buffer);
+        let before = doc.transact().state_vector();
+        let merged = {
+            let mut txn = doc.transact_mut();
+            let current = text.get_string(&txn);
+            for change in diff::rebase(&base, &current, &change) {
+                diff::apply_change(&text, &mut txn, &change);
+            }
+            text.get_string(&txn)
+        };
+        let update = Some(doc.transact().encode_diff_v1(&before));
+        if merged != contents {
+            self.dirty.insert(buffer.to_owned());
+        }
+        self.files.insert(buffer.to_owned(), contents);
+        update
+    }
+
+    /// Notes that the buffer changed in the doc and should be written back.
+    pub fn mark_dirty(&mut self, buffer: &str) {
+        if self.files.contains_key(buffer) {
+            self.dirty.insert(buffer.to_owned());
+        }
+    }
+
+    /// Writes every buffer that changed since the last flush back to its file.
+    pub async fn flush(&mut self, doc: &Doc) {
+        for buffer in std::mem::take(&mut self.dirty) {
+            let text = doc.get_or_insert_text(buffer.as_str());
+            let current = text.get_string(&doc.transact());
+            if self.files.get(&buffer) == Some(&current) {
+                continue;
+            }
+            let Some(path) = self.path_for(&buffer).await else {
+                continue;
+            };
+            if let Err(e) = fs::write(&path, to_disk(&current)).await {
+                error!("Failed to write {}: {}", path.display(), e);
+                self.dirty.insert(buffer);
+                continue;
+            }
+            debug!("Wrote {} back to disk", buffer);
+            self.files.insert(buffer, current);
+        }
+    }
+
+    /// Creates an empty file for a new buffer, along with any directories leading up to it.
+    /// Fails if there already is one.
+    pub async fn create(&mut self, buffer: &str) -> io::Result<()> {
+        let path = self.checked_path(buffer).await?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        info!("Created {}", buffer);
+        self.files.insert(buffer.to_owned(), String::new());
+        Ok(())
+    }
+
+    /// Moves the buffer's file, if it has one, to where `to` lives. Fails rather than replace
+    /// an existing file.
+    pub async fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        let source = self.checked_path(from).await?;
+        let target = self.checked_path(to).await?;
+        if fs::try_exists(&target).await? {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", to),
+            ));
+        }
+
+        if fs::try_exists(&source).await? {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::rename(&source, &target).await?;
+            info!("Renamed {} to {}", from, to);
+        }
+        if let Some(contents) = self.files.remove(from) {
+            self.files.insert(to.to_owned(), contents);
+        }
+        if self.dirty.remove(from) {
+            self.dirty.insert(to.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Deletes the buffer's file, if it has one, and stops tracking it.
+    pub async fn remove(&mut self, buffer: &str) -> io::Result<()> {
+        let path = self.checked_path(buffer).await?;
+        match fs::remove_file(&path).await {
+            Ok(()) => info!("Deleted {}", buffer),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.files.remove(buffer);
+        self.dirty.remove(buffer);
+        Ok(())
+    }
+
+    async fn checked_path(&self, buffer: &str) -> io::Result<PathBuf> {
+        self.path_for(buffer).await.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is outside of the shared directory", buffer),
+            )
+        })
+    }
+
+    // None if the buffer has no file under the root to back it
+    async fn read(&self, buffer: &str) -> Option<String> {
+        let path = self.path_for(buffer).await?;
+        match fs::read_to_string(&path).await {
+            Ok(contents) => Some(from_disk(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                error!("Failed to read {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+// the rules `list` walks by, for one path at a time: hidden files are left out, and so is
+// whatever the .gitignore and .ignore files from the root down exclude, deeper ones overriding
+struct IgnoreRules {
+    root: PathBuf,
+    // each directory's ignore files, parsed the first time a path below it comes by
+    dirs: HashMap<PathBuf, Gitignore>,
+}
+
+impl IgnoreRules {
+    fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            dirs: HashMap::new(),
+        }
+    }
+
+    fn is_ignored(&mut self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return true;
+        };
+        // an edited ignore file is read again next time. it's hidden itself
+        if let Some(name) = path.file_name() {
+            if name == ".gitignore" || name == ".ignore" {
+                if let Some(dir) = path.parent() {
+                    self.dirs.remove(dir);
+                }
+                return true;
+            }
+        }
+        let hidden = relative
+            .components()
+            .any(|c| c.as_os_str().to_str().is_none_or(|c| c.starts_with('.')));
+        if hidden {
+            return true;
+        }
+
+        let is_dir = path.is_dir();
+        let mut ignored = false;
+        let mut dir = self.root.clone();
+        let parents = relative.parent().into_iter().flat_map(|p| p.components());
+        for component in std::iter::once(None).chain(parents.map(Some)) {
+            if let Some(component) = component {
+                dir.push(component);
+            }
+            let rules = self.dirs.entry(dir.clone()).or_insert_with(|| {
+                let mut builder = GitignoreBuilder::new(&dir);
+                // missing ignore files are the common case
+                let _ = builder.add(dir.join(".gitignore"));
+                let _ = builder.add(dir.join(".ignore"));
+                builder.build().unwrap_or_else(|_| Gitignore::empty())
+            });
+            match rules.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => ignored = true,
+                Match::Whitelist(_) => ignored = false,
+                Match::None => {}
+            }
+        }
+        ignored
+    }
+}
+
+// hands on every changed path that isn't ignored once events for it stopped coming in. ends
+// along with the watcher
+fn debounce(changed: Receiver<PathBuf>, mut rules: IgnoreRules, on_change: impl Fn(PathBuf)) {
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    loop {
+        let received = match pending.values().min() {
+            Some(due) => changed.recv_timeout(due.saturating_duration_since(Instant::now())),
+            None => changed.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(path) => {
+                if !rules.is_ignored(&path) {
+                    pending.insert(path, Instant::now() + DEBOUNCE);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        let settled: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, due)| **due <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in settled {
+            pending.remove(&path);
+            on_change(path);
+        }
+    }
+}
+
+fn buffer_name(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Option<Vec<&str>> = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect();
+    Some(parts?.join("/"))
+}
+
+// edits the buffer's text from `current` to `new` as one contiguous change
+fn replace(doc: &Doc, buffer: &str, current: &str, new: &str) -> Option<Vec<u8>> {
+    let change = diff::diff(current, new)?;
+    let text = doc.get_or_insert_text(buffer);
+    let before = doc.transact().state_vector();
+    {
+        let mut txn = doc.transact_mut();
+        diff::apply_change(&text, &mut txn, &change);
+    }
+    Some(doc.transact().encode_diff_v1(&before))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::sync::{Arc, Mutex};
+    use yrs::Text;
+
+    fn contents(doc: &Doc, buffer: &str) -> String {
+        let text = doc.get_or_insert_text(buffer);
+        let txn = doc.transact();
+        text.get_string(&txn)
+    }
+
+    #[tokio::test]
+    async fn paths_outside_the_root_are_rejected() {
+        let dir = temp_dir("escape");
+        let workspace = Workspace::open(&dir, |_| {}).unwrap();
+        assert!(workspace.path_for("src/main.rs").await.is_some());
+        assert!(workspace.path_for("./notes.md").await.is_some());
+        assert!(workspace.path_for("../secret").await.is_none());
+        assert!(workspace.path_for("src/../../secret").await.is_none());
+        assert!(workspace.path_for("/etc/passwd").await.is_none());
+        assert!(workspace.path_for("").await.is_none());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn hidden_and_ignored_files_are_not_shared() {
+        let dir = temp_dir("hidden");
+        std::fs::create_dir_all(dir.join(".git/hooks")).unwrap();
+        std::fs::write(dir.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(dir.join(".git/config"), "").unwrap();
+        let workspace = Workspace::open(&dir, |_| {}).unwrap();
+        assert!(workspace.path_for("main.rs").await.is_some());
+        assert!(workspace.path_for(".git/config").await.is_none());
+        assert!(workspace.path_for(".git/hooks/pre-commit").await.is_none());
+        assert!(workspace.path_for("sub/.git/config").await.is_none());
+        assert!(workspace.path_for(".env").await.is_none());
+        assert!(workspace.path_for("debug.log").await.is_none());
+
+        // the host can share them, but never what's in .git
+        let workspace = workspace.sharing_ignored(true);
+        assert!(workspace.path_for(".env").await.is_some());
+        assert!(workspace.path_for("debug.log").await.is_some());
+        assert!(workspace.path_for(".git/config").await.is_none());
+        assert!(workspace
+            .path_for("./.git/hooks/pre-commit")
+            .await
+            .is_none());
+
+        // nor through a symlink that leads into it
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join(".git"), dir.join("meta")).unwrap();
+            assert!(workspace.path_for("meta/config").await.is_none());
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_out_of_the_root_are_rejected() {
+        let dir = temp_dir("symlink");
+        let outside = temp_dir("symlink-outside");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("gone"), dir.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(dir.join("src"), dir.join("inner")).unwrap();
+        let workspace = Workspace::open(&dir, |_| {}).unwrap();
+
+        assert!(workspace.path_for("link").await.is_none());
+        assert!(workspace.path_for("link/x").await.is_none());
+        assert!(workspace.path_for("link/new/x").await.is_none());
+        assert!(workspace.path_for("dangling").await.is_none());
+        let root = std::fs::canonicalize(&dir).unwrap();
+        assert_eq!(
+            workspace.path_for("inner/new/x.rs").await,
+            Some(root.join("src/new/x.rs"))
+        );
+        assert_eq!(
+            workspace.path_for("new/x.rs").await,
+            Some(root.join("new/x.rs"))
+        );
+        let _ = std::fs::remove_dir_all(dir);
+        let _ = std::fs::remove_dir_all(outside);
+    }
+
+    #[tokio::test]
+    async fn external_changes_merge_with_edits() {
+        let dir = temp_dir("merge");
+        std::fs::write(dir.join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        let mut workspace = Workspace::open(&dir, |_| {}).unwrap();
+        let doc = Doc::new();
+
+        assert!(workspace.load(&doc, "a.txt").await.is_some());
+        assert_eq!(contents(&doc, "a.txt"), "one\ntwo\nthree");
+
+        let text = doc.get_or_insert_text("a.txt");
+        text.insert(&mut doc.transact_mut(), 0, "zero\n");
+        workspace.mark_dirty("a.txt");
+        std::fs::write(dir.join("a.txt"), "one\ntwo\nthree\nfour\n").unwrap();
+
+        assert!(workspace.reload(&doc, "a.txt").await.is_some());
+        assert_eq!(contents(&doc, "a.txt"), "zero\none\ntwo\nthree\nfour");
+
+        workspace.flush(&doc).await;
+        let written = std::fs::read_to_string(dir.join("a.txt")).unwrap();
+        assert_eq!(written, "zero\none\ntwo\nthree\nfour\n");
+        assert!(workspace.reload(&doc, "a.txt").await.is_none());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn listing_honours_gitignore() {
+        let dir = temp_dir("list");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join("target")).unwrap();
+        std::fs::write(dir.join(".gitignore"), "target/\n*.log\n").unwrap();
+        std::fs::write(dir.join("src/main.rs"), "").unwrap();
+        std::fs::write(dir.join("README"), "").unwrap();
+        std::fs::write(dir.join("debug.log"), "").unwrap();
+        std::fs::write(dir.join("target/out"), "").unwrap();
+        let workspace = Workspace::open(&dir, |_| {}).unwrap();
+
+        let files = workspace.list("").await.unwrap();
+        assert_eq!(files, vec!["README".to_owned(), "src/main.rs".to_owned()]);
+        let files = workspace.list("src").await.unwrap();
+        assert_eq!(files, vec!["src/main.rs".to_owned()]);
+        assert!(workspace.list("..").await.is_none());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn watcher_skips_ignored_files_and_settles_bursts() {
+        let dir = temp_dir("watch");
+        std::fs::create_dir_all(dir.join("target")).unwrap();
+        std::fs::create_dir_all(dir.join("logs")).unwrap();
+        std::fs::write(dir.join(".gitignore"), "target/\n*.log\n").unwrap();
+        std::fs::write(dir.join("logs/.gitignore"), "!keep.log\n").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        let workspace = Workspace::open(&dir, move |path| sink.lock().unwrap().push(path));
+        let workspace = workspace.unwrap();
+
+        for i in 0..5 {
+            std::fs::write(dir.join("README"), i.to_string()).unwrap();
+            std::fs::write(dir.join("debug.log"), i.to_string()).unwrap();
+            std::fs::write(dir.join("target/out"), i.to_string()).unwrap();
+            std::fs::write(dir.join("logs/keep.log"), i.to_string()).unwrap();
+        }
+        std::thread::sleep(DEBOUNCE * 5);
+
+        let mut seen: Vec<_> = seen
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|path| workspace.buffer_for(path))
+            .collect();
+        seen.sort();
+        assert_eq!(seen, vec!["README".to_owned(), "logs/keep.log".to_owned()]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn files_follow_renames_and_deletes() {
+        let dir = temp_dir("lifecycle");
+        std::fs::write(dir.join("a.txt"), "one\n").unwrap();
+        let mut workspace = Workspace::open(&dir, |_| {}).unwrap();
+        let doc = Doc::new();
+        assert!(workspace.load(&doc, "a.txt").await.is_some());
+
+        workspace.create("new/b.txt").await.unwrap();
+        assert!(dir.join("new/b.txt").exists());
+        assert!(workspace.create("new/b.txt").await.is_err());
+        assert!(workspace.rename("a.txt", "new/b.txt").await.is_err());
+
+        workspace.rename("a.txt", "docs/a.txt").await.unwrap();
+        assert!(!dir.join("a.txt").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("docs/a.txt")).unwrap(),
+            "one\n"
+        );
+        assert!(workspace.files.contains_key("docs/a.txt"));
+        assert!(!workspace.files.contains_key("a.txt"));
+
+        workspace.remove("docs/a.txt").await.unwrap();
+        assert!(!dir.join("docs/a.txt").exists());
+        assert!(!workspace.files.contains_key("docs/a.txt"));
+        assert!(workspace.remove("../a.txt").await.is_err());
+
+        // nothing is created or moved through a symlink that leads out of the root
+        #[cfg(unix)]
+        {
+            let outside = temp_dir("lifecycle-outside");
+            std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+            assert!(workspace.create("link/evil").await.is_err());
+            assert!(workspace.create("link/deeper/evil").await.is_err());
+            workspace.create("c.txt").await.unwrap();
+            assert!(workspace.rename("c.txt", "link/c.txt").await.is_err());
+            assert!(dir.join("c.txt").exists());
+            assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+            let _ = std::fs::remove_dir_all(outside);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn missing_files_are_not_tracked() {
+        let dir = temp_dir("missing");
+        let mut workspace = Workspace::open(&dir, |_| {}).unwrap();
+        let doc = Doc::new();
+        assert!(workspace.load(&doc, "new.txt").await.is_none());
+        workspace.mark_dirty("new.txt");
+        workspace.flush(&doc).await;
+        assert!(!dir.join("new.txt").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use neo_live::client::{run_client, ClientOptions};
+use neo_live::protocol::{
+    encode_frame, FrameReader, PluginEdit, PluginOpen, PluginPatch, PluginShutdown, PluginUpdate,
+    Role,
+};
+use neo_live::server::{serve_listener, Listener, ServerOptions};
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+const TOKEN: &str = "resync";
+const WAIT: Duration = Duration::from_secs(10);
+
+// a server on a port of its own, running until the returned sender fires or is dropped
+async fn start_server(options: ServerOptions) -> (SocketAddr, oneshot::Sender<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let shutdown = async move {
+        let _ = stopped.await;
+        "the test is done with it".to_owned()
+    };
+    let options = ServerOptions {
+        token: TOKEN.to_owned(),
+        ..options
+    };
+    tokio::spawn(serve_listener(Listener::Tcp(listener), options, shutdown));
+    (addr, stop)
+}
+
+// the editor's end of a client, which dials whichever server `server` points at, reconnects
+// included
+struct Plugin {
+    input: DuplexStream,
+    output: FrameReader<DuplexStream>,
+}
+
+impl Plugin {
+    async fn start(server: &Arc<Mutex<SocketAddr>>, buffers: &[&str]) -> Self {
+        let (input, client_input) = tokio::io::duplex(1 << 16);
+        let (client_output, output) = tokio::io::duplex(1 << 16);
+        let server = Arc::clone(server);
+        let connector = move || {
+            let addr = *server.lock().unwrap();
+            async move { Ok(TcpStream::connect(addr).await?.into_split()) }
+        };
+        let options = ClientOptions {
+            token: neo_live::role_token(TOKEN, Role::Editor),
+            role: Role::Editor,
+            ..Default::default()
+        };
+        tokio::spawn(run_client(connector, client_input, client_output, options));
+
+        let mut plugin = Self {
+            input,
+            output: FrameReader::new(output),
+        };
+        let buffers = buffers.iter().map(|b| (*b).to_owned()).collect();
+        plugin.send(&PluginOpen::new(buffers)).await;
+        plugin
+    }
+
+    async fn send(&mut self, msg: &impl serde::Serialize) {
+        let framed = encode_frame(msg).unwrap();
+        self.input.write_all(&framed).await.unwrap();
+    }
+
+    async fn insert(&mut self, buffer: &str, start: u32, text: &str) {
+        let edit = PluginEdit::new(buffer.to_owned(), start, 0, text.to_owned());
+        self.send(&edit).await;
+    }
+
+    async fn next_frame(&mut self) -> Vec<u8> {
+        let read = tokio::time::timeout(WAIT, self.output.read_one()).await;
+        read.expect("timed out waiting on the client").unwrap()
+    }
+
+    // the next whole copy of each of `buffers` the client hands over, skipping anything else
+    async fn updates(&mut self, buffers: &[&str]) -> HashMap<String, PluginUpdate> {
+        let mut updates = HashMap::new();
+        while updates.len() < buffers.len() {
+            let frame = self.next_frame().await;
+            let Ok(update) = rmp_serde::from_slice::<PluginUpdate>(&frame) else {
+                continue;
+            };
+            if buffers.contains(&update.buffer().as_str()) {
+                updates.insert(update.buffer().clone(), update);
+            }
+        }
+        updates
+    }
+
+    // what the next patch to each of `buffers` inserts, for buffers that were empty
+    async fn insertions(&mut self, buffers: &[&str]) -> HashMap<String, String> {
+        let mut inserted = HashMap::new();
+        while inserted.len() < buffers.len() {
+            let frame = self.next_frame().await;
+            let Ok(patch) = rmp_serde::from_slice::<PluginPatch>(&frame) else {
+                continue;
+            };
+            if buffers.contains(&patch.buffer().as_str()) {
+                let text = patch
+                    .edits()
+                    .iter()
+                    .map(|edit| edit.text.as_str())
+                    .collect();
+                inserted.insert(patch.buffer().clone(), text);
+            }
+        }
+        inserted
+    }
+
+    async fn shutdown(&mut self) {
+        loop {
+            let frame = self.next_frame().await;
+            if rmp_serde::from_slice::<PluginShutdown>(&frame).is_ok() {
+                return;
+            }
+        }
+    }
+}
+
+// joins with a client of its own until the buffer reads `expected`, since the edits it waits on
+// land whenever the writer gets around to reconnecting
+async fn eventually_reads(server: &Arc<Mutex<SocketAddr>>, buffer: &str, expected: &str) {
+    let deadline = tokio::time::Instant::now() + WAIT;
+    loop {
+        let mut observer = Plugin::start(server, &[buffer]).await;
+        let text = observer.updates(&[buffer]).await[buffer].text().clone();
+        if text == expected {
+            return;
+        }
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "{} still reads {:?}",
+            buffer,
+            text
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+#[tokio::test]
+async fn refused_edits_are_dropped_and_later_ones_still_land() {
+    let (first, stop_first) = start_server(ServerOptions::default()).await;
+    let server = Arc::new(Mutex::new(first));
+    let mut writer = Plugin::start(&server, &["docs/a.txt", "notes.txt"]).await;
+    writer.updates(&["docs/a.txt", "notes.txt"]).await;
+
+    // the session comes back with docs/ read-only, which the client only learns once it
+    // rejoins. what it wrote there in the meantime is refused
+    stop_first.send(()).unwrap();
+    writer.shutdown().await;
+    writer.insert("docs/a.txt", 0, "offline").await;
+    let read_only = ServerOptions {
+        read_only: vec!["docs/".to_owned()],
+        ..Default::default()
+    };
+    let (second, _stop_second) = start_server(read_only).await;
+    *server.lock().unwrap() = second;
+
+    // the client starts over from the server's copy and carries on from there
+    let updates = writer.updates(&["docs/a.txt", "notes.txt"]).await;
+    assert_eq!(updates["docs/a.txt"].text(), "");
+    assert!(updates["docs/a.txt"].is_read_only());
+    writer.insert("notes.txt", 0, "still here").await;
+    eventually_reads(&server, "notes.txt", "still here").await;
+}
+
+#[tokio::test]
+async fn edits_to_several_buffers_made_offline_survive_a_restart() {
+    let (first, stop_first) = start_server(ServerOptions::default()).await;
+    let server = Arc::new(Mutex::new(first));
+    let mut writer = Plugin::start(&server, &["a.txt", "b.txt"]).await;
+    writer.updates(&["a.txt", "b.txt"]).await;
+    writer.insert("a.txt", 0, "online").await;
+
+    // the server comes back with nothing, so everything the client has goes over again
+    stop_first.send(()).unwrap();
+    writer.shutdown().await;
+    writer.insert("a.txt", 0, "offline ").await;
+    writer.insert("b.txt", 0, "offline too").await;
+    let (second, _stop_second) = start_server(ServerOptions::default()).await;
+    // someone already back in the session sees them come in
+    let mut observer = Plugin::start(&Arc::new(Mutex::new(second)), &["a.txt", "b.txt"]).await;
+    observer.updates(&["a.txt", "b.txt"]).await;
+    *server.lock().unwrap() = second;
+
+    let inserted = observer.insertions(&["a.txt", "b.txt"]).await;
+    assert_eq!(inserted["a.txt"], "offline online");
+    assert_eq!(inserted["b.txt"], "offline too");
+    eventually_reads(&server, "a.txt", "offline online").await;
+    writer.insert("b.txt", 0, "and back ").await;
+    eventually_reads(&server, "b.txt", "and back offline too").await;
+}